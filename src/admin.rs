@@ -0,0 +1,240 @@
+//! The `admin` subcommand, managing a server through its admin API
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    config::{Args, Output},
+    exit, ErrorResponse, MISSED_HEARTBEATS, OFFLINE_TIMEOUT,
+};
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum AdminCommand {
+    /// List the known devices of every user, archived ones included
+    Clients,
+    /// Rename a device
+    Rename {
+        device: String,
+        name: String,
+        /// User owning the device, the default user if not given
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Forget a device, archived or not
+    Delete {
+        device: String,
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Show this status instead of the computed one, or go back to that if none is given
+    Override {
+        status: Option<String>,
+        /// Unix time the override ends at
+        #[arg(long)]
+        until: Option<u64>,
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Delete the incident log
+    Purge {
+        /// Only incidents that ended before this Unix time
+        #[arg(long)]
+        before: Option<u64>,
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Reload the server's configuration
+    Reload,
+    /// Make a link that shows the status in detail to whoever has it, for a while
+    Share {
+        /// Hours the link works for
+        #[arg(long, default_value_t = 24)]
+        hours: u64,
+        /// What the link shows: aggregate, devices or messages
+        #[arg(long, default_value = "messages")]
+        privacy: String,
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Show who changed what, most recent first
+    Audit {
+        /// Only entries of this action, like override or reload
+        #[arg(long)]
+        action: Option<String>,
+        /// Only entries since this Unix time
+        #[arg(long)]
+        since: Option<u64>,
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+/// A link made by `share`
+#[derive(Deserialize)]
+struct Share {
+    path: String,
+    expires: u64,
+}
+
+/// An entry shown by `audit`
+#[derive(Deserialize)]
+struct AuditEntry {
+    timestamp: u64,
+    actor: String,
+    action: String,
+    user: Option<String>,
+    device: Option<String>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+/// The parts of a device shown by `clients`
+#[derive(Deserialize)]
+struct Device {
+    last_seen: u64,
+    message: Option<String>,
+    /// Seconds between heartbeats the device announced
+    interval: Option<u64>,
+    /// When it was archived for not being seen for long
+    archived: Option<u64>,
+}
+
+pub async fn admin_main(args: Args, command: AdminCommand) -> Result<(), Box<dyn Error>> {
+    let base = args.server_url("");
+    let http = reqwest::Client::new();
+    let request = match &command {
+        AdminCommand::Clients => http.get(format!("{}/admin/clients", base)),
+        AdminCommand::Rename { device, name, user } => http
+            .post(format!("{}/admin/rename", base))
+            .json(&json!({ "user": user.as_deref().unwrap_or_default(), "device": device, "name": name })),
+        AdminCommand::Delete { device, user } => http
+            .post(format!("{}/admin/delete", base))
+            .json(&json!({ "user": user.as_deref().unwrap_or_default(), "device": device })),
+        AdminCommand::Override {
+            status,
+            until,
+            user,
+        } => http
+            .post(format!("{}/admin/override", base))
+            .json(&json!({ "user": user.as_deref().unwrap_or_default(), "status": status, "until": until })),
+        AdminCommand::Purge { before, user } => {
+            let mut query = Vec::new();
+            if let Some(before) = before {
+                query.push(("before", before.to_string()));
+            }
+            if let Some(user) = user {
+                query.push(("user", user.clone()));
+            }
+            http.delete(format!("{}/history", base)).query(&query)
+        }
+        AdminCommand::Reload => http.post(format!("{}/admin/reload", base)),
+        AdminCommand::Share {
+            hours,
+            privacy,
+            user,
+        } => http
+            .post(format!("{}/admin/share", base))
+            .json(&json!({
+                "user": user.as_deref().unwrap_or_default(),
+                "hours": hours,
+                "privacy": privacy,
+            })),
+        AdminCommand::Audit {
+            action,
+            since,
+            user,
+        } => {
+            let mut query = Vec::new();
+            if let Some(action) = action {
+                query.push(("action", action.clone()));
+            }
+            if let Some(since) = since {
+                query.push(("since", since.to_string()));
+            }
+            if let Some(user) = user {
+                query.push(("user", user.clone()));
+            }
+            http.get(format!("{}/admin/audit", base)).query(&query)
+        }
+    };
+    let request = match &args.token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let res = request.send().await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let message = match res.json::<ErrorResponse>().await {
+            Ok(e) => format!("{} ({}: {})", status, e.code, e.message),
+            Err(_) => status.to_string(),
+        };
+        return Err(exit::status(status, message));
+    }
+    if args.output == Output::Json {
+        // What the server answered, wrapped if it is plain text
+        let text = res.text().await?;
+        match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(value) => println!("{}", value),
+            Err(_) => println!("{}", json!({ "result": text })),
+        }
+    } else if let AdminCommand::Clients = command {
+        let clients: BTreeMap<String, BTreeMap<String, Device>> = res.json().await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (user, devices) in clients {
+            for (name, device) in devices {
+                let ago = now.saturating_sub(device.last_seen);
+                let timeout = device
+                    .interval
+                    .map_or(OFFLINE_TIMEOUT, |interval| interval * MISSED_HEARTBEATS);
+                println!(
+                    "{}\t{}\t{}\tlast seen {}s ago\t{}",
+                    if user.is_empty() { "(default)" } else { &user },
+                    name,
+                    if device.archived.is_some() {
+                        "ARCHIVED"
+                    } else if ago <= timeout {
+                        "ONLINE"
+                    } else {
+                        "OFFLINE"
+                    },
+                    ago,
+                    device.message.unwrap_or_default()
+                );
+            }
+        }
+    } else if let AdminCommand::Share { .. } = command {
+        let share: Share = res.json().await?;
+        println!("{}{}\nworks until {}", base, share.path, share.expires);
+    } else if let AdminCommand::Audit { .. } = command {
+        let entries: Vec<AuditEntry> = res.json().await?;
+        let show =
+            |value: Option<serde_json::Value>| value.map_or("-".to_string(), |v| v.to_string());
+        for entry in entries {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{} -> {}",
+                entry.timestamp,
+                entry.actor,
+                entry.action,
+                match entry.user.as_deref() {
+                    Some("") => "(default)",
+                    user => user.unwrap_or("-"),
+                },
+                entry.device.as_deref().unwrap_or("-"),
+                show(entry.before),
+                show(entry.after)
+            );
+        }
+    } else {
+        println!("{}", res.text().await?);
+    }
+    Ok(())
+}
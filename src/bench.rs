@@ -0,0 +1,123 @@
+//! Load testing a server with many simulated clients
+
+use crate::{
+    config::Args,
+    keys::{read_secret_key, sign_timestamp},
+    HeartBeat, TIMEOUT,
+};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{task::JoinSet, time};
+
+/// Send `rate` heartbeats per second for `duration` seconds, round-robin from
+/// `clients` devices, then report throughput and latency
+pub async fn bench_main(
+    args: Args,
+    clients: usize,
+    rate: f64,
+    duration: u64,
+) -> Result<(), Box<dyn Error>> {
+    if clients == 0 || rate <= 0.0 {
+        return Err("--clients and --rate must be positive".into());
+    }
+    let privkey = match &args.privkey {
+        Some(path) => Some(Arc::new(read_secret_key(path)?)),
+        None => None,
+    };
+    let url = args.server_url(args.heartbeat_path.as_deref().unwrap_or("/heartbeat"));
+    let http = reqwest::Client::new();
+    let hash = args.hash.unwrap_or_default();
+    info!(
+        "Sending {} heartbeat(s) per second from {} client(s) to {} for {}s",
+        rate, clients, url, duration
+    );
+
+    let mut tasks = JoinSet::new();
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / rate));
+    let start = Instant::now();
+    let mut sent = 0;
+    while start.elapsed() < Duration::from_secs(duration) {
+        interval.tick().await;
+        let device = format!("bench-{}", sent % clients);
+        let privkey = privkey.clone();
+        let mut req = http.post(&url).timeout(Duration::from_secs(TIMEOUT));
+        if let Some(token) = &args.token {
+            req = req.bearer_auth(token);
+        }
+        tasks.spawn(async move {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let signature = match privkey {
+                Some(key) => Some(
+                    tokio::task::spawn_blocking(move || sign_timestamp(&key, hash, timestamp))
+                        .await
+                        .unwrap()
+                        .map_err(|e| e.to_string())?,
+                ),
+                None => None,
+            };
+            let info = HeartBeat {
+                timestamp,
+                hash: signature.as_ref().map(|_| hash.to_string()),
+                signature,
+                detached_signature: None,
+                device: Some(device),
+                message: None,
+                place: None,
+                latency_ms: None,
+                interval: None,
+                client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                uptime: None,
+                metrics: None,
+                extra: None,
+            };
+            let sent = Instant::now();
+            let res = req.json(&info).send().await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(res.status().to_string());
+            }
+            Ok(sent.elapsed())
+        });
+        sent += 1;
+    }
+
+    let mut latencies = Vec::new();
+    let mut errors: BTreeMap<String, usize> = BTreeMap::new();
+    while let Some(result) = tasks.join_next().await {
+        match result.unwrap() {
+            Ok(latency) => latencies.push(latency),
+            Err(e) => *errors.entry(e).or_default() += 1,
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    info!(
+        "{} heartbeat(s) in {:.1}s: {} accepted, {} failed, {:.1} accepted/s",
+        sent,
+        elapsed,
+        latencies.len(),
+        sent - latencies.len(),
+        latencies.len() as f64 / elapsed
+    );
+    if !latencies.is_empty() {
+        latencies.sort();
+        let percentile =
+            |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+        info!(
+            "Latency: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            percentile(100)
+        );
+    }
+    for (error, count) in errors {
+        warn!("{} failed: {}", count, error);
+    }
+    Ok(())
+}
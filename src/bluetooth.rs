@@ -0,0 +1,45 @@
+//! Whether a paired phone is near the computer, so that walking away from the
+//! desk counts as being away before the screen locks
+//!
+//! Asks the system's Bluetooth tools: `bluetoothctl` (BlueZ) on Linux and
+//! `blueutil` on macOS.
+
+#[cfg(not(target_os = "windows"))]
+use tokio::process::Command;
+
+/// Whether the device with this address is connected, or was seen recently on Linux
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub async fn in_range(address: &str) -> Result<bool, String> {
+    let output = Command::new("bluetoothctl")
+        .args(["info", address])
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run bluetoothctl: {}", e))?;
+    let info = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || info.contains("not available") {
+        return Err(format!("{} is not a known Bluetooth device", address));
+    }
+    Ok(info
+        .lines()
+        .map(str::trim)
+        .any(|line| line == "Connected: yes" || line.starts_with("RSSI:")))
+}
+
+/// Whether the device with this address is connected
+#[cfg(target_os = "macos")]
+pub async fn in_range(address: &str) -> Result<bool, String> {
+    let output = Command::new("blueutil")
+        .args(["--is-connected", address])
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run blueutil: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("{} is not a known Bluetooth device", address));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "1")
+}
+
+#[cfg(target_os = "windows")]
+pub async fn in_range(_address: &str) -> Result<bool, String> {
+    Err("Bluetooth proximity is not supported on Windows".to_string())
+}
@@ -0,0 +1,39 @@
+//! How long the device has been running, so the server can tell when it rebooted
+//!
+//! Clocks that keep counting during standby are used where there is one, as a laptop waking up
+//! did not reboot.
+
+/// Seconds since the device booted
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn uptime() -> Option<u64> {
+    clock(libc::CLOCK_BOOTTIME)
+}
+
+/// Seconds since the device booted, which on macOS includes standby
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub fn uptime() -> Option<u64> {
+    clock(libc::CLOCK_MONOTONIC)
+}
+
+#[cfg(unix)]
+fn clock(id: libc::clockid_t) -> Option<u64> {
+    let mut time: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(id, &mut time) } != 0 {
+        return None;
+    }
+    time.tv_sec.try_into().ok()
+}
+
+/// Seconds since the device booted, including standby
+#[cfg(windows)]
+pub fn uptime() -> Option<u64> {
+    use windows_sys::Win32::System::SystemInformation::GetTickCount64;
+
+    Some(unsafe { GetTickCount64() } / 1000)
+}
+
+/// Not known on these systems
+#[cfg(not(any(unix, windows)))]
+pub fn uptime() -> Option<u64> {
+    None
+}
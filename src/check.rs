@@ -0,0 +1,54 @@
+//! Validating a setup without running it
+
+use std::error::Error;
+
+use crate::{
+    config::{Args, Output},
+    exit,
+    keys::read_secret_key,
+    server::check_server,
+};
+
+/// Check everything the server or client would need, reporting each problem
+pub async fn check_main(args: Args, notify: bool) -> Result<(), Box<dyn Error>> {
+    let mut results = Vec::new();
+    if let Some(client) = &args.client {
+        results.push(Ok(format!(
+            "Server address {}:{}",
+            client,
+            args.port.unwrap()
+        )));
+        if let Some(path) = &args.privkey {
+            results.push(
+                read_secret_key(path)
+                    .map(|_| format!("Private key {}", path.display()))
+                    .map_err(|e| e.to_string()),
+            );
+        }
+    }
+    if args.server || args.client.is_none() {
+        results.extend(check_server(&args, notify).await);
+    }
+    let problems = results.iter().filter(|r| r.is_err()).count();
+    if args.output == Output::Json {
+        let results: Vec<_> = results
+            .iter()
+            .map(|result| match result {
+                Ok(what) => serde_json::json!({ "ok": true, "check": what }),
+                Err(e) => serde_json::json!({ "ok": false, "error": e }),
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(results));
+    }
+    for result in results {
+        match result {
+            Ok(what) => info!("OK: {}", what),
+            Err(e) => error!("{}", e),
+        }
+    }
+    if problems > 0 {
+        return Err(exit::config(format!("{} problem(s) found", problems)));
+    }
+    info!("No problems found");
+    Ok(())
+}
@@ -1,95 +1,484 @@
-use crate::{config::Args, HeartBeat, HEARTBEAT_INTERVAL, TIMEOUT};
-use pgp::{
-    crypto::hash::HashAlgorithm,
-    types::{KeyTrait, SecretKeyTrait},
-    Deserializable, SignedSecretKey,
+use crate::{
+    bluetooth, boot,
+    config::{load_client_config, Args, Output},
+    ctl::{self, Control},
+    exit, extra,
+    hooks::{self, Hooks},
+    keys::{read_secret_key, sign_timestamp},
+    power,
+    supervisor::{self, Limits},
+    system,
+    telemetry::{Span, SpanKind},
+    wifi, DeviceState, ErrorResponse, HeartBeat, HeartbeatResponse, HEARTBEAT_INTERVAL, TIMEOUT,
+    ZOMBIE_TIMEOUT,
+};
+use pgp::{crypto::hash::HashAlgorithm, SignedSecretKey};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
 };
 use std::{
     error::Error,
-    fs::File,
-    io::Read,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    task::{JoinHandle, JoinSet},
+    time,
 };
-use tokio::time;
+
+/// Seconds a signature made ahead of time may be off, well within what the server accepts
+const PRESIGN_TOLERANCE: u64 = 2;
+/// How often a long wait looks at the wall clock
+const WAKE_CHECK: time::Duration = time::Duration::from_secs(5);
+/// Wall-clock time beyond the timers' time that counts as having been suspended
+const STANDBY_GAP: time::Duration = time::Duration::from_secs(10);
 
 pub async fn client_main(args: Args) -> Result<(), Box<dyn Error>> {
-    let privkey = if let Some(path) = args.privkey {
-        let content = File::open(path).and_then(|mut f| {
-            let mut s = String::new();
-            f.read_to_string(&mut s)?;
-            Ok(s)
-        })?;
-        let (privkey, _) = SignedSecretKey::from_string(&content)?;
-        if !privkey.is_signing_key() {
-            return Err("Private key is not a signing key".into());
+    if args.profiles.is_empty() {
+        return run(args).await;
+    }
+    let config = load_client_config(&args).map_err(exit::config)?;
+    #[cfg(feature = "tray")]
+    if args.tray {
+        if args.profiles.len() > 1 {
+            return Err("The tray icon can only show one profile".into());
         }
-        Some(privkey)
-    } else {
-        None
+        // Inline rather than spawned, as the tray has to run on the main thread
+        let name = &args.profiles[0];
+        let profile = config
+            .profiles
+            .get(name)
+            .ok_or_else(|| exit::config(format!("Unknown profile {}", name)))?;
+        info!("Running profile {}", name);
+        return run(args.with_profile(name, profile).map_err(exit::config)?).await;
+    }
+    let mut clients = JoinSet::new();
+    for name in &args.profiles {
+        let profile = config
+            .profiles
+            .get(name)
+            .ok_or_else(|| exit::config(format!("Unknown profile {}", name)))?;
+        let args = args.with_profile(name, profile).map_err(exit::config)?;
+        let name = name.clone();
+        clients.spawn(async move {
+            info!("Running profile {}", name);
+            run(args)
+                .await
+                .map_err(|e| format!("Profile {}: {}", name, e))
+        });
+    }
+    while let Some(result) = clients.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Run one client, with or without a profile
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let privkey = match &args.privkey {
+        Some(path) => Some(read_secret_key(path).map_err(exit::config)?),
+        None => None,
     };
+    let control = Arc::new(Control::new(args.message.clone()));
+    if args.once {
+        // Leaving the control socket to the client that keeps running
+        return heartbeats(args, privkey, control).await;
+    }
+    #[cfg(target_os = "windows")]
+    crate::session::watch(control.clone());
+    let path = args.ctl_socket.clone().unwrap_or_else(ctl::default_path);
+    tokio::spawn({
+        let control = control.clone();
+        async move {
+            if let Err(e) = ctl::serve(&path, control).await {
+                warn!("Control socket {}: {}", path.display(), e);
+            }
+        }
+    });
+    if let Some(port) = args.health_port {
+        tokio::spawn({
+            let control = control.clone();
+            async move {
+                if let Err(e) = crate::health::serve(port, control).await {
+                    warn!("Health endpoint on port {}: {}", port, e);
+                }
+            }
+        });
+    }
+    #[cfg(feature = "tray")]
+    if args.tray {
+        let messages = args.tray_messages.clone();
+        tokio::spawn({
+            let control = control.clone();
+            async move {
+                if let Err(e) = supervised(args, privkey, control).await {
+                    crate::exit::fail(e);
+                }
+            }
+        });
+        return tokio::task::block_in_place(|| crate::tray::run(control, messages));
+    }
+    supervised(args, privkey, control).await
+}
+
+/// The heartbeats, restarted by the supervisor if the client uses too much
+async fn supervised(
+    args: Args,
+    privkey: Option<SignedSecretKey>,
+    control: Arc<Control>,
+) -> Result<(), Box<dyn Error>> {
+    supervisor::supervise(Limits::new(&args), || {
+        let (args, privkey, control) = (args.clone(), privkey.clone(), control.clone());
+        tokio::spawn(async move {
+            heartbeats(args, privkey, control)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    })
+    .await
+}
+
+/// End `--once` with how the heartbeat went, printed as JSON with `--output json`
+fn finish_once(
+    args: &Args,
+    outcome: Result<serde_json::Value, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if args.output == Output::Json {
+        match &outcome {
+            Ok(value) => println!("{}", value),
+            Err(e) => println!(
+                "{}",
+                serde_json::json!({ "sent": false, "error": e.to_string() })
+            ),
+        }
+    }
+    outcome.map(|_| ())
+}
+
+async fn heartbeats(
+    args: Args,
+    privkey: Option<SignedSecretKey>,
+    control: Arc<Control>,
+) -> Result<(), Box<dyn Error>> {
+    let device = args.device.clone().or_else(host_name);
     let client: reqwest::Client = reqwest::Client::new();
+    let mut latency_ms = None;
+    // As suggested by the server
+    let mut interval = args.interval.unwrap_or(HEARTBEAT_INTERVAL);
+    // Grows while the server is overloaded and does not say for how long
+    let mut backoff = 0;
+    let privkey = privkey.map(Arc::new);
+    let mut presigned = None;
+    let hash = args.hash.unwrap_or_default();
+    let mut hooks = Hooks::new(
+        args.hooks.clone(),
+        args.hook_failures.unwrap_or(hooks::DEFAULT_FAILURES),
+        device.clone(),
+        &control,
+    );
     loop {
+        hooks.message(&control);
+        if control.paused() {
+            info!("Paused");
+            wait(&control, interval).await;
+            continue;
+        }
         if is_afk() {
-            println!("info: AFK");
-            tokio::time::sleep(tokio::time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+            info!("AFK");
+            if args.once {
+                let skipped = serde_json::json!({ "sent": false, "skipped": "afk" });
+                return finish_once(&args, Ok(skipped));
+            }
+            wait(&control, interval).await;
             continue;
         }
-        let timestamp = SystemTime::now()
+        if let Some(phone) = &args.bluetooth {
+            match bluetooth::in_range(phone).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("AFK, phone is not near");
+                    if args.once {
+                        let skipped = serde_json::json!({ "sent": false, "skipped": "phone" });
+                        return finish_once(&args, Ok(skipped));
+                    }
+                    wait(&control, interval).await;
+                    continue;
+                }
+                // Not knowing where the phone is must not make the user look away
+                Err(e) => warn!("{}", e),
+            }
+        }
+        let mut span = Span::new("heartbeat", SpanKind::Client, None);
+        let mut timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let signature = privkey.as_ref().map(|key| {
-            key.create_signature(
-                || "".to_string(),
-                HashAlgorithm::default(),
-                &timestamp.to_string().into_bytes(),
-            )
-            .unwrap()
-        });
+        let sign_span = span.child("sign");
+        let signature = match &privkey {
+            Some(key) => match sign(key, hash, timestamp, presigned.take()).await {
+                Ok((signed, signature)) => {
+                    timestamp = signed;
+                    Some(signature)
+                }
+                Err(e) => {
+                    drop(sign_span);
+                    span.fail(&e);
+                    error!("Cannot sign heartbeat: {}", e);
+                    control.failed(format!("cannot sign: {}", e));
+                    if args.once {
+                        return finish_once(
+                            &args,
+                            Err(exit::config(format!("Cannot sign: {}", e))),
+                        );
+                    }
+                    hooks.heartbeat(&control);
+                    drop(span);
+                    wait(&control, interval).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        drop(sign_span);
+        let battery_interval = match args.battery_interval {
+            Some(battery_interval) if power::saving().await => Some(battery_interval),
+            _ => None,
+        };
+        let extra = match &args.extra_command {
+            Some(command) => extra::fields(command)
+                .await
+                .inspect_err(|e| warn!("Extra command {}", e))
+                .ok(),
+            None => None,
+        };
         let info = HeartBeat {
             timestamp,
-            signature: signature.map(|s| s.into_iter().map(hex::encode).collect()),
+            hash: signature.as_ref().map(|_| hash.to_string()),
+            signature,
+            detached_signature: None,
+            device: device.clone(),
+            message: control.message(),
+            place: match args.places.is_empty() {
+                true => None,
+                false => wifi::place(&args.places).await,
+            },
+            latency_ms,
+            // The server takes the device for offline once it missed a few of them
+            interval: Some(battery_interval.unwrap_or(interval).max(1)),
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            uptime: boot::uptime(),
+            metrics: args.system_metrics.then(system::metrics),
+            extra,
         };
 
-        let scheme = if args.https { "https" } else { "http" };
-        let res = client
-            .post(format!(
-                "{}://{}:{}/heartbeat",
-                scheme,
-                args.client.as_ref().unwrap(),
-                args.port.unwrap()
-            ))
+        let mut req =
+            client.post(args.server_url(args.heartbeat_path.as_deref().unwrap_or("/heartbeat")));
+        if let Some(token) = &args.token {
+            req = req.bearer_auth(token);
+        }
+        let sent = Instant::now();
+        let res = req
+            .header("traceparent", span.context().traceparent())
+            .header(reqwest::header::ACCEPT, "application/json")
             .json(&info)
             .timeout(time::Duration::from_secs(TIMEOUT))
             .send()
             .await;
+        latency_ms = res
+            .is_ok()
+            .then(|| sent.elapsed().as_millis().try_into().unwrap_or(u32::MAX));
+        let mut delay = battery_interval.unwrap_or(interval);
         match res {
             Ok(res) => {
+                span.set("http.response.status_code", res.status().as_u16());
                 if res.status().is_success() {
-                    if res.text().await? == "Heartbeat received" {
-                        println!("info: Heartbeat sent");
+                    // Servers from before JSON answers only say "Heartbeat received" in plain
+                    // text, and leave the interval to the client
+                    let plain = res
+                        .headers()
+                        .get(reqwest::header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .is_some_and(|t| t.starts_with("text/plain"));
+                    let response = if plain {
+                        res.text().await.map(|_| None)
                     } else {
-                        println!("error: Heartbeat failed: invalid response");
+                        res.json::<HeartbeatResponse>().await.map(Some)
+                    };
+                    match response {
+                        Ok(response)
+                            if response.as_ref().is_none_or(|r| r.status == "received") =>
+                        {
+                            if let Some(response) = &response {
+                                if response.device_state == DeviceState::New {
+                                    info!("Registered as new device {}", response.device);
+                                }
+                                if response.device_state == DeviceState::Resumed {
+                                    hooks.offline(&control);
+                                }
+                            }
+                            info!("Heartbeat sent");
+                            control.succeeded(timestamp);
+                            if args.once {
+                                let mut sent = match &response {
+                                    Some(response) => serde_json::to_value(response)?,
+                                    None => serde_json::json!({}),
+                                };
+                                sent["sent"] = true.into();
+                                sent["timestamp"] = timestamp.into();
+                                return finish_once(&args, Ok(sent));
+                            }
+                            // Echoing the battery interval while that was announced
+                            if let (Some(response), None, None) =
+                                (&response, args.interval, battery_interval)
+                            {
+                                interval = response.next_interval.clamp(1, ZOMBIE_TIMEOUT);
+                            }
+                            delay = battery_interval.unwrap_or(interval);
+                            backoff = 0;
+                        }
+                        _ => {
+                            span.fail("invalid response");
+                            error!("Heartbeat failed: invalid response");
+                            control.failed("invalid response".to_string());
+                            if args.once {
+                                return finish_once(&args, Err("Invalid response".into()));
+                            }
+                        }
                     }
                 } else {
-                    println!("error: Heartbeat failed: {}", res.status());
+                    let status = res.status();
+                    if status == StatusCode::TOO_MANY_REQUESTS
+                        || status == StatusCode::SERVICE_UNAVAILABLE
+                    {
+                        backoff = (backoff * 2).clamp(interval, ZOMBIE_TIMEOUT);
+                        control.set_backoff(backoff);
+                        // At least a second, so that `Retry-After: 0` cannot make it spin
+                        delay = retry_after(res.headers())
+                            .unwrap_or(backoff)
+                            .clamp(1, ZOMBIE_TIMEOUT);
+                        warn!("Server is busy, next heartbeat in {}s", delay);
+                    }
+                    let reason = match res.json::<ErrorResponse>().await {
+                        Ok(e) => {
+                            if e.code == "timestamp_stale" {
+                                warn!(
+                                    "The clock seems to be off, {}",
+                                    e.detail.unwrap_or_default()
+                                );
+                            } else if e.code == "hash_rejected" {
+                                warn!("Choose another --hash, {}", e.detail.unwrap_or_default());
+                            } else if e.code == "client_outdated" {
+                                warn!("{}", e.detail.unwrap_or_default());
+                            }
+                            format!("{} ({}: {})", status, e.code, e.message)
+                        }
+                        Err(_) => status.to_string(),
+                    };
+                    span.fail(&reason);
+                    error!("Heartbeat failed: {}", reason);
+                    if args.once {
+                        return finish_once(&args, Err(exit::status(status, reason)));
+                    }
+                    control.failed(reason);
                 }
             }
             Err(e) => {
-                println!("error: Heartbeat failed: {}", e);
+                span.fail(&e);
+                error!("Heartbeat failed: {}", e);
+                if args.once {
+                    return finish_once(&args, Err(exit::network(e)));
+                }
+                control.failed(e.to_string());
             }
         };
+        drop(span);
+        hooks.heartbeat(&control);
+
+        if let (true, Some(key)) = (args.presign, &privkey) {
+            let next = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + delay;
+            let key = key.clone();
+            let handle = tokio::task::spawn_blocking(move || sign_timestamp(&key, hash, next));
+            presigned = Some((next, handle));
+        }
+        if wait(&control, delay).await {
+            info!("Resumed from standby");
+            // Made for a time long past, so it would only be thrown away
+            presigned = None;
+        }
+    }
+}
+
+/// A signature made ahead of time, and the timestamp it is for
+type Presigned = (u64, JoinHandle<pgp::errors::Result<Vec<String>>>);
+
+/// Sign `timestamp`, unless the signature made ahead of time is close enough to it
+async fn sign(
+    key: &Arc<SignedSecretKey>,
+    hash: HashAlgorithm,
+    timestamp: u64,
+    presigned: Option<Presigned>,
+) -> Result<(u64, Vec<String>), String> {
+    if let Some((signed, handle)) = presigned {
+        if signed.abs_diff(timestamp) <= PRESIGN_TOLERANCE {
+            if let Ok(Ok(signature)) = handle.await {
+                return Ok((signed, signature));
+            }
+        }
+    }
+    let key = key.clone();
+    tokio::task::spawn_blocking(move || sign_timestamp(&key, hash, timestamp))
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|signature| (timestamp, signature))
+        .map_err(|e| e.to_string())
+}
+
+/// Seconds to wait according to a `Retry-After` header, given in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.timestamp() - chrono::Utc::now().timestamp()).max(0) as u64)
+}
 
-        time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+/// Sleep for `interval` seconds, or until a command asks for a heartbeat, and tell whether the
+/// machine was suspended meanwhile
+///
+/// The monotonic clock timers use stands still during standby on some platforms, so this
+/// sleeps in steps and also ends once the wall clock says the interval is over. After resuming,
+/// the next heartbeat therefore goes out right away, not only after the rest of the interval.
+/// Otherwise the monotonic clock decides, so that setting the wall clock back, like NTP may,
+/// does not hold heartbeats off.
+async fn wait(control: &Control, interval: u64) -> bool {
+    let start = (Instant::now(), SystemTime::now());
+    let interval = time::Duration::from_secs(interval);
+    let deadline = (time::Instant::now() + interval, start.1 + interval);
+    let sleep = async {
+        while let (Some(left), Ok(_)) = (
+            deadline.0.checked_duration_since(time::Instant::now()),
+            deadline.1.duration_since(SystemTime::now()),
+        ) {
+            time::sleep(left.min(WAKE_CHECK)).await;
+        }
+    };
+    tokio::select! {
+        _ = sleep => {}
+        _ = control.wake.notified() => {}
     }
+    let slept = start.1.elapsed().unwrap_or_default();
+    slept.saturating_sub(start.0.elapsed()) > STANDBY_GAP
 }
 
 #[cfg(target_os = "windows")]
 fn is_afk() -> bool {
-    sysinfo::System::new_all()
-        .processes()
-        .iter()
-        .any(|(_, p)| p.name().to_ascii_lowercase() == "logonui.exe")
+    crate::session::is_locked()
 }
 
 #[cfg(target_os = "macos")]
@@ -110,3 +499,69 @@ fn is_afk() -> bool {
 fn is_afk() -> bool {
     false
 }
+
+/// Name of this device if `--device` is not given
+#[cfg(target_os = "windows")]
+fn host_name() -> Option<String> {
+    use windows_sys::Win32::System::SystemInformation::{
+        ComputerNamePhysicalDnsHostname, GetComputerNameExW,
+    };
+
+    let mut buffer = [0u16; 256];
+    let mut len = buffer.len() as u32;
+    // SAFETY: the buffer holds `len` characters, which becomes the length of the name
+    let ok = unsafe {
+        GetComputerNameExW(
+            ComputerNamePhysicalDnsHostname,
+            buffer.as_mut_ptr(),
+            &mut len,
+        )
+    } != 0;
+    ok.then(|| String::from_utf16_lossy(&buffer[..len as usize]))
+        .filter(|name| !name.is_empty())
+}
+
+/// Name of this device if `--device` is not given
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is as long as told, and the name in it ends with a NUL if it fits
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return None;
+    }
+    let len = buffer.iter().position(|&b| b == 0)?;
+    String::from_utf8(buffer[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, retry_after.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(retry_after(&headers("120")), Some(120));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(0));
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("-5")), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let in_a_minute = chrono::Utc::now() + chrono::Duration::seconds(60);
+        let seconds = retry_after(&headers(&in_a_minute.to_rfc2822())).unwrap();
+        assert!((58..=60).contains(&seconds), "{}", seconds);
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(0)
+        );
+    }
+}
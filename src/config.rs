@@ -1,35 +1,1069 @@
-use std::{error::Error, net::ToSocketAddrs, path::PathBuf};
+use std::{collections::BTreeMap, env, error::Error, fmt, fs, net::ToSocketAddrs, path::PathBuf};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use ipnet::IpNet;
+use pgp::crypto::hash::HashAlgorithm;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer,
+};
 
-#[derive(Parser, Debug)]
+use crate::{
+    admin::AdminCommand,
+    ctl::{self, CtlCommand},
+    hooks::{self, HookEvent},
+    keys,
+    logging::{self, LogSink, Rotation},
+    supervisor::LimitAction,
+    wifi, MAX_INTERVAL,
+};
+
+#[derive(Parser, Debug, Clone)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Run the program as a server
-    #[arg(short = 's', long)]
+    #[arg(short = 's', long, global = true, env = "ONLINE_STATUS_SERVER")]
     pub server: bool,
     /// Run the program as a client
-    #[arg(short = 'c', long)]
+    #[arg(short = 'c', long, global = true, env = "ONLINE_STATUS_CLIENT")]
     pub client: Option<String>,
     /// Port number
-    #[arg(short = 'p', long)]
+    #[arg(short = 'p', long, global = true, env = "ONLINE_STATUS_PORT")]
     pub port: Option<u16>,
     /// Whether use HTTPS in client mode
-    #[arg(long)]
+    #[arg(long, global = true, env = "ONLINE_STATUS_HTTPS")]
     pub https: bool,
     /// Path to public key file (optional for server)
-    #[arg(long, value_name = "FILE")]
+    #[arg(long, value_name = "FILE", global = true, env = "ONLINE_STATUS_PUBKEY")]
     pub pubkey: Option<PathBuf>,
     /// Path to private key file (optional for client)
-    #[arg(long, value_name = "FILE")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        global = true,
+        env = "ONLINE_STATUS_PRIVKEY"
+    )]
+    pub privkey: Option<PathBuf>,
+    /// Path to server configuration file (reloaded on SIGHUP), or client one with profiles
+    #[arg(long, value_name = "FILE", global = true, env = "ONLINE_STATUS_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Client profile from the config file to run (repeatable, to run several at once)
+    #[arg(
+        long = "profile",
+        value_name = "NAME",
+        global = true,
+        env = "ONLINE_STATUS_PROFILE",
+        value_delimiter = ','
+    )]
+    pub profiles: Vec<String>,
+    /// Token identifying the user in client mode (alternative to --privkey), or the admin token for `admin`
+    #[arg(long, global = true, env = "ONLINE_STATUS_TOKEN")]
+    pub token: Option<String>,
+    /// Name of this device in client mode (defaults to the host name)
+    #[arg(long, env = "ONLINE_STATUS_DEVICE")]
+    pub device: Option<String>,
+    /// Status message to show in client mode
+    #[arg(long, env = "ONLINE_STATUS_MESSAGE")]
+    pub message: Option<String>,
+    /// Seconds between heartbeats in client mode, instead of what the server asks for
+    #[arg(long, value_name = "SECONDS", env = "ONLINE_STATUS_INTERVAL")]
+    pub interval: Option<u64>,
+    /// Seconds between heartbeats in client mode while on battery or a cellular connection
+    #[arg(long, value_name = "SECONDS", env = "ONLINE_STATUS_BATTERY_INTERVAL")]
+    pub battery_interval: Option<u64>,
+    /// Count as away while the paired phone with this Bluetooth address is not near
+    #[arg(long, value_name = "ADDRESS", env = "ONLINE_STATUS_BLUETOOTH")]
+    pub bluetooth: Option<String>,
+    /// Tell the server where you are by the Wi-Fi network, e.g. `work=CorpWiFi` (repeatable)
+    #[arg(
+        long = "place",
+        value_name = "LABEL=SSID",
+        value_parser = wifi::parse_place,
+        env = "ONLINE_STATUS_PLACE",
+        value_delimiter = '\n'
+    )]
+    pub places: Vec<(String, String)>,
+    /// Run a shell command on an event of the client, e.g. `dnd-on=<command>` (repeatable)
+    #[arg(
+        long = "on",
+        value_name = "EVENT=COMMAND",
+        value_parser = hooks::parse_hook,
+        env = "ONLINE_STATUS_ON",
+        value_delimiter = '\n'
+    )]
+    pub hooks: Vec<(HookEvent, String)>,
+    /// Failed heartbeats in a row after which the `failing` hook runs (defaults to 3)
+    #[arg(long, value_name = "N", env = "ONLINE_STATUS_HOOK_FAILURES")]
+    pub hook_failures: Option<u64>,
+    /// Report the client's own health on http://127.0.0.1:PORT/health and /metrics
+    #[arg(long, value_name = "PORT", env = "ONLINE_STATUS_HEALTH_PORT")]
+    pub health_port: Option<u16>,
+    /// Megabytes of memory the client may use before it restarts its heartbeats or exits
+    #[arg(long, value_name = "MB", env = "ONLINE_STATUS_MAX_MEMORY")]
+    pub max_memory: Option<u64>,
+    /// Percent of a CPU core the client may keep using before it restarts its heartbeats or exits
+    #[arg(long, value_name = "PERCENT", env = "ONLINE_STATUS_MAX_CPU")]
+    pub max_cpu: Option<f64>,
+    /// What to do once over --max-memory or --max-cpu
+    #[arg(long, value_enum, default_value_t = LimitAction::Restart, env = "ONLINE_STATUS_ON_LIMIT")]
+    pub on_limit: LimitAction,
+    /// Sign the next heartbeat while waiting for it, so slow devices send it right away
+    #[arg(long, env = "ONLINE_STATUS_PRESIGN")]
+    pub presign: bool,
+    /// Attach the load, memory and disk use of the device to heartbeats
+    #[arg(long, env = "ONLINE_STATUS_SYSTEM_METRICS")]
+    pub system_metrics: bool,
+    /// Run a shell command before every heartbeat and attach the JSON object it prints as
+    /// custom fields
+    #[arg(long, value_name = "COMMAND", env = "ONLINE_STATUS_EXTRA_COMMAND")]
+    pub extra_command: Option<String>,
+    /// Hash algorithm to sign heartbeats with in client mode, e.g. SHA512 (defaults to SHA256)
+    #[arg(
+        long,
+        value_name = "ALGORITHM",
+        value_parser = keys::parse_hash,
+        env = "ONLINE_STATUS_HASH"
+    )]
+    pub hash: Option<HashAlgorithm>,
+    /// Where to write the log
+    #[arg(long, value_enum, default_value_t = LogSink::Stdout, env = "ONLINE_STATUS_LOG_SINK")]
+    pub log_sink: LogSink,
+    /// Write the log to this file instead
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "log_sink",
+        env = "ONLINE_STATUS_LOG_FILE"
+    )]
+    pub log_file: Option<PathBuf>,
+    /// Start a new log file once it exceeds this many megabytes (0 for no limit)
+    #[arg(
+        long,
+        value_name = "MB",
+        default_value_t = 10,
+        env = "ONLINE_STATUS_LOG_MAX_SIZE"
+    )]
+    pub log_max_size: u64,
+    /// Also start a new log file periodically
+    #[arg(long, value_enum, default_value_t = Rotation::Never, env = "ONLINE_STATUS_LOG_ROTATE")]
+    pub log_rotate: Rotation,
+    /// Number of old log files to keep
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        env = "ONLINE_STATUS_LOG_KEEP"
+    )]
+    pub log_keep: usize,
+    /// Show an icon in the system tray in client mode
+    #[cfg(feature = "tray")]
+    #[arg(long, env = "ONLINE_STATUS_TRAY")]
+    pub tray: bool,
+    /// Status message offered by the tray menu (repeatable)
+    #[cfg(feature = "tray")]
+    #[arg(
+        long = "tray-message",
+        value_name = "TEXT",
+        env = "ONLINE_STATUS_TRAY_MESSAGE",
+        value_delimiter = '\n'
+    )]
+    pub tray_messages: Vec<String>,
+    /// Path the server is reachable under, e.g. /presence behind a reverse proxy
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        env = "ONLINE_STATUS_BASE_PATH"
+    )]
+    pub base_path: Option<String>,
+    /// Path heartbeats are sent to, if the server's heartbeat_path is changed
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        env = "ONLINE_STATUS_HEARTBEAT_PATH"
+    )]
+    pub heartbeat_path: Option<String>,
+    /// Control socket of the client (a named pipe on Windows)
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        env = "ONLINE_STATUS_CTL_SOCKET"
+    )]
+    pub ctl_socket: Option<PathBuf>,
+    /// Export traces to this OTLP/HTTP collector, e.g. http://localhost:4318
+    #[arg(long, value_name = "URL", env = "ONLINE_STATUS_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// Send one heartbeat in client mode, unless away, and exit, with an error code if it failed
+    #[arg(long, env = "ONLINE_STATUS_ONCE")]
+    pub once: bool,
+    /// How `ctl`, `admin`, `check` and `--once` print their results, logging to stderr for json
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Output::Text,
+        global = true,
+        env = "ONLINE_STATUS_OUTPUT"
+    )]
+    pub output: Output,
+}
+
+/// How results are printed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    /// One JSON document on stdout
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Validate the configuration, key files, bind address and webhooks without running
+    Check {
+        /// Also send a test event to every webhook
+        #[arg(long)]
+        notify: bool,
+    },
+    /// Send a heartbeat signed with --privkey (or carrying --token) to an in-process server
+    /// with this configuration and check that the status becomes ONLINE; with -c, also
+    /// compare the clock with that server's
+    Selftest,
+    /// Simulate many clients sending heartbeats to the server given with -c
+    Bench {
+        /// Number of simulated devices
+        #[arg(long, default_value_t = 100)]
+        clients: usize,
+        /// Heartbeats per second, across all devices
+        #[arg(long, default_value_t = 10.0)]
+        rate: f64,
+        /// How long to run, in seconds
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+    },
+    /// Control the running client
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Manage the server given with -c, authenticating with --token as its admin_token
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+    /// Write the --config, its public keys and history, and with -c the devices known to that
+    /// running server, to one file for moving to a new host
+    ExportBundle {
+        /// File to write, stdout if not given
+        #[arg(long, short = 'o', value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Restore the files of an exported bundle, or with -c its devices into that running server
+    ImportBundle {
+        bundle: PathBuf,
+        /// Overwrite files that exist with other content
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the completions of a shell, e.g. to ~/.local/share/bash-completion/completions
+    Completions { shell: Shell },
+    /// Print the man page in roff, e.g. to /usr/share/man/man1/online_status.1
+    Manpage,
+}
+
+/// Client settings, from the config file given in client mode
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Sets of client options, chosen with `--profile`
+    pub profiles: BTreeMap<String, ClientProfile>,
+}
+
+/// Client options of one profile, overriding those on the command line
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClientProfile {
+    /// Server to send heartbeats to, like `-c`
+    pub server: String,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub https: bool,
+    pub privkey: Option<PathBuf>,
+    pub token: Option<String>,
+    pub device: Option<String>,
+    pub message: Option<String>,
+    pub hash: Option<String>,
+    pub interval: Option<u64>,
+    pub battery_interval: Option<u64>,
+    pub base_path: Option<String>,
+    pub heartbeat_path: Option<String>,
+    /// Port of this profile's health endpoint, as profiles cannot share one
+    pub health_port: Option<u16>,
+    /// Commands to run by event, replacing those given with `--on`
+    #[serde(default)]
+    pub on: BTreeMap<String, String>,
+    pub hook_failures: Option<u64>,
+    /// Like `--system-metrics`
+    #[serde(default)]
+    pub system_metrics: bool,
+    pub extra_command: Option<String>,
+}
+
+/// Server settings that can be changed without a restart
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Public key files of the default user, whose status is at `/status`
+    pub pubkeys: Vec<PathBuf>,
+    /// Bearer tokens of the default user
+    pub tokens: Vec<String>,
+    /// Tokens of single devices of the default user, by device name, only good for `/beat`
+    pub device_tokens: BTreeMap<String, String>,
+    /// What anonymous viewers may see of the default user
+    pub privacy: Privacy,
+    /// Tokens that let viewers see everything about the default user
+    pub viewer_tokens: Vec<String>,
+    /// Named viewers and what they may see of the default user
+    pub friends: BTreeMap<String, Privacy>,
+    /// Going offline during these hours is not an incident for the default user
+    pub quiet_hours: Option<QuietHours>,
+    /// The only device the plain `/status` of the default user reflects
+    pub primary_device: Option<String>,
+    /// Device names or key fingerprints that do not count towards the default user being online
+    pub ignore_devices: Vec<String>,
+    /// Further users, whose status is at `/u/<name>/status`
+    pub users: BTreeMap<String, UserConfig>,
+    /// People that can authenticate when viewing a status
+    pub viewers: BTreeMap<String, ViewerConfig>,
+    /// Name of the default user on `/roster`
+    pub name: Option<String>,
+    /// Other servers whose status is shown on `/roster`
+    pub peers: Vec<PeerConfig>,
+    /// Seconds between polls of the peers
+    pub peer_interval: Option<u64>,
+    /// Upstream server that accepted heartbeats are passed on to
+    pub relay: Option<RelayConfig>,
+    /// Other instances of this server sharing the same clients
+    pub cluster: Option<ClusterConfig>,
+    /// Primary server this one only mirrors the status of, without receiving heartbeats
+    pub mirror: Option<MirrorConfig>,
+    /// Devices that are online while they are on the local network
+    pub lan: Option<LanConfig>,
+    /// Calendars during whose events their user is busy rather than online
+    pub calendars: Vec<CalendarConfig>,
+    /// Other systems that may report presence at `/external/<source>`
+    pub external: Vec<ExternalSource>,
+    /// The server's own presence, as a device
+    pub canary: Option<CanaryConfig>,
+    /// What to do once a user has been offline for long
+    pub dead_man: Vec<DeadManConfig>,
+    /// Webhooks told ever later while a user stays offline
+    pub escalation: Vec<EscalationConfig>,
+    /// Rules on the system metrics devices report, sent as events when they fire
+    pub alert: Vec<AlertConfig>,
+    /// File to append one line per request to, or `-` for stdout
+    pub access_log: Option<PathBuf>,
+    /// Webhooks that are sent events as JSON
+    pub notify: Vec<NotifyConfig>,
+    /// Commands run on events
+    pub exec: Vec<ExecConfig>,
+    /// IRC channel to announce events in and answer `!status` queries
+    pub irc: Option<IrcConfig>,
+    /// Matrix room to announce events in (needs the `matrix` feature)
+    pub matrix: Option<MatrixConfig>,
+    /// NATS server every accepted heartbeat is published to (needs the `nats` feature)
+    pub nats: Option<NatsConfig>,
+    /// StatsD or InfluxDB server the state of every user is pushed to
+    pub push: Option<PushConfig>,
+    /// Slack account whose status follows a user's
+    pub slack: Option<SlackConfig>,
+    /// GitHub account whose profile status follows a user's
+    pub github: Option<GithubConfig>,
+    /// Stability score (0 to 1) below which a device counts as flaky
+    pub flaky_threshold: Option<f64>,
+    /// Template for the plain-text `/status`, e.g. `{{state}} since {{duration}}`
+    pub status_template: Option<String>,
+    /// Rhai script computing the status of every user (needs the `scripting` feature)
+    pub status_script: Option<PathBuf>,
+    /// Directory of WASI plugins (needs the `plugins` feature)
+    pub plugins: Option<PathBuf>,
+    /// MaxMind City or Country database locating devices (needs the `geoip` feature)
+    pub geoip: Option<PathBuf>,
+    /// MaxMind ASN database naming the network of devices (needs the `geoip` feature)
+    pub geoip_asn: Option<PathBuf>,
+    /// File the incident log is kept in across restarts
+    pub history: Option<PathBuf>,
+    /// File admin actions and reloads are appended to, to keep them across restarts
+    pub audit_log: Option<PathBuf>,
+    /// Days incidents are kept after they ended, archived devices after they were archived, and
+    /// audit log entries, forever if unset
+    pub retention_days: Option<u64>,
+    /// Seconds after startup in which users with no device online yet keep their state from
+    /// the history, or are `UNKNOWN`, instead of going offline
+    pub warmup: Option<u64>,
+    /// Bearer token for the `/admin` endpoints, which are disabled if unset
+    pub admin_token: Option<String>,
+    /// Identity provider admins can log in with instead of the admin token
+    pub oidc: Option<OidcConfig>,
+    /// Key share links are signed with, which are disabled if unset
+    pub share_secret: Option<String>,
+    /// Path heartbeats are received at instead of `/heartbeat`
+    pub heartbeat_path: Option<String>,
+    /// Path of the plain status instead of `/status`, the JSON one gets `.json` appended
+    pub status_path: Option<String>,
+    /// Networks heartbeats are accepted from, any if empty
+    pub heartbeat_allow: Vec<IpNet>,
+    /// Networks heartbeats are never accepted from
+    pub heartbeat_deny: Vec<IpNet>,
+    /// How client addresses are stored and logged
+    pub ip_addresses: IpAddresses,
+    /// Salt for hashing client addresses, random on every start if unset
+    pub ip_salt: Option<String>,
+    /// Hash algorithms heartbeat and viewer signatures may use, all but weak ones if empty
+    pub hash_algorithms: Vec<String>,
+    /// Secret key of the server itself, used to sign `/status.json`
+    pub server_key: Option<PathBuf>,
+    /// Let another server bind the port while this one is still running (Unix only)
+    pub reuse_port: bool,
+    /// Seconds open connections get to finish once the server is asked to stop
+    pub drain_timeout: Option<u64>,
+    /// Heartbeats of clients older than this version, like `0.2.0`, are rejected
+    pub min_client_version: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    /// Public key files, a heartbeat signed by any of them counts for this user
+    pub pubkeys: Vec<PathBuf>,
+    /// Bearer tokens, a heartbeat carrying any of them counts for this user
+    pub tokens: Vec<String>,
+    /// Tokens of single devices, by device name, only good for `/beat`
+    pub device_tokens: BTreeMap<String, String>,
+    /// What anonymous viewers may see
+    pub privacy: Privacy,
+    /// Tokens that let viewers see everything
+    pub viewer_tokens: Vec<String>,
+    /// Named viewers and what they may see
+    pub friends: BTreeMap<String, Privacy>,
+    /// Going offline during these hours is not an incident
+    pub quiet_hours: Option<QuietHours>,
+    /// The only device the plain `/status` reflects
+    pub primary_device: Option<String>,
+    /// Device names or key fingerprints that do not count towards being online
+    pub ignore_devices: Vec<String>,
+}
+
+/// A daily time range in the server's local time, like `22:00-08:00`
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct QuietHours {
+    /// Minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    /// Whether a minute of the day (0 to 1439) falls into the range
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let minutes = |s: &str| {
+            let (h, m) = s.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        value
+            .split_once('-')
+            .and_then(|(start, end)| Some((minutes(start)?, minutes(end)?)))
+            .map(|(start, end)| QuietHours { start, end })
+            .ok_or_else(|| format!("Invalid time range {:?}, expected HH:MM-HH:MM", value))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewerConfig {
+    /// Bearer tokens identifying this viewer
+    pub tokens: Vec<String>,
+    /// Public key files, requests signed by any of them identify this viewer
+    pub pubkeys: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    /// Name shown on `/roster`
+    pub name: String,
+    /// URL of the peer's `status.json`
+    pub url: String,
+    /// Viewer token for the peer, if it is not public
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    /// URL of the upstream `heartbeat` endpoint
+    pub url: String,
+    /// Re-sign heartbeats with this key instead of forwarding them unchanged
     pub privkey: Option<PathBuf>,
+    /// Re-send heartbeats with this token instead of forwarding them unchanged
+    pub token: Option<String>,
+    /// Hash algorithm to re-sign heartbeats with, e.g. SHA512 (defaults to SHA256)
+    pub hash: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Base URLs of the other nodes, e.g. `http://10.0.0.2:8080`
+    pub nodes: Vec<String>,
+    /// Shared secret all nodes authenticate with
+    pub secret: String,
+    /// Seconds between two rounds of gossip
+    pub interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Base URL of the primary, e.g. `http://10.0.0.1:8080`
+    pub primary: String,
+    /// The primary's cluster secret
+    pub secret: String,
+    /// Seconds between two polls
+    pub interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LanConfig {
+    /// ARP table to look for devices in, `/proc/net/arp` if no lease file is given either
+    pub arp_table: Option<PathBuf>,
+    /// dnsmasq lease file to look for devices in, e.g. `/var/lib/misc/dnsmasq.leases`
+    pub dhcp_leases: Option<PathBuf>,
+    /// Seconds between two looks
+    pub interval: Option<u64>,
+    pub devices: Vec<LanDevice>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarConfig {
+    /// iCalendar feed, like the secret address of a Google calendar or a CalDAV calendar's URL
+    pub url: String,
+    /// User it belongs to, the default user if not given
+    pub user: Option<String>,
+    /// Shown instead of ONLINE during events, `BUSY` if not given
+    pub status: Option<String>,
+    /// Shown after the status in parentheses, `in a meeting` if not given
+    pub label: Option<String>,
+    /// Seconds between two fetches
+    pub interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LanDevice {
+    /// MAC address, e.g. `aa:bb:cc:dd:ee:ff`
+    pub mac: String,
+    /// Device name it is shown under
+    pub name: String,
+    /// User it belongs to, the default user if unset
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalSource {
+    /// Name in the path, which is also the name of the virtual device, e.g. `router`
+    pub source: String,
+    pub token: String,
+    /// User it reports the presence of, the default user if unset
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// Name of the device, `server` if unset
+    pub device: Option<String>,
+    /// User the device belongs to, the default user if unset
+    pub user: Option<String>,
+    pub message: Option<String>,
+    /// `heartbeat` endpoint of another server, which watches this one
+    pub upstream: Option<String>,
+    /// Token the heartbeats carry upstream
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeadManConfig {
+    /// User watched, the default user if unset
+    pub user: Option<String>,
+    /// Hours all devices must have been offline for
+    pub hours: u64,
+    /// Program to run, followed by its arguments
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// URL the `dead_man` event is POSTed to
+    pub webhook: Option<String>,
+    /// Address mailed through `sendmail`
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EscalationConfig {
+    /// User watched, the default user if unset
+    pub user: Option<String>,
+    /// Minutes all devices must have been offline for
+    pub minutes: u64,
+    /// URL the `escalated` event, and later the `recovered` one, are POSTed to
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub metric: Metric,
+    /// Fires once the metric is above this
+    pub above: f64,
+    /// Minutes the metric must stay above, 0 if unset
+    #[serde(default)]
+    pub minutes: u64,
+    /// Only devices of this user, any user if unset
+    pub user: Option<String>,
+    /// Only this device, any device if unset
+    pub device: Option<String>,
+}
+
+/// One of the system metrics of a device
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Load,
+    MemoryPercent,
+    DiskPercent,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// URL the events are POSTed to
+    pub url: String,
+    /// Only send these events, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// URL of the provider, where `/.well-known/openid-configuration` is found
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends the browser back to, the server's URL of `/login/callback`
+    pub redirect_url: String,
+    /// Email addresses or subjects of the users who may log in
+    pub admins: Vec<String>,
+    /// Hours a login lasts, 12 if unset
+    pub session_hours: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    /// Program to run, followed by its arguments, which may contain `{{user}}` and the like
+    pub command: Vec<String>,
+    /// Only run on these events, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Seconds after which the program is killed, 5 if unset
+    pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IrcConfig {
+    /// Host name of the IRC server, like `irc.libera.chat`
+    pub server: String,
+    /// 6697, or 6667 for plain connections, if not given
+    pub port: Option<u16>,
+    /// Connect without TLS
+    #[serde(default)]
+    pub plain: bool,
+    /// Server password
+    pub password: Option<String>,
+    pub nick: String,
+    pub channel: String,
+    /// Events announced in the channel, `user_online` and `user_offline` if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    /// Host name of the server, like `nats.example.com`, or its URL, like
+    /// `tls://nats.example.com:4222`
+    pub server: String,
+    /// 4222 if not given, and unless `server` is a URL
+    pub port: Option<u16>,
+    /// Connect with TLS even if the server does not ask for it
+    #[serde(default)]
+    pub tls: bool,
+    /// `online_status.heartbeats` if not given
+    pub subject: Option<String>,
+    /// Authentication token, or `user` and `password`, or a `.creds` file
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub credentials: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PushConfig {
+    /// Address of a StatsD server, like `127.0.0.1:8125`
+    pub statsd: Option<String>,
+    /// InfluxDB write URL, like `http://localhost:8086/api/v2/write?org=home&bucket=presence`
+    pub influx: Option<String>,
+    /// API token for InfluxDB
+    pub influx_token: Option<String>,
+    /// Seconds between two pushes, 60 if not given
+    pub interval: Option<u64>,
+    /// Start of the metric names, `online_status` if not given
+    pub prefix: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    /// Base URL of the bot account's homeserver, like `https://matrix.org`
+    pub homeserver: String,
+    pub access_token: String,
+    /// ID of the room, like `!abcdefg:matrix.org`, which the bot has joined
+    pub room: Option<String>,
+    /// Events announced in the room, `user_online` and `user_offline` if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// User whose status the presence of the bot account mirrors, `""` for the default user
+    pub presence: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    /// User token with the `users.profile:write` and `users:write` scopes
+    pub token: String,
+    /// User whose status is synced, the default user if not given
+    pub user: Option<String>,
+    /// Emoji of statuses like `BUSY (in a meeting)`, `:no_entry:` if not given
+    pub busy_emoji: Option<String>,
+    /// Emoji of the message while online, `:speech_balloon:` if not given
+    pub message_emoji: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GithubConfig {
+    /// Token with the `user` scope
+    pub token: String,
+    /// User whose status is synced, the default user if not given
+    pub user: Option<String>,
+    /// Seconds a status must last before it is set, 120 if not given
+    pub debounce: Option<u64>,
+    /// Seconds at least between two updates, 300 if not given
+    pub min_interval: Option<u64>,
+    /// Emoji of statuses like `BUSY (in a meeting)`, `:no_entry:` if not given
+    pub busy_emoji: Option<String>,
+    /// Emoji of the message while online, `:speech_balloon:` if not given
+    pub message_emoji: Option<String>,
+}
+
+/// How much of a user's status is shown, from least to most
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+    /// Nothing without a viewer token
+    Private,
+    /// Only ONLINE/OFFLINE
+    #[default]
+    Aggregate,
+    /// Also the list of devices and when they were last seen
+    Devices,
+    /// Also the status messages
+    Messages,
+}
+
+/// What the server keeps of client addresses
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpAddresses {
+    /// Stored and logged as they are
+    #[default]
+    Keep,
+    /// Stored and logged as salted hashes
+    Hash,
+    /// Stored as salted hashes, and left out of logs
+    Redact,
+}
+
+/// Prefix of the environment variables options are read from
+const ENV_PREFIX: &str = "ONLINE_STATUS_";
+
+/// The error [`FieldNames`] stops with, holding the fields of the struct asked for
+#[derive(Debug)]
+struct Fields(&'static [&'static str]);
+
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fields: {:?}", self.0)
+    }
+}
+
+impl Error for Fields {}
+
+impl de::Error for Fields {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        Fields(&[])
+    }
+}
+
+/// A deserializer that only finds out the fields of a struct
+struct FieldNames;
+
+impl<'de> Deserializer<'de> for FieldNames {
+    type Error = Fields;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Fields> {
+        Err(Fields(&[]))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Fields> {
+        Err(Fields(fields))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// The top-level keys of the config file
+fn config_keys() -> &'static [&'static str] {
+    match ServerConfig::deserialize(FieldNames) {
+        Err(Fields(fields)) => fields,
+        Ok(_) => &[],
+    }
+}
+
+/// Config file keys set by `ONLINE_STATUS_*` variables that are not arguments,
+/// with `__` between nested keys, e.g. `ONLINE_STATUS_USERS__BOB__TOKENS`.
+/// Values are TOML, or taken as strings if they do not parse.
+fn env_config() -> Result<toml::Table, Box<dyn Error>> {
+    config_from_vars(env::vars())
+}
+
+/// Like [`env_config`], but from the given variables. Those naming no key of the config file
+/// are left out with a warning, so that a stray variable does not stop the server.
+fn config_from_vars(
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<toml::Table, Box<dyn Error>> {
+    let command = Args::command();
+    let arguments: Vec<_> = command
+        .get_arguments()
+        .filter_map(|a| a.get_env())
+        .collect();
+    let keys = config_keys();
+    let mut table = toml::Table::new();
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if arguments.iter().any(|a| *a == name.as_str()) {
+            continue;
+        }
+        let top = key.split("__").next().unwrap_or_default().to_lowercase();
+        if !keys.contains(&top.as_str()) {
+            warn!("Ignoring {}, which sets no key of the config file", name);
+            continue;
+        }
+        let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+        let key = key.to_lowercase();
+        let mut path: Vec<&str> = key.split("__").collect();
+        let last = path.pop().unwrap();
+        let mut parent = &mut table;
+        for part in path {
+            parent = parent
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{}: {} is not a table", name, part))?;
+        }
+        parent.insert(last.to_string(), value);
+    }
+    Ok(table)
+}
+
+/// Merge `other` into `base`, replacing everything but tables
+fn merge(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(other)) => merge(base, other),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Read the configuration file (if any) and merge in the command line options
+pub fn load_server_config(args: &Args) -> Result<ServerConfig, Box<dyn Error>> {
+    let mut table: toml::Table = match &args.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        None => toml::Table::new(),
+    };
+    merge(&mut table, env_config()?);
+    let mut config: ServerConfig = toml::Value::Table(table).try_into()?;
+    if let Some(pubkey) = &args.pubkey {
+        config.pubkeys.push(pubkey.clone());
+    }
+    for name in config.users.keys() {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("Invalid user name: {:?}", name).into());
+        }
+    }
+    let friends = config
+        .friends
+        .keys()
+        .chain(config.users.values().flat_map(|u| u.friends.keys()));
+    for name in friends {
+        if !config.viewers.contains_key(name) {
+            return Err(format!("Unknown viewer: {:?}", name).into());
+        }
+    }
+    Ok(config)
+}
+
+pub fn load_client_config(args: &Args) -> Result<ClientConfig, Box<dyn Error>> {
+    let Some(path) = &args.config else {
+        return Err("Profiles need a config file, given with --config".into());
+    };
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(toml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+impl Args {
+    /// The arguments for running the client with a profile, each with its own control socket
+    pub fn with_profile(
+        &self,
+        name: &str,
+        profile: &ClientProfile,
+    ) -> Result<Args, Box<dyn Error>> {
+        let mut args = self.clone();
+        args.client = Some(profile.server.clone());
+        args.port = profile.port.or(args.port);
+        args.https |= profile.https;
+        args.system_metrics |= profile.system_metrics;
+        args.extra_command = profile.extra_command.clone().or(args.extra_command);
+        if let Some(path) = &profile.privkey {
+            if !path.exists() {
+                return Err(format!("Private key file of profile {} does not exist", name).into());
+            }
+            args.privkey = Some(path.clone());
+        }
+        args.token = profile.token.clone().or(args.token);
+        args.device = profile.device.clone().or(args.device);
+        args.message = profile.message.clone().or(args.message);
+        if let Some(hash) = &profile.hash {
+            args.hash = Some(keys::parse_hash(hash)?);
+        }
+        args.interval = profile.interval.or(args.interval);
+        args.battery_interval = profile.battery_interval.or(args.battery_interval);
+        check_intervals(&args).map_err(|e| format!("Profile {}: {}", name, e))?;
+        if let Some(path) = &profile.base_path {
+            args.base_path = normalize_base_path(path);
+        }
+        args.heartbeat_path = profile.heartbeat_path.clone().or(args.heartbeat_path);
+        if !profile.on.is_empty() {
+            args.hooks = profile
+                .on
+                .iter()
+                .map(|(event, command)| Ok((HookEvent::parse(event)?, command.clone())))
+                .collect::<Result<_, String>>()
+                .map_err(|e| format!("Profile {}: {}", name, e))?;
+        }
+        args.hook_failures = profile.hook_failures.or(args.hook_failures);
+        args.ctl_socket = Some(ctl::profile_path(name));
+        args.health_port = profile.health_port;
+        args.profiles = vec![name.to_string()];
+        Ok(args)
+    }
+
+    /// URL of `path` on the server given with -c
+    pub fn server_url(&self, path: &str) -> String {
+        format!(
+            "{}://{}:{}{}{}",
+            if self.https { "https" } else { "http" },
+            self.client.as_ref().unwrap(),
+            self.port.unwrap(),
+            self.base_path.as_deref().unwrap_or_default(),
+            path
+        )
+    }
 }
 
 pub fn try_parse_args() -> Result<Args, Box<dyn Error>> {
     let mut args = Args::try_parse()?;
+    args.base_path = args.base_path.as_deref().and_then(normalize_base_path);
+    if let Some(path) = &args.log_file {
+        logging::init_file(
+            path,
+            args.log_max_size * 1024 * 1024,
+            args.log_rotate,
+            args.log_keep,
+        )
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    } else if args.output == Output::Json && args.log_sink == LogSink::Stdout {
+        // Leaving stdout to the results
+        logging::init_stderr().map_err(|e| format!("stderr: {}", e))?;
+    } else {
+        logging::init_sink(args.log_sink).map_err(|e| format!("{:?}: {}", args.log_sink, e))?;
+    }
     if args.server && args.client.is_some() {
         return Err("Cannot specify both server and client mode".into());
     }
-    if !args.server && args.client.is_none() {
+    if !args.server && args.client.is_none() && args.profiles.is_empty() && args.command.is_none() {
         return Err("Must specify either server or client mode".into());
     }
     if args.pubkey.is_some() && !args.pubkey.as_ref().unwrap().exists() {
@@ -38,22 +1072,157 @@ pub fn try_parse_args() -> Result<Args, Box<dyn Error>> {
     if args.privkey.is_some() && !args.privkey.as_ref().unwrap().exists() {
         return Err("Private key file does not exist".into());
     }
+    check_intervals(&args)?;
+    if matches!(args.command, Some(Command::Bench { .. })) && args.client.is_none() {
+        return Err("Must specify the server to bench with -c".into());
+    }
+    if matches!(args.command, Some(Command::Admin { .. })) && args.client.is_none() {
+        return Err("Must specify the server to manage with -c".into());
+    }
+    // Importing writes the configuration
+    let import = matches!(args.command, Some(Command::ImportBundle { .. }));
+    if args.config.is_some() && !args.config.as_ref().unwrap().exists() && !import {
+        return Err("Config file does not exist".into());
+    }
+    // Uses both the server configuration and the client's credentials
+    let selftest = matches!(args.command, Some(Command::Selftest));
+    let export = matches!(args.command, Some(Command::ExportBundle { .. }));
+    // Printed for packaging, with nothing else on stdout
+    let docs = matches!(
+        args.command,
+        Some(Command::Completions { .. } | Command::Manpage)
+    );
     if args.port.is_none() {
         args.port = Some(8080);
-        println!("info: Port not specified, using default port 8080");
+        if !matches!(args.command, Some(Command::Ctl { .. }))
+            && !docs
+            && args.profiles.is_empty()
+            && !((selftest || export || import) && args.client.is_none())
+        {
+            info!("Port not specified, using default port 8080");
+        }
     }
-    if args.client.is_some() {
-        let addr_with_port = format!("{}:{}", args.client.as_ref().unwrap(), args.port.unwrap());
+    if let Some(client) = &args.client {
+        let addr_with_port = format!("{}:{}", client, args.port.unwrap());
         let mut addrs = addr_with_port.to_socket_addrs()?;
         if addrs.next().is_none() {
             return Err("Invalid client address".into());
         }
     }
     if args.server && args.privkey.is_some() {
-        println!("warn: Private key will not be used in server mode");
+        warn!("Private key will not be used in server mode");
+    }
+    if args.client.is_some() && args.pubkey.is_some() && !selftest {
+        warn!("Public key will not be used in client mode");
     }
-    if args.client.is_some() && args.pubkey.is_some() {
-        println!("warn: Public key will not be used in client mode");
+    if args.server && args.token.is_some() {
+        warn!("Token will not be used in server mode");
+    }
+    if args.client.is_some()
+        && args.config.is_some()
+        && args.profiles.is_empty()
+        && !selftest
+        && !export
+    {
+        warn!("Config file will not be used in client mode");
     }
     Ok(args)
 }
+
+/// Whether the server would wait for heartbeats sent every `--interval` or `--battery-interval`
+/// seconds, rather than take the device for offline in between
+fn check_intervals(args: &Args) -> Result<(), String> {
+    let intervals = [
+        ("Interval", args.interval),
+        ("Battery interval", args.battery_interval),
+    ];
+    match intervals
+        .iter()
+        .find(|(_, i)| i.is_some_and(|i| i > MAX_INTERVAL))
+    {
+        Some((name, _)) => Err(format!(
+            "{} cannot be longer than {} seconds, as servers count longer ones as that",
+            name, MAX_INTERVAL
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Print the completions of `shell` for every argument and subcommand
+pub fn print_completions(shell: Shell) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
+
+/// Print the man page, listing the subcommands
+pub fn print_manpage() -> std::io::Result<()> {
+    clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())
+}
+
+/// Either none or like `/presence`
+fn normalize_base_path(path: &str) -> Option<String> {
+    Some(format!("/{}", path.trim_matches('/'))).filter(|path| path != "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_hours(range: &str) -> Result<QuietHours, String> {
+        QuietHours::try_from(range.to_string())
+    }
+
+    #[test]
+    fn stray_variables() {
+        let vars = [
+            ("ONLINE_STATUS_PRIVACY", "devices"),
+            ("ONLINE_STATUS_USERS__BOB__TOKENS", r#"["bob-token"]"#),
+            ("ONLINE_STATUS_EVENT", "online"),
+            ("ONLINE_STATUS_SERVER", "true"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let table = config_from_vars(vars).unwrap();
+        let mut keys: Vec<_> = table.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["privacy", "users"]);
+        let config: ServerConfig = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.users["bob"].tokens, ["bob-token"]);
+    }
+
+    #[test]
+    fn quiet_hours_parse() {
+        let quiet = quiet_hours("09:30-17:00").unwrap();
+        assert_eq!((quiet.start, quiet.end), (9 * 60 + 30, 17 * 60));
+        let quiet = quiet_hours(" 22:00 - 8:05 ").unwrap();
+        assert_eq!((quiet.start, quiet.end), (22 * 60, 8 * 60 + 5));
+        for range in [
+            "",
+            "22:00",
+            "22:00-",
+            "24:00-08:00",
+            "22:60-08:00",
+            "22-08",
+            "a:b-c:d",
+        ] {
+            assert!(quiet_hours(range).is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn quiet_hours_contains() {
+        let day = quiet_hours("09:00-17:00").unwrap();
+        assert!(!day.contains(9 * 60 - 1));
+        assert!(day.contains(9 * 60));
+        assert!(day.contains(17 * 60 - 1));
+        assert!(!day.contains(17 * 60));
+        // Across midnight
+        let night = quiet_hours("22:00-08:00").unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(0));
+        assert!(night.contains(8 * 60 - 1));
+        assert!(!night.contains(8 * 60));
+        assert!(!night.contains(12 * 60));
+    }
+}
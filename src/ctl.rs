@@ -0,0 +1,256 @@
+//! Local control socket of the client daemon, and the `ctl` subcommand talking to it
+//!
+//! Requests are single lines of JSON, answered by a single line of text, or of JSON for
+//! `status-json`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Notify,
+};
+
+use crate::{
+    config::{Args, Output},
+    exit,
+};
+
+/// Message of "Do not disturb", as set from the tray
+pub const DND_MESSAGE: &str = "Do not disturb";
+
+#[derive(clap::Subcommand, Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum CtlCommand {
+    /// Show whether heartbeats are sent, the message and the last result
+    Status,
+    /// Stop sending heartbeats
+    Pause,
+    /// Send heartbeats again
+    Resume,
+    /// Change the status message, or clear it if none is given
+    SetMessage { message: Option<String> },
+    /// Send a heartbeat right away
+    SendNow,
+    /// Status for `--output json`, answered with the state and the health as JSON
+    #[command(skip)]
+    StatusJson,
+}
+
+/// What the running client can be told to change
+#[derive(Default)]
+pub struct Control {
+    state: Mutex<ControlState>,
+    /// Wakes the client up from waiting for the next heartbeat
+    pub wake: Notify,
+}
+
+#[derive(Default)]
+struct ControlState {
+    paused: bool,
+    message: Option<String>,
+    last: Option<String>,
+    health: Health,
+}
+
+/// How well the client is doing, for local monitoring
+#[derive(Serialize, Default, Clone)]
+pub struct Health {
+    pub paused: bool,
+    /// Timestamp of the latest accepted heartbeat
+    pub last_success: Option<u64>,
+    /// Failed heartbeats since the last accepted one
+    pub consecutive_failures: u64,
+    /// Seconds the client waits longer because the server is busy
+    pub backoff: u64,
+    pub heartbeats_sent: u64,
+    pub heartbeats_failed: u64,
+}
+
+impl Control {
+    pub fn new(message: Option<String>) -> Self {
+        Control {
+            state: Mutex::new(ControlState {
+                message,
+                ..Default::default()
+            }),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    pub fn message(&self) -> Option<String> {
+        self.state.lock().unwrap().message.clone()
+    }
+
+    /// Remember that the server accepted the heartbeat at `timestamp`
+    pub fn succeeded(&self, timestamp: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.last = Some(format!("sent at {}", timestamp));
+        state.health.last_success = Some(timestamp);
+        state.health.consecutive_failures = 0;
+        state.health.backoff = 0;
+        state.health.heartbeats_sent += 1;
+    }
+
+    /// Remember why the latest heartbeat failed
+    pub fn failed(&self, reason: String) {
+        let mut state = self.state.lock().unwrap();
+        state.last = Some(format!("failed: {}", reason));
+        state.health.consecutive_failures += 1;
+        state.health.heartbeats_failed += 1;
+    }
+
+    pub fn set_backoff(&self, backoff: u64) {
+        self.state.lock().unwrap().health.backoff = backoff;
+    }
+
+    pub fn health(&self) -> Health {
+        let state = self.state.lock().unwrap();
+        Health {
+            paused: state.paused,
+            ..state.health.clone()
+        }
+    }
+
+    /// Carry out a command, returning the answer to send back
+    pub fn apply(&self, command: CtlCommand) -> String {
+        let mut state = self.state.lock().unwrap();
+        let wake = !matches!(command, CtlCommand::Pause);
+        match command {
+            CtlCommand::Status => {
+                return format!(
+                    "{}, message: {}, last heartbeat: {}",
+                    if state.paused { "paused" } else { "running" },
+                    state.message.as_deref().unwrap_or("none"),
+                    state.last.as_deref().unwrap_or("none"),
+                )
+            }
+            CtlCommand::StatusJson => {
+                let mut status = serde_json::to_value(Health {
+                    paused: state.paused,
+                    ..state.health.clone()
+                })
+                .unwrap();
+                status["message"] = state.message.clone().into();
+                status["last_heartbeat"] = state.last.clone().into();
+                return status.to_string();
+            }
+            CtlCommand::Pause => state.paused = true,
+            CtlCommand::Resume => state.paused = false,
+            CtlCommand::SetMessage { message } => state.message = message,
+            CtlCommand::SendNow => {}
+        }
+        if wake {
+            self.wake.notify_one();
+        }
+        "OK".to_string()
+    }
+}
+
+/// Where the socket is unless `--ctl-socket` is given
+pub fn default_path() -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"\\.\pipe\online_status")
+    } else {
+        std::env::temp_dir().join("online_status.sock")
+    }
+}
+
+/// Where the client running a profile listens
+pub fn profile_path(profile: &str) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(format!(r"\\.\pipe\online_status-{}", profile))
+    } else {
+        std::env::temp_dir().join(format!("online_status-{}.sock", profile))
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(stream: S, control: &Control) {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    if stream.read_line(&mut line).await.is_err() {
+        return;
+    }
+    let response = match serde_json::from_str(&line) {
+        Ok(command) => control.apply(command),
+        Err(e) => format!("Invalid request: {}", e),
+    };
+    let _ = stream.write_all(format!("{}\n", response).as_bytes()).await;
+}
+
+/// Accept commands for the client on `path` until the program exits
+#[cfg(unix)]
+pub async fn serve(path: &Path, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = std::fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move { handle(stream, &control).await });
+    }
+}
+
+/// Accept commands for the client on `path` until the program exits
+#[cfg(windows)]
+pub async fn serve(path: &Path, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)?;
+    loop {
+        server.connect().await?;
+        let stream = std::mem::replace(&mut server, ServerOptions::new().create(path)?);
+        let control = control.clone();
+        tokio::spawn(async move { handle(stream, &control).await });
+    }
+}
+
+/// Send one command to the running client and print its answer
+pub async fn ctl_main(args: Args, command: CtlCommand) -> Result<(), Box<dyn Error>> {
+    let path = match (&args.ctl_socket, &args.profiles[..]) {
+        (Some(path), _) => path.clone(),
+        (None, []) => default_path(),
+        (None, [profile]) => profile_path(profile),
+        (None, _) => return Err("Can only control one profile at a time".into()),
+    };
+    let connect_error = |e| exit::network(format!("Cannot connect to {}: {}", path.display(), e));
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .map_err(connect_error)?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(&path)
+        .map_err(connect_error)?;
+    let mut stream = BufReader::new(stream);
+    let json = args.output == Output::Json;
+    let command = match command {
+        CtlCommand::Status if json => CtlCommand::StatusJson,
+        command => command,
+    };
+    let status = matches!(command, CtlCommand::StatusJson);
+    let request = serde_json::to_string(&command)?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_line(&mut response).await?;
+    let response = response.trim_end();
+    if json && !status {
+        println!("{}", serde_json::json!({ "result": response }));
+    } else {
+        println!("{}", response);
+    }
+    Ok(())
+}
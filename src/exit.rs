@@ -0,0 +1,85 @@
+//! Exit codes, so that wrapper scripts and service managers can tell why the program stopped
+//!
+//! | Code | Meaning |
+//! |------|---------|
+//! | 0    | Success |
+//! | 1    | Any other error |
+//! | 2    | Invalid arguments or configuration, including key files |
+//! | 3    | The server, or the running client for `ctl`, cannot be reached |
+//! | 4    | The server rejected the token, key or signature |
+//! | 75   | The client went over `--max-memory` or `--max-cpu` |
+
+use std::{error::Error, fmt};
+
+pub const FAILURE: i32 = 1;
+pub const CONFIG: i32 = 2;
+pub const NETWORK: i32 = 3;
+pub const AUTH: i32 = 4;
+/// `EX_TEMPFAIL` of `sysexits.h`, as starting afresh should help
+pub const LIMITS: i32 = 75;
+
+/// An error that ends the program with its own code
+#[derive(Debug)]
+struct Failure {
+    code: i32,
+    error: Box<dyn Error>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for Failure {}
+
+fn with_code(code: i32, error: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+    Box::new(Failure {
+        code,
+        error: error.into(),
+    })
+}
+
+pub fn config(error: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+    with_code(CONFIG, error)
+}
+
+pub fn network(error: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+    with_code(NETWORK, error)
+}
+
+pub fn auth(error: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+    with_code(AUTH, error)
+}
+
+/// The code of an error, by its type if it was not given one
+pub fn code(error: &(dyn Error + 'static)) -> i32 {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        failure.code
+    } else if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        match e.status().map(|s| s.as_u16()) {
+            Some(401 | 403) => AUTH,
+            Some(_) => FAILURE,
+            None if e.is_connect() || e.is_timeout() || e.is_request() => NETWORK,
+            None => FAILURE,
+        }
+    } else if error.is::<toml::de::Error>() {
+        CONFIG
+    } else {
+        FAILURE
+    }
+}
+
+/// Report the error and exit with its code
+pub fn fail(error: Box<dyn Error>) -> ! {
+    error!("{}", error);
+    std::process::exit(code(&*error))
+}
+
+/// The code for an error status of the server
+pub fn status(status: reqwest::StatusCode, error: impl Into<Box<dyn Error>>) -> Box<dyn Error> {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => auth(error),
+        _ => error.into(),
+    }
+}
@@ -0,0 +1,33 @@
+//! Custom fields of heartbeats, printed as a JSON object by the command of `--extra-command`
+
+use serde_json::{Map, Value};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Longest the command may take, so that it cannot hold up heartbeats
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run `command` in a shell and read the object it prints
+pub async fn fields(command: &str) -> Result<Map<String, Value>, String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let output = tokio::time::timeout(TIMEOUT, shell.arg(command).kill_on_drop(true).output())
+        .await
+        .map_err(|_| format!("timed out after {}s", TIMEOUT.as_secs()))?
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status));
+    }
+    match serde_json::from_slice(&output.stdout) {
+        Ok(Value::Object(fields)) => Ok(fields),
+        Ok(_) => Err("did not print a JSON object".to_string()),
+        Err(e) => Err(format!("did not print JSON: {}", e)),
+    }
+}
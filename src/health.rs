@@ -0,0 +1,72 @@
+//! Local HTTP endpoint of the client reporting its own health, for monitoring
+//! the client itself rather than the status it sends
+//!
+//! `/health` answers with [`Health`] as JSON, `/metrics` with the same numbers
+//! for Prometheus.
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Json, Router};
+use std::{fmt::Write, net::Ipv4Addr, sync::Arc};
+
+use crate::ctl::Control;
+
+/// Serve on 127.0.0.1 only, as the numbers are of no business to anyone else
+pub async fn serve(port: u16, control: Arc<Control>) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .with_state(control);
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+    info!("Health endpoint on http://127.0.0.1:{}/health", port);
+    axum::serve(listener, app).await
+}
+
+async fn health(State(control): State<Arc<Control>>) -> impl IntoResponse {
+    Json(control.health())
+}
+
+async fn metrics(State(control): State<Arc<Control>>) -> impl IntoResponse {
+    let health = control.health();
+    let mut out = String::new();
+    let gauges = [
+        (
+            "last_success_timestamp_seconds",
+            "When the server last accepted a heartbeat",
+            health.last_success.unwrap_or_default(),
+        ),
+        (
+            "consecutive_failures",
+            "Failed heartbeats since the last accepted one",
+            health.consecutive_failures,
+        ),
+        (
+            "backoff_seconds",
+            "Extra wait because the server is busy",
+            health.backoff,
+        ),
+        (
+            "paused",
+            "Whether heartbeats are paused",
+            health.paused as u64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let name = format!("online_status_client_{}", name);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    let name = "online_status_client_heartbeats_total";
+    let _ = writeln!(out, "# HELP {} Heartbeats sent, by result", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(
+        out,
+        "{}{{result=\"success\"}} {}",
+        name, health.heartbeats_sent
+    );
+    let _ = writeln!(
+        out,
+        "{}{{result=\"failure\"}} {}",
+        name, health.heartbeats_failed
+    );
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
@@ -0,0 +1,168 @@
+//! Commands the client runs on its own events, for local automation like pausing
+//! notifications while the server cannot be reached or changing the wallpaper with DND
+//!
+//! Commands run through the shell, with the event in `ONLINE_STATUS_EVENT`. The client does
+//! not wait for them, and only logs those that fail.
+
+use tokio::process::Command;
+
+use crate::ctl::{Control, DND_MESSAGE};
+
+/// Consecutive failed heartbeats after which `failing` is run, unless `--hook-failures` is given
+pub const DEFAULT_FAILURES: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// Heartbeats failed a number of times in a row
+    Failing,
+    /// A heartbeat was accepted again after `Failing`
+    Recovered,
+    /// The server took the device for offline until this heartbeat
+    Offline,
+    /// The message was set to that of "Do not disturb"
+    DndOn,
+    /// The message was changed from that of "Do not disturb"
+    DndOff,
+}
+
+impl HookEvent {
+    const ALL: [HookEvent; 5] = [
+        HookEvent::Failing,
+        HookEvent::Recovered,
+        HookEvent::Offline,
+        HookEvent::DndOn,
+        HookEvent::DndOff,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Failing => "failing",
+            HookEvent::Recovered => "recovered",
+            HookEvent::Offline => "offline",
+            HookEvent::DndOn => "dnd-on",
+            HookEvent::DndOff => "dnd-off",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|event| event.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|event| event.name()).collect();
+                format!(
+                    "unknown event {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Parse `--on failing='notify-send "Server unreachable"'`
+pub fn parse_hook(arg: &str) -> Result<(HookEvent, String), String> {
+    match arg.split_once('=') {
+        Some((event, command)) if !command.is_empty() => {
+            Ok((HookEvent::parse(event)?, command.to_string()))
+        }
+        _ => Err("expected <event>=<command>".to_string()),
+    }
+}
+
+/// Which events happened, to run every hook once per change
+pub struct Hooks {
+    commands: Vec<(HookEvent, String)>,
+    /// Failed heartbeats in a row that count as failing
+    failures: u64,
+    device: Option<String>,
+    failing: bool,
+    dnd: bool,
+}
+
+impl Hooks {
+    pub fn new(
+        commands: Vec<(HookEvent, String)>,
+        failures: u64,
+        device: Option<String>,
+        control: &Control,
+    ) -> Self {
+        Hooks {
+            commands,
+            failures: failures.max(1),
+            device,
+            failing: false,
+            // Starting with the DND message is no change
+            dnd: control.message().as_deref() == Some(DND_MESSAGE),
+        }
+    }
+
+    /// Look at the result of the latest heartbeat
+    pub fn heartbeat(&mut self, control: &Control) {
+        let failures = control.health().consecutive_failures;
+        if failures == 0 && self.failing {
+            self.failing = false;
+            self.run(HookEvent::Recovered, control);
+        } else if failures >= self.failures && !self.failing {
+            self.failing = true;
+            self.run(HookEvent::Failing, control);
+        }
+    }
+
+    /// The server answered that the device had been offline
+    pub fn offline(&self, control: &Control) {
+        self.run(HookEvent::Offline, control);
+    }
+
+    /// Look at whether the message is that of "Do not disturb"
+    pub fn message(&mut self, control: &Control) {
+        let dnd = control.message().as_deref() == Some(DND_MESSAGE);
+        if dnd != self.dnd {
+            self.dnd = dnd;
+            let event = if dnd {
+                HookEvent::DndOn
+            } else {
+                HookEvent::DndOff
+            };
+            self.run(event, control);
+        }
+    }
+
+    fn run(&self, event: HookEvent, control: &Control) {
+        let health = control.health();
+        for (_, command) in self.commands.iter().filter(|(e, _)| *e == event) {
+            info!("Running {} hook", event.name());
+            let mut shell = if cfg!(windows) {
+                let mut shell = Command::new("cmd");
+                shell.arg("/C");
+                shell
+            } else {
+                let mut shell = Command::new("sh");
+                shell.arg("-c");
+                shell
+            };
+            shell
+                .arg(command)
+                .env("ONLINE_STATUS_EVENT", event.name())
+                .env(
+                    "ONLINE_STATUS_FAILURES",
+                    health.consecutive_failures.to_string(),
+                )
+                .env(
+                    "ONLINE_STATUS_DEVICE",
+                    self.device.as_deref().unwrap_or_default(),
+                )
+                .env(
+                    "ONLINE_STATUS_MESSAGE",
+                    control.message().unwrap_or_default(),
+                );
+            let command = command.clone();
+            tokio::spawn(async move {
+                match shell.status().await {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => error!("Hook {} failed: {}", command, status),
+                    Err(e) => error!("Cannot run hook {}: {}", command, e),
+                }
+            });
+        }
+    }
+}
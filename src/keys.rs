@@ -0,0 +1,143 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use pgp::{
+    crypto::hash::HashAlgorithm,
+    packet::{SignatureConfig, SignatureType, SignatureVersion, Subpacket, SubpacketData},
+    types::{KeyTrait, SecretKeyTrait},
+    ArmorOptions, Deserializable, SignedPublicKey, SignedSecretKey, StandaloneSignature,
+};
+use std::{error::Error, fs, path::Path};
+
+pub fn read_public_key(path: &Path) -> Result<SignedPublicKey, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (public_key, _) =
+        SignedPublicKey::from_string(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(public_key)
+}
+
+pub fn read_secret_key(path: &Path) -> Result<SignedSecretKey, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (secret_key, _) =
+        SignedSecretKey::from_string(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !secret_key.is_signing_key() {
+        return Err(format!("{}: Private key is not a signing key", path.display()).into());
+    }
+    Ok(secret_key)
+}
+
+/// Hash algorithms that are safe to sign heartbeats with
+pub const STRONG_HASHES: [HashAlgorithm; 6] = [
+    HashAlgorithm::SHA2_256,
+    HashAlgorithm::SHA2_384,
+    HashAlgorithm::SHA2_512,
+    HashAlgorithm::SHA2_224,
+    HashAlgorithm::SHA3_256,
+    HashAlgorithm::SHA3_512,
+];
+
+/// Parse a hash algorithm name such as `SHA256` or `SHA3-512`, refusing weak ones like SHA-1
+pub fn parse_hash(name: &str) -> Result<HashAlgorithm, String> {
+    let hash: HashAlgorithm = name
+        .to_ascii_uppercase()
+        .parse()
+        .map_err(|_| format!("Unknown hash algorithm {:?}", name))?;
+    if !STRONG_HASHES.contains(&hash) {
+        return Err(format!("Hash algorithm {} is too weak", hash));
+    }
+    Ok(hash)
+}
+
+/// Sign the timestamp of a heartbeat, as hex-encoded MPIs
+pub fn sign_timestamp(
+    key: &SignedSecretKey,
+    hash: HashAlgorithm,
+    timestamp: u64,
+) -> pgp::errors::Result<Vec<String>> {
+    let signature =
+        key.create_signature(|| "".to_string(), hash, &timestamp.to_string().into_bytes())?;
+    Ok(signature.into_iter().map(hex::encode).collect())
+}
+
+/// Create a detached OpenPGP signature, as `gpg --detach-sign` would
+pub fn detached_signature(
+    key: &SignedSecretKey,
+    hash: HashAlgorithm,
+    data: &[u8],
+) -> Result<StandaloneSignature, Box<dyn Error>> {
+    let config = SignatureConfig::new_v4(
+        SignatureVersion::V4,
+        SignatureType::Binary,
+        key.algorithm(),
+        hash,
+        vec![
+            Subpacket::regular(SubpacketData::SignatureCreationTime(Utc::now())),
+            Subpacket::regular(SubpacketData::Issuer(key.key_id())),
+        ],
+        vec![],
+    );
+    Ok(StandaloneSignature::new(config.sign(
+        key,
+        || "".to_string(),
+        data,
+    )?))
+}
+
+/// The armored public half of a secret key
+pub fn armored_public_key(key: &SignedSecretKey) -> Result<String, Box<dyn Error>> {
+    let public_key = key.public_key().sign(key, || "".to_string())?;
+    Ok(public_key.to_armored_string(ArmorOptions::default())?)
+}
+
+/// Read a detached signature, ASCII-armored as `gpg --armor --detach-sign` writes it, or
+/// base64-encoded binary
+pub fn parse_detached(text: &str) -> Result<StandaloneSignature, String> {
+    let text = text.trim();
+    if text.starts_with("-----BEGIN") {
+        return StandaloneSignature::from_string(text)
+            .map(|(signature, _)| signature)
+            .map_err(|e| e.to_string());
+    }
+    let bytes = BASE64.decode(text).map_err(|e| e.to_string())?;
+    StandaloneSignature::from_bytes(&bytes[..]).map_err(|e| e.to_string())
+}
+
+/// Check a detached signature made with one of the `hashes` against a key or any of its
+/// subkeys
+pub fn verify_detached(
+    key: &SignedPublicKey,
+    signature: &StandaloneSignature,
+    data: &[u8],
+    hashes: &[HashAlgorithm],
+) -> bool {
+    if !hashes.contains(&signature.signature.hash_alg()) {
+        return false;
+    }
+    signature.verify(key, data).is_ok()
+        || key
+            .public_subkeys
+            .iter()
+            .any(|subkey| signature.verify(subkey, data).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hashes() {
+        assert_eq!(parse_hash("SHA256"), Ok(HashAlgorithm::SHA2_256));
+        assert_eq!(parse_hash("sha512"), Ok(HashAlgorithm::SHA2_512));
+        assert_eq!(parse_hash("SHA3-512"), Ok(HashAlgorithm::SHA3_512));
+        for hash in STRONG_HASHES {
+            assert_eq!(parse_hash(&hash.to_string()), Ok(hash));
+        }
+    }
+
+    #[test]
+    fn weak_or_unknown() {
+        assert!(parse_hash("SHA1").unwrap_err().contains("too weak"));
+        assert!(parse_hash("MD5").unwrap_err().contains("too weak"));
+        assert!(parse_hash("SHA257").unwrap_err().contains("Unknown"));
+        assert!(parse_hash("").unwrap_err().contains("Unknown"));
+    }
+}
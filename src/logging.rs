@@ -0,0 +1,305 @@
+//! Log output to stdout, a rotated file, journald or the Windows Event Log
+//!
+//! Use the `info!`, `warn!` and `error!` macros (available in every module),
+//! which write lines in the same `level: message` format to stdout and files.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+
+/// Where log lines go, besides a file given with `--log-file`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSink {
+    Stdout,
+    /// The systemd journal, with structured fields
+    Journald,
+    /// The Windows Event Log
+    Eventlog,
+}
+
+/// When to start a new log file regardless of its size
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn period(self) -> Option<Duration> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(Duration::from_secs(3600)),
+            Rotation::Daily => Some(Duration::from_secs(86400)),
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    max_size: u64,
+    rotation: Rotation,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(LogFile {
+            size: metadata.len(),
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            path,
+            file,
+            max_size,
+            rotation,
+            keep,
+        })
+    }
+
+    fn needs_rotation(&self, len: u64) -> bool {
+        let expired = self
+            .rotation
+            .period()
+            .is_some_and(|p| self.opened.elapsed().unwrap_or_default() >= p);
+        expired || (self.max_size > 0 && self.size > 0 && self.size + len > self.max_size)
+    }
+
+    /// Shift `file.1` to `file.2` and so on, dropping the oldest
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation(line.len() as u64) {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum Sink {
+    File(Mutex<LogFile>),
+    Stderr,
+    #[cfg(unix)]
+    Journald(journald::Journal),
+    #[cfg(windows)]
+    EventLog(eventlog::EventSource),
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Send all further log lines to `path` instead of stdout
+pub fn init_file(path: &Path, max_size: u64, rotation: Rotation, keep: usize) -> io::Result<()> {
+    let file = LogFile::open(path.to_path_buf(), max_size, rotation, keep)?;
+    set_sink(Sink::File(Mutex::new(file)))
+}
+
+/// Send all further log lines to stderr, leaving stdout to what the program prints
+pub fn init_stderr() -> io::Result<()> {
+    set_sink(Sink::Stderr)
+}
+
+/// Send all further log lines to a system log
+pub fn init_sink(sink: LogSink) -> io::Result<()> {
+    match sink {
+        LogSink::Stdout => Ok(()),
+        #[cfg(unix)]
+        LogSink::Journald => set_sink(Sink::Journald(journald::Journal::connect()?)),
+        #[cfg(windows)]
+        LogSink::Eventlog => set_sink(Sink::EventLog(eventlog::EventSource::register()?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Log sink {:?} is not available on this platform", sink),
+        )),
+    }
+}
+
+fn set_sink(sink: Sink) -> io::Result<()> {
+    SINK.set(sink)
+        .map_err(|_| io::Error::other("Log output already set"))
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub fn write(level: &str, module: &str, args: fmt::Arguments) {
+    let result = match SINK.get() {
+        None => {
+            println!("{}: {}", level, args);
+            return;
+        }
+        Some(Sink::File(file)) => file.lock().unwrap().write(&format!(
+            "{} {}: {}\n",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            level,
+            args
+        )),
+        Some(Sink::Stderr) => {
+            eprintln!("{}: {}", level, args);
+            return;
+        }
+        #[cfg(unix)]
+        Some(Sink::Journald(journal)) => journal.send(level, module, &args.to_string()),
+        #[cfg(windows)]
+        Some(Sink::EventLog(source)) => source.report(level, &args.to_string()),
+    };
+    if let Err(e) = result {
+        // Nowhere else to report it
+        eprintln!("error: Cannot write log: {}", e);
+        println!("{}: {}", level, args);
+    }
+}
+
+#[cfg(unix)]
+mod journald {
+    //! The native journal protocol, see systemd.journal-fields(7)
+
+    use std::{io, os::unix::net::UnixDatagram};
+
+    const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+    pub(super) struct Journal(UnixDatagram);
+
+    impl Journal {
+        pub(super) fn connect() -> io::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET)?;
+            Ok(Journal(socket))
+        }
+
+        pub(super) fn send(&self, level: &str, module: &str, message: &str) -> io::Result<()> {
+            let priority = match level {
+                "error" => "3",
+                "warn" => "4",
+                _ => "6",
+            };
+            let mut datagram = Vec::new();
+            for (key, value) in [
+                ("MESSAGE", message),
+                ("PRIORITY", priority),
+                ("SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME")),
+                ("CODE_MODULE", module),
+            ] {
+                datagram.extend_from_slice(key.as_bytes());
+                if value.contains('\n') {
+                    datagram.push(b'\n');
+                    datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                } else {
+                    datagram.push(b'=');
+                }
+                datagram.extend_from_slice(value.as_bytes());
+                datagram.push(b'\n');
+            }
+            self.0.send(&datagram)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod eventlog {
+    use std::{io, iter, ptr};
+    use windows_sys::Win32::{
+        Foundation::HANDLE,
+        System::EventLog::{
+            RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+            EVENTLOG_WARNING_TYPE,
+        },
+    };
+
+    pub(super) struct EventSource(HANDLE);
+
+    // The handle is only used for ReportEventW, which is thread-safe
+    unsafe impl Send for EventSource {}
+    unsafe impl Sync for EventSource {}
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(iter::once(0)).collect()
+    }
+
+    impl EventSource {
+        pub(super) fn register() -> io::Result<Self> {
+            let name = wide(env!("CARGO_PKG_NAME"));
+            let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(EventSource(handle))
+        }
+
+        pub(super) fn report(&self, level: &str, message: &str) -> io::Result<()> {
+            let typ = match level {
+                "error" => EVENTLOG_ERROR_TYPE,
+                "warn" => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let message = wide(message);
+            let strings = [message.as_ptr()];
+            let ok = unsafe {
+                ReportEventW(
+                    self.0,
+                    typ,
+                    0,
+                    0,
+                    ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    ptr::null(),
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::write("info", module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::write("warn", module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::write("error", module_path!(), format_args!($($arg)*))
+    };
+}
@@ -1,39 +1,173 @@
+use admin::admin_main;
+use bench::bench_main;
+use check::check_main;
 use client::client_main;
-use config::try_parse_args;
+use config::{print_completions, print_manpage, try_parse_args, Command};
+use ctl::ctl_main;
 use serde::{Deserialize, Serialize};
-use server::server_main;
+use server::{export_main, import_main, selftest_main, server_main};
 
+#[macro_use]
+mod logging;
+
+mod admin;
+mod bench;
+mod bluetooth;
+mod boot;
+mod check;
 mod client;
 mod config;
+mod ctl;
+mod exit;
+mod extra;
+mod health;
+mod hooks;
+mod keys;
+mod power;
 mod server;
+#[cfg(windows)]
+mod session;
+mod supervisor;
+mod system;
+mod telemetry;
+#[cfg(feature = "tray")]
+mod tray;
+mod wifi;
 
 const TIMEOUT: u64 = 5;
 const HEARTBEAT_INTERVAL: u64 = 60; // 1 minute
 const OFFLINE_TIMEOUT: u64 = 180; // 3 minutes
+/// Heartbeats a device that announced its interval may miss before it is offline, instead of
+/// `OFFLINE_TIMEOUT`
+const MISSED_HEARTBEATS: u64 = 3;
+/// Longest interval a device may announce, longer ones count as this
+const MAX_INTERVAL: u64 = 900;
 const ZOMBIE_TIMEOUT: u64 = 3600; // 1 hour
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct HeartBeat {
     timestamp: u64,
     signature: Option<Vec<String>>,
+    /// Hash algorithm of the signature, SHA256 if not given
+    hash: Option<String>,
+    /// Detached OpenPGP signature of the timestamp, armored or base64-encoded, instead of
+    /// `signature`
+    detached_signature: Option<String>,
+    device: Option<String>,
+    message: Option<String>,
+    /// Label of the network the device is on, like `work`
+    place: Option<String>,
+    /// Round-trip time of the previous heartbeat
+    latency_ms: Option<u32>,
+    /// Seconds until the next heartbeat, so the server knows when the device is overdue
+    interval: Option<u64>,
+    /// Version of the client, like `0.0.1`, for `min_client_version` of the server
+    client_version: Option<String>,
+    /// Seconds since the device booted, so the server notices reboots
+    uptime: Option<u64>,
+    /// Sent with `--system-metrics`
+    metrics: Option<SystemMetrics>,
+    /// Custom fields from `--extra-command`, which the server keeps as they are
+    extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Coarse health of a device, each left out where it is not known
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SystemMetrics {
+    /// Load average over one minute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    load: Option<f64>,
+    /// Share of memory in use, from 0 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_percent: Option<f64>,
+    /// Share of the root or system disk in use, from 0 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk_percent: Option<f64>,
+}
+
+/// The server's answer to an accepted heartbeat, if the client accepts JSON
+#[derive(Serialize, Deserialize)]
+struct HeartbeatResponse {
+    /// Always `received`
+    status: String,
+    server_time: u64,
+    /// Seconds until the server expects the next heartbeat
+    next_interval: u64,
+    device: String,
+    device_state: DeviceState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum DeviceState {
+    /// The server did not know the device
+    New,
+    /// The device was offline
+    Resumed,
+    Online,
+}
+
+/// Body of a rejected request, like `{"code": "signature_invalid", "message": "..."}`
+#[derive(Deserialize)]
+struct ErrorResponse {
+    code: String,
+    message: String,
+    detail: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = try_parse_args().unwrap_or_else(|e| {
-        println!("error: {}", e);
-        std::process::exit(1);
+    let args = try_parse_args().unwrap_or_else(|e| match e.downcast::<clap::Error>() {
+        Ok(e) => e.exit(),
+        // Anything else is about the arguments too
+        Err(e) => exit::fail(exit::config(e)),
     });
+    if let Some(endpoint) = &args.otlp_endpoint {
+        telemetry::init(endpoint, env!("CARGO_PKG_NAME"));
+    }
 
-    if args.server {
-        server_main(args).await.unwrap_or_else(|e| {
-            println!("error: {}", e);
-            std::process::exit(1);
-        });
-    } else if args.client.is_some() {
-        client_main(args).await.unwrap_or_else(|e| {
-            println!("error: {}", e);
-            std::process::exit(1);
-        });
+    if let Some(Command::Completions { shell }) = args.command {
+        print_completions(shell);
+    } else if let Some(Command::Manpage) = args.command {
+        print_manpage().unwrap_or_else(|e| exit::fail(e.into()));
+    } else if let Some(Command::Ctl { command }) = &args.command {
+        let command = command.clone();
+        ctl_main(args, command)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::Admin { command }) = &args.command {
+        let command = command.clone();
+        admin_main(args, command)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::Check { notify }) = args.command {
+        check_main(args, notify)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::ExportBundle { output }) = &args.command {
+        let output = output.clone();
+        export_main(args, output)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::ImportBundle { bundle, force }) = &args.command {
+        let (bundle, force) = (bundle.clone(), *force);
+        import_main(args, bundle, force)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::Selftest) = args.command {
+        selftest_main(args).await.unwrap_or_else(|e| exit::fail(e));
+    } else if let Some(Command::Bench {
+        clients,
+        rate,
+        duration,
+    }) = args.command
+    {
+        bench_main(args, clients, rate, duration)
+            .await
+            .unwrap_or_else(|e| exit::fail(e));
+    } else if args.server {
+        server_main(args).await.unwrap_or_else(|e| exit::fail(e));
+    } else if args.client.is_some() || !args.profiles.is_empty() {
+        client_main(args).await.unwrap_or_else(|e| exit::fail(e));
     }
 }
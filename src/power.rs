@@ -0,0 +1,76 @@
+//! Whether the device should save power, so that laptops and phones can send heartbeats
+//! less often while on battery or a cellular connection
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+use std::{fs, path::Path};
+
+/// On battery, or on a cellular connection
+pub async fn saving() -> bool {
+    on_battery().await || cellular().await
+}
+
+#[cfg(not(target_os = "windows"))]
+async fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "windows")]
+async fn on_battery() -> bool {
+    use windows_sys::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+    let known = unsafe { GetSystemPowerStatus(&mut status) } != 0;
+    // 0 is offline, 1 online and 255 unknown, like on desktops without a battery
+    known && status.ACLineStatus == 0
+}
+
+#[cfg(target_os = "macos")]
+async fn on_battery() -> bool {
+    output("pmset", &["-g", "batt"])
+        .await
+        .is_some_and(|batt| batt.contains("'Battery Power'"))
+}
+
+/// Running on a battery that discharges, with no charger online
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+async fn on_battery() -> bool {
+    let read = |dir: &Path, file: &str| {
+        fs::read_to_string(dir.join(file))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let mut discharging = false;
+    for supply in supplies.flatten() {
+        let dir = supply.path();
+        match read(&dir, "type").as_str() {
+            "Mains" | "USB" if read(&dir, "online") == "1" => return false,
+            "Battery" => discharging |= read(&dir, "status") == "Discharging",
+            _ => {}
+        }
+    }
+    discharging
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+async fn cellular() -> bool {
+    output("nmcli", &["-t", "-f", "type,state", "dev"])
+        .await
+        .is_some_and(|devices| devices.lines().any(|line| line == "gsm:connected"))
+}
+
+/// Not known on these systems
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+async fn cellular() -> bool {
+    false
+}
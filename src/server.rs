@@ -1,27 +1,113 @@
 use axum::{
-    extract::{ConnectInfo, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use pgp::{
     crypto::hash::HashAlgorithm,
-    types::{Mpi, PublicKeyTrait},
-    Deserializable, SignedPublicKey,
+    ser::Serialize as _,
+    types::{KeyTrait, Mpi, PublicKeyTrait},
+    SignedPublicKey, SignedSecretKey, StandaloneSignature,
 };
 use reqwest::header;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
     error::Error,
-    fs::File,
-    io::Read,
-    net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::broadcast;
+use tower_http::compression::CompressionLayer;
 
-use crate::{config::Args, HeartBeat, OFFLINE_TIMEOUT, TIMEOUT, ZOMBIE_TIMEOUT};
+#[cfg(feature = "matrix")]
+use crate::config::MatrixConfig;
+#[cfg(feature = "nats")]
+use crate::config::NatsConfig;
+use crate::{
+    config::{
+        load_server_config, AlertConfig, Args, CalendarConfig, CanaryConfig, ClusterConfig,
+        DeadManConfig, EscalationConfig, ExecConfig, ExternalSource, GithubConfig, IrcConfig,
+        LanConfig, MirrorConfig, NotifyConfig, OidcConfig, PeerConfig, Privacy, PushConfig,
+        QuietHours, SlackConfig, UserConfig,
+    },
+    exit,
+    keys::{
+        armored_public_key, detached_signature, parse_detached, parse_hash, read_public_key,
+        read_secret_key, verify_detached, STRONG_HASHES,
+    },
+    telemetry::{self, Span, SpanContext, SpanKind},
+    DeviceState, HeartBeat, HeartbeatResponse, SystemMetrics, HEARTBEAT_INTERVAL, MAX_INTERVAL,
+    MISSED_HEARTBEATS, OFFLINE_TIMEOUT, TIMEOUT, ZOMBIE_TIMEOUT,
+};
+
+mod access_log;
+mod admin;
+mod alert;
+mod anonymize;
+mod audit;
+mod badge;
+mod board;
+mod bundle;
+mod calendar;
+mod canary;
+mod check;
+mod client_version;
+mod cluster;
+mod dashboard;
+mod dead_man;
+mod error;
+mod escalation;
+mod exec;
+mod external;
+mod federation;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod github;
+mod ip_filter;
+mod irc;
+mod lan;
+mod listener;
+#[cfg(feature = "matrix")]
+mod matrix;
+mod metrics;
+mod mirror;
+#[cfg(feature = "nats")]
+mod nats;
+mod notify;
+mod oidc;
+mod page;
+#[cfg(feature = "plugins")]
+mod plugins;
+mod presence;
+mod push;
+mod relay;
+#[cfg(feature = "scripting")]
+mod script;
+mod selftest;
+mod share;
+mod signature_cache;
+mod sink;
+mod slack;
+mod stability;
+mod template;
+mod uptime;
+mod watchdog;
+mod widget;
+
+pub(crate) use bundle::{export_main, import_main};
+pub(crate) use check::check_server;
+use error::ApiError;
+pub(crate) use selftest::selftest_main;
 
 const TEAPOT_BODY: &str = r#"<!DOCTYPE html>
 <html>
@@ -55,44 +141,1374 @@ const TEAPOT_BODY: &str = r#"<!DOCTYPE html>
 </body>
 </html>"#;
 
+/// Answer to `GET /ping`, for whoever sets up a script without the client
+const PING_USAGE: &str = "\
+Send a heartbeat by POSTing to this URL with one of your tokens, e.g. every minute from cron:
+
+    * * * * * curl -fsS -X POST 'https://<server>/ping?token=<token>&device=<name>'
+
+Parameters:
+    token    token of the user, or send it as 'Authorization: Bearer <token>' instead
+    device   name of the device (defaults to its address)
+    message  status message to show
+
+The answer is 'Heartbeat received', or JSON with 'Accept: application/json'.
+";
+
+/// Answer to `GET /beat` without a token
+const BEAT_USAGE: &str = "\
+Send a heartbeat for a single device by GETting this URL with its device token, e.g. from
+Tasker or Shortcuts:
+
+    https://<server>/beat?token=<device token>&device=<name>
+
+Parameters:
+    token    token of the device, as configured in `device_tokens`
+    device   name of the device, must be the one the token is for if given
+    message  status message to show
+
+Beats of a device are accepted at most every 30 seconds.
+";
+
+/// The user configured at the top level of the config file, shown at `/status`
+const DEFAULT_USER: &str = "";
+const MAX_DEVICE_NAME_LEN: usize = 64;
+const MAX_MESSAGE_LEN: usize = 256;
+const MAX_PLACE_LEN: usize = 32;
+/// Longest the custom fields of a heartbeat may be, as JSON
+const MAX_EXTRA_LEN: usize = 4096;
+/// How much later a device may seem to have booted, as uptimes and latencies are rounded, before
+/// it counts as rebooted
+const REBOOT_SLACK: u64 = 60;
+const MAX_BULK_USERS: usize = 100;
+const LATENCY_SAMPLES: usize = 10;
+/// Seconds at least between two accepted `/beat`s of a device
+const BEAT_MIN_INTERVAL: u64 = 30;
+/// Events a slow bot may fall behind by before it misses some
+const EVENT_BACKLOG: usize = 64;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Device {
+    last_seen: u64,
+    /// First heartbeat of the current session
+    #[serde(default)]
+    online_since: u64,
+    message: Option<String>,
+    /// Round-trip times reported by the client, most recent last
+    #[serde(default)]
+    latency_ms: VecDeque<u32>,
+    /// Seconds between recent heartbeats
+    #[serde(default)]
+    intervals: VecDeque<u64>,
+    #[serde(default)]
+    flaky: bool,
+    /// Fingerprint of the key signing the latest heartbeat, in hex
+    #[serde(default)]
+    key: Option<String>,
+    /// Client timestamp of the latest accepted heartbeat
+    #[serde(default)]
+    timestamp: u64,
+    /// Where the latest heartbeat came from, if a GeoIP database is configured
+    #[serde(default)]
+    location: Option<String>,
+    #[serde(default)]
+    network: Option<String>,
+    /// Label of the network the device is on, as the client reports it
+    #[serde(default)]
+    place: Option<String>,
+    /// Seconds between heartbeats the device announced, unknown for older clients and `/ping`
+    #[serde(default)]
+    interval: Option<u64>,
+    /// When the device booted, by server time, from the uptime it reported
+    #[serde(default)]
+    booted_at: Option<u64>,
+    /// Reported with the latest heartbeat, if the client sends them
+    #[serde(default)]
+    metrics: Option<SystemMetrics>,
+    /// Custom fields of the latest heartbeat, kept as they are
+    #[serde(default)]
+    extra: Option<Map<String, Value>>,
+    /// When the device was archived for not being seen for `ZOMBIE_TIMEOUT` seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archived: Option<u64>,
+}
+
+impl Device {
+    /// Seconds without a heartbeat after which the device is offline, a few of its intervals
+    fn offline_timeout(&self) -> u64 {
+        self.interval
+            .map_or(OFFLINE_TIMEOUT, |interval| interval * MISSED_HEARTBEATS)
+    }
+
+    fn online(&self, now: u64) -> bool {
+        self.last_seen + self.offline_timeout() >= now
+    }
+}
+
+type Devices = HashMap<String, Device>; // device name -> device
+/// The devices of every user, each shared with the snapshots until it changes, so that a
+/// heartbeat only copies the devices of its user
+type ClientMap = HashMap<String, Arc<Devices>>;
+
+/// The locked client map, published as a new snapshot when unlocked if it was changed
+struct ClientsGuard<'a> {
+    clients: MutexGuard<'a, ClientMap>,
+    published: &'a RwLock<Arc<ClientMap>>,
+    changed: bool,
+}
+
+impl Deref for ClientsGuard<'_> {
+    type Target = ClientMap;
+
+    fn deref(&self) -> &ClientMap {
+        &self.clients
+    }
+}
+
+impl DerefMut for ClientsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ClientMap {
+        self.changed = true;
+        &mut self.clients
+    }
+}
+
+impl ClientsGuard<'_> {
+    /// The devices of `user` for changes, copied if a snapshot still shares them
+    fn devices_mut(&mut self, user: &str) -> &mut Devices {
+        Arc::make_mut(self.entry(user.to_string()).or_default())
+    }
+
+    /// Those of a user who has devices
+    fn get_devices_mut(&mut self, user: &str) -> Option<&mut Devices> {
+        self.get_mut(user).map(Arc::make_mut)
+    }
+}
+
+impl Drop for ClientsGuard<'_> {
+    fn drop(&mut self) {
+        if self.changed {
+            // Only the devices of changed users are not shared with the last snapshot
+            *self.published.write().unwrap() = Arc::new(self.clients.clone());
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct AppState {
-    clients: Arc<Mutex<HashMap<IpAddr, u64>>>, // IP address -> timestamp
-    public_key: Arc<Option<pgp::SignedPublicKey>>,
+    /// Changed by heartbeats and the like, under [`AppState::lock_clients`]
+    clients: Arc<Mutex<ClientMap>>,
+    /// The client map after the last change, for reading
+    published: Arc<RwLock<Arc<ClientMap>>>,
+    /// Devices taken out of the client map, for the admin API and in case they come back
+    archive: Arc<Mutex<HashMap<String, Devices>>>,
+    /// Devices deleted within `cluster::TOMBSTONE_TIMEOUT`, for the other nodes of a cluster
+    deleted: Arc<Mutex<cluster::Tombstones>>,
+    peers: Arc<Mutex<HashMap<String, federation::PeerStatus>>>,
+    presence: Arc<Mutex<HashMap<String, presence::Presence>>>,
+    /// Signatures accepted within the last `TIMEOUT` seconds, each with its timestamp,
+    /// so that none is accepted twice
+    signatures: Arc<Mutex<HashMap<Vec<String>, u64>>>,
+    /// Statuses set through the admin API, by user
+    overrides: Arc<Mutex<HashMap<String, admin::StatusOverride>>>,
+    /// Events of the configured calendars, by URL
+    calendars: Arc<Mutex<HashMap<String, calendar::Fetched>>>,
+    metrics: Arc<metrics::Metrics>,
+    watchdog: Arc<watchdog::Watchdog>,
+    /// The latest delivery to every sink events or statuses are sent to
+    deliveries: Arc<Mutex<BTreeMap<String, notify::Delivery>>>,
+    /// Admins logged in with the identity provider
+    sessions: Arc<Mutex<oidc::Sessions>>,
+    /// Admin actions and reloads, oldest first
+    audit: Arc<Mutex<Vec<audit::AuditEntry>>>,
+    /// Every event, for the bots to announce
+    events: broadcast::Sender<notify::Event>,
+    /// Every accepted heartbeat, for the message queue
+    #[cfg(feature = "nats")]
+    receipts: broadcast::Sender<nats::Receipt>,
+    settings: Arc<RwLock<Arc<Settings>>>,
+    args: Arc<Args>,
+    http: reqwest::Client,
+    /// When the server started, as Unix time
+    started: u64,
 }
 
-pub async fn server_main(args: Args) -> Result<(), Box<dyn Error>> {
-    let public_key = if let Some(path) = args.pubkey {
-        let content = File::open(path).and_then(|mut f| {
-            let mut s = String::new();
-            f.read_to_string(&mut s)?;
-            Ok(s)
-        })?;
-        let (public_key, _) = SignedPublicKey::from_string(&content)?;
-        Some(public_key)
-    } else {
-        None
-    };
-    let state = AppState {
-        clients: Arc::new(Mutex::new(HashMap::new())),
-        public_key: Arc::new(public_key),
+/// Everything derived from the configuration, swapped as a whole on reload
+#[derive(Debug)]
+struct Settings {
+    users: HashMap<String, User>,
+    viewers: HashMap<String, Viewer>,
+    admin_token: Option<String>,
+    oidc: Option<OidcConfig>,
+    share_secret: Option<String>,
+    server_key: Option<SignedSecretKey>,
+    name: Option<String>,
+    peers: Vec<PeerConfig>,
+    peer_interval: Option<u64>,
+    relay: Option<relay::Relay>,
+    cluster: Option<ClusterConfig>,
+    mirror: Option<MirrorConfig>,
+    lan: Option<LanConfig>,
+    calendars: Vec<CalendarConfig>,
+    external: Vec<ExternalSource>,
+    canary: Option<CanaryConfig>,
+    dead_man: Vec<DeadManConfig>,
+    escalation: Vec<EscalationConfig>,
+    alert: Vec<AlertConfig>,
+    /// Fixed at startup, like the other routes
+    heartbeat_path: String,
+    status_path: String,
+    signature_cache: signature_cache::SignatureCache,
+    access_log: Option<access_log::AccessLog>,
+    notify: Vec<NotifyConfig>,
+    exec: Vec<ExecConfig>,
+    irc: Option<IrcConfig>,
+    #[cfg(feature = "matrix")]
+    matrix: Option<MatrixConfig>,
+    #[cfg(feature = "nats")]
+    nats: Option<NatsConfig>,
+    push: Option<PushConfig>,
+    slack: Option<SlackConfig>,
+    github: Option<GithubConfig>,
+    flaky_threshold: f64,
+    history: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    retention_days: Option<u64>,
+    /// Seconds after startup without offline statuses
+    warmup: u64,
+    reuse_port: bool,
+    drain_timeout: u64,
+    /// Checked to be a version
+    min_client_version: Option<String>,
+    status_template: Option<String>,
+    #[cfg(feature = "scripting")]
+    status_script: Option<script::Script>,
+    #[cfg(feature = "plugins")]
+    plugins: Option<Arc<plugins::Plugins>>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<geoip::GeoIp>,
+    heartbeat_filter: ip_filter::IpFilter,
+    anonymizer: anonymize::Anonymizer,
+    /// Hash algorithms signatures may be made with
+    hash_algorithms: Vec<HashAlgorithm>,
+}
+
+#[derive(Debug)]
+struct User {
+    public_keys: Vec<SignedPublicKey>,
+    tokens: Vec<String>,
+    /// Device name -> token
+    device_tokens: HashMap<String, String>,
+    privacy: Privacy,
+    viewer_tokens: Vec<String>,
+    friends: HashMap<String, Privacy>,
+    quiet_hours: Option<QuietHours>,
+    primary_device: Option<String>,
+    /// Upper-case device names and key fingerprints
+    ignore_devices: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Viewer {
+    tokens: Vec<String>,
+    public_keys: Vec<SignedPublicKey>,
+}
+
+/// The signature of a heartbeat, decoded
+enum HeartbeatSignature {
+    /// Raw MPIs over the timestamp, made with the given hash
+    Mpis(HashAlgorithm, Vec<Mpi>),
+    /// Standard detached OpenPGP signature of the timestamp
+    Detached(StandaloneSignature),
+}
+
+/// What a viewer presented to identify themselves
+struct ViewerCredentials<'a> {
+    token: Option<&'a str>,
+    share: Option<&'a str>,
+    /// Timestamp and detached signature over it
+    signed: Option<(u64, StandaloneSignature)>,
+}
+
+impl<'a> ViewerCredentials<'a> {
+    /// Those of anyone, like the people in a chat room
+    fn anonymous() -> Self {
+        ViewerCredentials {
+            token: None,
+            share: None,
+            signed: None,
+        }
+    }
+
+    fn from_request(headers: &'a HeaderMap, query: &'a ViewerQuery) -> Result<Self, StatusCode> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let signed = match (header("x-viewer-timestamp"), header("x-viewer-signature")) {
+            (Some(timestamp), Some(signature)) => Some((
+                timestamp.parse().map_err(|_| StatusCode::BAD_REQUEST)?,
+                parse_detached(signature).map_err(|_| StatusCode::BAD_REQUEST)?,
+            )),
+            (None, None) => None,
+            _ => return Err(StatusCode::BAD_REQUEST),
+        };
+        Ok(ViewerCredentials {
+            token: bearer_token(headers).or(query.token.as_deref()),
+            share: query.share.as_deref(),
+            signed,
+        })
+    }
+}
+
+impl User {
+    fn load(config: UserConfig) -> Result<Self, Box<dyn Error>> {
+        let public_keys = config
+            .pubkeys
+            .iter()
+            .map(|path| read_public_key(path))
+            .collect::<Result<_, _>>()?;
+        Ok(User {
+            public_keys,
+            tokens: config.tokens,
+            device_tokens: config.device_tokens.into_iter().collect(),
+            privacy: config.privacy,
+            viewer_tokens: config.viewer_tokens,
+            friends: config.friends.into_iter().collect(),
+            quiet_hours: config.quiet_hours,
+            primary_device: config.primary_device,
+            ignore_devices: config
+                .ignore_devices
+                .iter()
+                .map(|d| d.replace(' ', "").to_uppercase())
+                .collect(),
+        })
+    }
+
+    /// Whether a device does not count towards the user being online
+    fn ignores(&self, name: &str, device: &Device) -> bool {
+        self.ignore_devices
+            .iter()
+            .any(|ignored| *ignored == name.to_uppercase() || device.key.as_ref() == Some(ignored))
+    }
+}
+
+impl Settings {
+    fn load(args: &Args) -> Result<Self, Box<dyn Error>> {
+        let config = load_server_config(args)?;
+        #[cfg(not(feature = "scripting"))]
+        if config.status_script.is_some() {
+            return Err("status_script needs a build with the scripting feature".into());
+        }
+        #[cfg(not(feature = "plugins"))]
+        if config.plugins.is_some() {
+            return Err("plugins need a build with the plugins feature".into());
+        }
+        for path in [&config.heartbeat_path, &config.status_path]
+            .into_iter()
+            .flatten()
+        {
+            if !path.starts_with('/') || path.len() < 2 {
+                return Err(
+                    format!("Invalid route {:?}, must start with / and be longer", path).into(),
+                );
+            }
+        }
+        listener::check_reuse_port(config.reuse_port)?;
+        if config.mirror.is_some() && config.cluster.is_some() {
+            return Err("A mirror cannot be part of a cluster".into());
+        }
+        if config.mirror.is_some() && config.lan.is_some() {
+            return Err("A mirror cannot watch the local network".into());
+        }
+        #[cfg(not(feature = "matrix"))]
+        if config.matrix.is_some() {
+            return Err("matrix needs a build with the matrix feature".into());
+        }
+        #[cfg(not(feature = "nats"))]
+        if config.nats.is_some() {
+            return Err("nats needs a build with the nats feature".into());
+        }
+        #[cfg(not(feature = "geoip"))]
+        if config.geoip.is_some() || config.geoip_asn.is_some() {
+            return Err("geoip needs a build with the geoip feature".into());
+        }
+        let mut users = HashMap::new();
+        users.insert(
+            DEFAULT_USER.to_string(),
+            User::load(UserConfig {
+                pubkeys: config.pubkeys,
+                tokens: config.tokens,
+                device_tokens: config.device_tokens,
+                privacy: config.privacy,
+                viewer_tokens: config.viewer_tokens,
+                friends: config.friends,
+                quiet_hours: config.quiet_hours,
+                primary_device: config.primary_device,
+                ignore_devices: config.ignore_devices,
+            })?,
+        );
+        for (name, user) in config.users {
+            users.insert(name, User::load(user)?);
+        }
+        let mut viewers = HashMap::new();
+        for (name, viewer) in config.viewers {
+            let public_keys = viewer
+                .pubkeys
+                .iter()
+                .map(|path| read_public_key(path))
+                .collect::<Result<_, _>>()?;
+            viewers.insert(
+                name,
+                Viewer {
+                    tokens: viewer.tokens,
+                    public_keys,
+                },
+            );
+        }
+        let lan = match config.lan {
+            Some(lan) => Some(lan::load(lan, &users)?),
+            None => None,
+        };
+        let calendars = calendar::load(config.calendars, &users)?;
+        let external = external::load(config.external, &users)?;
+        let canary = match config.canary {
+            Some(canary) => Some(canary::load(canary, &users)?),
+            None => None,
+        };
+        let dead_man = dead_man::load(config.dead_man, &users)?;
+        let escalation = escalation::load(config.escalation, &users)?;
+        let alert = alert::load(config.alert, &users)?;
+        if let Some(user) = config.slack.as_ref().and_then(|s| s.user.as_ref()) {
+            if !users.contains_key(user) {
+                return Err(format!("Slack status of unknown user {}", user).into());
+            }
+        }
+        if let Some(user) = config.github.as_ref().and_then(|g| g.user.as_ref()) {
+            if !users.contains_key(user) {
+                return Err(format!("GitHub status of unknown user {}", user).into());
+            }
+        }
+        #[cfg(feature = "matrix")]
+        if let Some(user) = config.matrix.as_ref().and_then(|m| m.presence.as_ref()) {
+            match users.get(user) {
+                None => return Err(format!("Matrix presence of unknown user {}", user).into()),
+                Some(u) if u.privacy == Privacy::Private => {
+                    return Err(format!("Matrix presence of private user {}", user).into())
+                }
+                Some(_) => {}
+            }
+        }
+        let server_key = match &config.server_key {
+            Some(path) => Some(read_secret_key(path)?),
+            None => None,
+        };
+        let relay = match config.relay {
+            Some(relay) => Some(relay::Relay {
+                url: relay.url,
+                privkey: match &relay.privkey {
+                    Some(path) => Some(read_secret_key(path)?),
+                    None => None,
+                },
+                token: relay.token,
+                hash: match &relay.hash {
+                    Some(hash) => parse_hash(hash)?,
+                    None => HashAlgorithm::default(),
+                },
+            }),
+            None => None,
+        };
+        Ok(Settings {
+            users,
+            viewers,
+            admin_token: config.admin_token,
+            share_secret: config.share_secret,
+            oidc: match config.oidc {
+                Some(oidc) => Some(oidc::load(oidc)?),
+                None => None,
+            },
+            server_key,
+            name: config.name,
+            peers: config.peers,
+            peer_interval: config.peer_interval,
+            relay,
+            cluster: config.cluster,
+            mirror: config.mirror,
+            lan,
+            calendars,
+            external,
+            canary,
+            dead_man,
+            escalation,
+            alert,
+            heartbeat_path: config
+                .heartbeat_path
+                .or_else(|| args.heartbeat_path.clone())
+                .unwrap_or_else(|| "/heartbeat".to_string()),
+            status_path: config.status_path.unwrap_or_else(|| "/status".to_string()),
+            signature_cache: Default::default(),
+            access_log: match &config.access_log {
+                Some(path) => Some(
+                    access_log::AccessLog::open(path)
+                        .map_err(|e| format!("{}: {}", path.display(), e))?,
+                ),
+                None => None,
+            },
+            notify: config.notify,
+            exec: exec::load(config.exec)?,
+            irc: config.irc,
+            #[cfg(feature = "matrix")]
+            matrix: config.matrix,
+            #[cfg(feature = "nats")]
+            nats: config.nats,
+            push: config.push,
+            slack: config.slack,
+            github: config.github,
+            flaky_threshold: config
+                .flaky_threshold
+                .unwrap_or(stability::DEFAULT_FLAKY_THRESHOLD),
+            history: config.history,
+            audit_log: config.audit_log,
+            retention_days: config.retention_days,
+            warmup: config.warmup.unwrap_or(0),
+            reuse_port: config.reuse_port,
+            drain_timeout: config
+                .drain_timeout
+                .unwrap_or(listener::DEFAULT_DRAIN_TIMEOUT),
+            min_client_version: match config.min_client_version {
+                Some(version) => {
+                    client_version::Version::parse(&version)
+                        .map_err(|e| format!("min_client_version: {}", e))?;
+                    Some(version)
+                }
+                None => None,
+            },
+            #[cfg(feature = "scripting")]
+            status_script: match &config.status_script {
+                Some(path) => Some(script::Script::load(path)?),
+                None => None,
+            },
+            #[cfg(feature = "plugins")]
+            plugins: match &config.plugins {
+                Some(dir) => Some(plugins::Plugins::load(dir)?),
+                None => None,
+            },
+            heartbeat_filter: ip_filter::IpFilter {
+                allow: config.heartbeat_allow,
+                deny: config.heartbeat_deny,
+            },
+            anonymizer: anonymize::Anonymizer::new(config.ip_addresses, config.ip_salt),
+            hash_algorithms: if config.hash_algorithms.is_empty() {
+                STRONG_HASHES.to_vec()
+            } else {
+                config
+                    .hash_algorithms
+                    .iter()
+                    .map(|name| parse_hash(name))
+                    .collect::<Result<_, _>>()?
+            },
+            #[cfg(feature = "geoip")]
+            geoip: match (&config.geoip, &config.geoip_asn) {
+                (None, None) => None,
+                (city, asn) => Some(geoip::GeoIp::load(city.as_deref(), asn.as_deref())?),
+            },
+            status_template: match config.status_template {
+                Some(t) => template::validate(&t).map(|_| Some(t))?,
+                None => None,
+            },
+        })
+    }
+
+    /// Whether heartbeats must carry a valid signature or token
+    fn requires_auth(&self) -> bool {
+        self.users
+            .values()
+            .any(|u| !u.public_keys.is_empty() || !u.tokens.is_empty())
+    }
+
+    /// Find the user a heartbeat belongs to, and the fingerprint of the key it was signed with
+    fn authenticate(
+        &self,
+        info: &HeartBeat,
+        token: Option<&str>,
+    ) -> Result<(&str, Option<String>), ApiError> {
+        if !self.requires_auth() {
+            return Ok((DEFAULT_USER, None));
+        }
+        if let Some(token) = token {
+            return self
+                .users
+                .iter()
+                .find(|(_, u)| u.tokens.iter().any(|t| t == token))
+                .map(|(name, _)| (name.as_str(), None))
+                .ok_or_else(|| {
+                    ApiError::new(StatusCode::UNAUTHORIZED, "token_invalid", "Unknown token")
+                });
+        }
+        let invalid = || {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "signature_invalid",
+                "Signature does not match any known key",
+            )
+        };
+        let malformed =
+            |message| ApiError::new(StatusCode::BAD_REQUEST, "signature_malformed", message);
+        let rejected = |detail: String| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "hash_rejected",
+                "Hash algorithm is not accepted",
+            )
+            .detail(detail)
+        };
+        let unaccepted = |hash| {
+            rejected(format!(
+                "{} is not one of {}",
+                hash,
+                self.hash_names().join(", ")
+            ))
+        };
+        let (cache_key, signature) = match (&info.signature, &info.detached_signature) {
+            (Some(signature), None) => {
+                let hash = match &info.hash {
+                    Some(name) => parse_hash(name).map_err(rejected)?,
+                    None => HashAlgorithm::default(),
+                };
+                if !self.hash_algorithms.contains(&hash) {
+                    return Err(unaccepted(hash));
+                }
+                let mpis = signature
+                    .iter()
+                    .map(|s| hex::decode(s).map(Mpi::from_raw))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| malformed("Signature is not hex-encoded").detail(e.to_string()))?;
+                (
+                    signature_cache::key(info.timestamp, hash, signature),
+                    HeartbeatSignature::Mpis(hash, mpis),
+                )
+            }
+            (None, Some(text)) => {
+                let signature = parse_detached(text)
+                    .map_err(|e| malformed("Detached signature cannot be read").detail(e))?;
+                let hash = signature.signature.hash_alg();
+                if !self.hash_algorithms.contains(&hash) {
+                    return Err(unaccepted(hash));
+                }
+                (
+                    signature_cache::key(info.timestamp, hash, std::slice::from_ref(text)),
+                    HeartbeatSignature::Detached(signature),
+                )
+            }
+            (Some(_), Some(_)) => {
+                return Err(malformed("Give either signature or detached_signature"));
+            }
+            (None, None) => {
+                return Err(ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "credentials_missing",
+                    "Neither a signature nor a token was given",
+                ));
+            }
+        };
+        if let Some(signer) = self.signature_cache.get(&cache_key) {
+            let (name, fingerprint) = signer.ok_or_else(invalid)?;
+            let (name, _) = self.users.get_key_value(&name).ok_or_else(invalid)?;
+            return Ok((name, Some(fingerprint)));
+        }
+        let data = info.timestamp.to_string().into_bytes();
+        let mut result = Err(invalid());
+        for (name, user) in &self.users {
+            for public_key in &user.public_keys {
+                let checked = match &signature {
+                    HeartbeatSignature::Mpis(hash, mpis) => {
+                        public_key.verify_signature(*hash, &data, mpis)
+                    }
+                    HeartbeatSignature::Detached(signature) => {
+                        match verify_detached(public_key, signature, &data, &self.hash_algorithms) {
+                            true => Ok(()),
+                            false => continue,
+                        }
+                    }
+                };
+                match checked {
+                    Ok(()) => {
+                        let fingerprint = hex::encode_upper(public_key.fingerprint());
+                        self.signature_cache
+                            .insert(cache_key, Some((name.clone(), fingerprint.clone())));
+                        return Ok((name, Some(fingerprint)));
+                    }
+                    Err(pgp::errors::Error::SignatureError(_)) => {}
+                    Err(e) => {
+                        result = Err(malformed("Signature cannot be checked").detail(e.to_string()))
+                    }
+                }
+            }
+        }
+        if result
+            .as_ref()
+            .is_err_and(|e| e.code() == "signature_invalid")
+        {
+            self.signature_cache.insert(cache_key, None);
+        }
+        result
+    }
+
+    /// Names of the accepted hash algorithms, as clients give them
+    fn hash_names(&self) -> Vec<String> {
+        self.hash_algorithms.iter().map(|h| h.to_string()).collect()
+    }
+
+    /// Find the named viewer presenting these credentials
+    fn identify_viewer(
+        &self,
+        credentials: &ViewerCredentials,
+        now: u64,
+    ) -> Result<Option<&str>, StatusCode> {
+        if let Some(token) = credentials.token {
+            return self
+                .viewers
+                .iter()
+                .find(|(_, v)| v.tokens.iter().any(|t| t == token))
+                .map(|(name, _)| Some(name.as_str()))
+                .ok_or(StatusCode::UNAUTHORIZED);
+        }
+        let Some((timestamp, signature)) = &credentials.signed else {
+            return Ok(None);
+        };
+        if now.abs_diff(*timestamp) > TIMEOUT {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let data = timestamp.to_string().into_bytes();
+        self.viewers
+            .iter()
+            .find(|(_, v)| {
+                v.public_keys
+                    .iter()
+                    .any(|key| verify_detached(key, signature, &data, &self.hash_algorithms))
+            })
+            .map(|(name, _)| Some(name.as_str()))
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+
+    /// How much of a user's status the requester may see
+    fn access(
+        &self,
+        user: &str,
+        credentials: &ViewerCredentials,
+        now: u64,
+    ) -> Result<Privacy, StatusCode> {
+        if let Some(token) = credentials.share {
+            let secret = self
+                .share_secret
+                .as_deref()
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let shared = share::verify(secret, token, user, now).ok_or(StatusCode::UNAUTHORIZED)?;
+            return Ok(shared.max(self.users.get(user).ok_or(StatusCode::NOT_FOUND)?.privacy));
+        }
+        let user = self.users.get(user).ok_or(StatusCode::NOT_FOUND)?;
+        if let Some(token) = credentials.token {
+            if user.viewer_tokens.iter().any(|t| t == token) {
+                return Ok(Privacy::Messages);
+            }
+        }
+        let privacy = match self.identify_viewer(credentials, now)? {
+            Some(viewer) => user
+                .friends
+                .get(viewer)
+                .map_or(user.privacy, |&p| p.max(user.privacy)),
+            None => user.privacy,
+        };
+        if privacy == Privacy::Private {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(privacy)
+    }
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    status: Cow<'static, str>,
+    /// Whether the devices make the user online, before the script, plugins, calendar or an
+    /// override change the status, so that a meeting is no incident
+    #[serde(skip)]
+    online: bool,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Start of the current streak of at least one device being online
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_since: Option<u64>,
+    /// When the last device was last seen, if none is online
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offline_since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Vec<DeviceReport>>,
+    /// When the server started
+    server_started: u64,
+    /// Devices may not have sent a heartbeat since the server started yet, so they may be shown
+    /// as offline although they are not
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    incomplete: bool,
+}
+
+#[derive(Serialize)]
+struct DeviceReport {
+    name: String,
+    status: &'static str,
+    last_seen: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offline_since: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Most recent heartbeat round-trip time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u32>,
+    /// Average over the last few heartbeats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_latency_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stability: Option<stability::Stability>,
+    /// Coarse location of the latest heartbeat's address, like `Berlin, DE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    /// Autonomous system of that address, like `AS3320 Deutsche Telekom AG`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    /// Label of the network it is on, like `work`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    place: Option<String>,
+    /// When the device last booted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    booted_at: Option<u64>,
+    /// Load, memory and disk use, if the client reports them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics: Option<SystemMetrics>,
+    /// Custom fields the client attached, as they are
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<Map<String, Value>>,
+    /// Does not count towards the user being online
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ignored: bool,
+}
+
+#[derive(Deserialize)]
+struct ViewerQuery {
+    token: Option<String>,
+    /// Token of a share link
+    share: Option<String>,
+}
+
+impl AppState {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// The client map, for changes, which readers see once it is unlocked again
+    fn lock_clients(&self) -> ClientsGuard<'_> {
+        ClientsGuard {
+            clients: watchdog::lock(&self.clients),
+            published: &self.published,
+            changed: false,
+        }
+    }
+
+    /// The client map as last published, which neither waits for nor holds up writers
+    fn snapshot(&self) -> Arc<ClientMap> {
+        self.published.read().unwrap().clone()
+    }
+
+    /// Archive devices not seen for `ZOMBIE_TIMEOUT` seconds, and purge archived ones and audit
+    /// entries after `retention_days`
+    fn prune(&self, now: u64) {
+        let stale = |d: &Device| now.saturating_sub(d.last_seen) > ZOMBIE_TIMEOUT;
+        let mut archived = Vec::new();
+        if self.snapshot().values().flat_map(|d| d.values()).any(stale) {
+            for (user, devices) in self.lock_clients().iter_mut() {
+                if !devices.values().any(stale) {
+                    continue;
+                }
+                for (name, device) in Arc::make_mut(devices).extract_if(|_, d| stale(d)) {
+                    archived.push((user.clone(), name, device));
+                }
+            }
+        }
+
+        // Not locked before the client map, which heartbeats lock it within
+        let mut archive = self.archive.lock().unwrap();
+        for (user, name, mut device) in archived {
+            info!(
+                "Archived device {}, last seen at {}",
+                name, device.last_seen
+            );
+            device.archived = Some(now);
+            archive.entry(user).or_default().insert(name, device);
+        }
+        // Also drops the ones that came back other than by heartbeats, like through the LAN
+        let clients = self.snapshot();
+        let retention = self
+            .settings()
+            .retention_days
+            .map(|days| days * presence::DAY);
+        for (user, devices) in archive.iter_mut() {
+            devices.retain(|name, d| {
+                let age = now.saturating_sub(d.archived.unwrap_or(now));
+                retention.is_none_or(|r| age <= r)
+                    && !clients.get(user).is_some_and(|c| c.contains_key(name))
+            });
+        }
+        archive.retain(|_, devices| !devices.is_empty());
+        drop(archive);
+        if let Some(retention) = retention {
+            audit::prune(self, now.saturating_sub(retention));
+        }
+    }
+
+    /// The status of a user, with as much detail as `privacy` allows
+    fn report(&self, user: &str, now: u64, privacy: Privacy) -> StatusReport {
+        let clients = self.snapshot();
+        let none = HashMap::new();
+        let devices = clients.get(user).map_or(&none, |d| &**d);
+
+        let settings = self.settings();
+        let ignored =
+            |name: &str, d: &Device| settings.users.get(user).is_some_and(|u| u.ignores(name, d));
+        let counted: Vec<&Device> = devices
+            .iter()
+            .filter(|(name, d)| !ignored(name, d))
+            .map(|(_, d)| d)
+            .collect();
+        let online = |d: &Device| d.online(now);
+        let status = |d: &Device| if online(d) { "ONLINE" } else { "OFFLINE" };
+        let message = if privacy >= Privacy::Messages {
+            counted
+                .iter()
+                .filter(|d| online(d))
+                .max_by_key(|d| d.last_seen)
+                .and_then(|d| d.message.clone())
+        } else {
+            None
+        };
+        let device_reports = (privacy >= Privacy::Devices).then(|| {
+            let mut reports: Vec<_> = devices
+                .iter()
+                .map(|(name, d)| DeviceReport {
+                    name: name.clone(),
+                    status: status(d),
+                    last_seen: d.last_seen,
+                    online_since: online(d).then_some(d.online_since),
+                    offline_since: (!online(d)).then_some(d.last_seen),
+                    message: d.message.clone().filter(|_| privacy >= Privacy::Messages),
+                    latency_ms: d.latency_ms.back().copied(),
+                    avg_latency_ms: (!d.latency_ms.is_empty()).then(|| {
+                        (d.latency_ms.iter().map(|&l| l as u64).sum::<u64>()
+                            / d.latency_ms.len() as u64) as u32
+                    }),
+                    stability: d.stability(),
+                    location: d.location.clone().filter(|_| privacy >= Privacy::Messages),
+                    network: d.network.clone().filter(|_| privacy >= Privacy::Messages),
+                    place: d.place.clone(),
+                    booted_at: d.booted_at,
+                    metrics: d.metrics.clone().filter(|_| privacy >= Privacy::Messages),
+                    extra: d.extra.clone().filter(|_| privacy >= Privacy::Messages),
+                    ignored: ignored(name, d),
+                })
+                .collect();
+            reports.sort_by(|a, b| a.name.cmp(&b.name));
+            reports
+        });
+        let (online_since, offline_since) = if privacy >= Privacy::Devices {
+            streak(&counted, now)
+        } else {
+            (None, None)
+        };
+        let status: Cow<str> = if counted.iter().any(|d| online(d)) {
+            "ONLINE".into()
+        } else {
+            self.offline_status(user, now).into()
+        };
+        let online = status == "ONLINE";
+        #[cfg(feature = "scripting")]
+        let status = match &settings.status_script {
+            Some(script) => script
+                .status(user, &status, devices, ignored, now)
+                .map_or(status, Cow::Owned),
+            None => status,
+        };
+        #[cfg(feature = "plugins")]
+        let status = match &settings.plugins {
+            Some(plugins) => plugins
+                .status(user, status.into_owned(), devices, ignored, now)
+                .into(),
+            None => status,
+        };
+        let status = match status == "ONLINE" {
+            true => calendar::busy_status(self, user, now).map_or(status, Cow::Owned),
+            false => status,
+        };
+        let status = match self.status_override(user, now) {
+            Some(status) => status.into(),
+            None => status,
+        };
+        StatusReport {
+            status,
+            online,
+            timestamp: now,
+            message,
+            online_since,
+            offline_since,
+            devices: device_reports,
+            server_started: self.started,
+            // Devices announcing the longest interval count as online that long without a
+            // heartbeat
+            incomplete: now
+                < self.started + (MAX_INTERVAL * MISSED_HEARTBEATS).max(settings.warmup),
+        }
+    }
+
+    /// Whether the server started less than `warmup` seconds ago
+    fn warming_up(&self, now: u64) -> bool {
+        now < self.started + self.settings().warmup
+    }
+
+    /// The status of a user with no device online, which may just not have sent a heartbeat
+    /// since the server started
+    fn offline_status(&self, user: &str, now: u64) -> &'static str {
+        if !self.warming_up(now) {
+            return "OFFLINE";
+        }
+        match self.presence.lock().unwrap().get(user).map(|p| p.online) {
+            Some(true) => "ONLINE",
+            Some(false) => "OFFLINE",
+            None => "UNKNOWN",
+        }
+    }
+
+    /// The status set through the admin API, if it has not expired
+    fn status_override(&self, user: &str, now: u64) -> Option<String> {
+        let overrides = self.overrides.lock().unwrap();
+        overrides
+            .get(user)
+            .filter(|o| o.until.is_none_or(|until| now < until))
+            .map(|o| o.status.clone())
+    }
+
+    /// Load the configuration again, on behalf of `actor` for the audit log
+    fn reload(&self, actor: &str) -> Result<(), Box<dyn Error>> {
+        let settings = Arc::new(Settings::load(&self.args)?);
+        info!("Configuration reloaded, {} user(s)", settings.users.len());
+        let old = std::mem::replace(&mut *self.settings.write().unwrap(), settings.clone());
+        audit::reloaded(self, actor, &old, &settings);
+        Ok(())
+    }
+}
+
+/// When the user's current online or offline streak began. Device sessions
+/// that overlap extend the online streak back to the earliest of them.
+fn streak(devices: &[&Device], now: u64) -> (Option<u64>, Option<u64>) {
+    let online: Vec<_> = devices.iter().filter(|d| d.online(now)).collect();
+    let Some(mut since) = online.iter().map(|d| d.online_since).min() else {
+        return (None, devices.iter().map(|d| d.last_seen).max());
     };
-    let app = Router::new()
+    loop {
+        let earlier = devices
+            .iter()
+            .filter(|d| d.online_since < since && d.online(since))
+            .map(|d| d.online_since)
+            .min();
+        match earlier {
+            Some(earlier) => since = earlier,
+            None => return (Some(since), None),
+        }
+    }
+}
+
+pub async fn server_main(args: Args) -> Result<(), Box<dyn Error>> {
+    let settings = Settings::load(&args).map_err(exit::config)?;
+    let port = args.port.unwrap();
+    let state = AppState::new(args, settings).map_err(exit::config)?;
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone()));
+    tokio::spawn(federation::poll_peers(state.clone()));
+    tokio::spawn(cluster::gossip(state.clone()));
+    tokio::spawn(mirror::poll(state.clone()));
+    tokio::spawn(lan::watch(state.clone()));
+    tokio::spawn(calendar::poll(state.clone()));
+    tokio::spawn(presence::watch(state.clone()));
+    tokio::spawn(watchdog::watch(state.clone()));
+    tokio::spawn(canary::beat(state.clone()));
+    tokio::spawn(dead_man::watch(state.clone()));
+    tokio::spawn(escalation::watch(state.clone()));
+    tokio::spawn(alert::watch(state.clone()));
+    tokio::spawn(irc::run(state.clone()));
+    #[cfg(feature = "matrix")]
+    tokio::spawn(matrix::run(state.clone()));
+    #[cfg(feature = "nats")]
+    tokio::spawn(nats::run(state.clone()));
+    tokio::spawn(push::run(state.clone()));
+    tokio::spawn(sink::sync::<SlackConfig>(state.clone()));
+    tokio::spawn(sink::sync::<GithubConfig>(state.clone()));
+
+    let settings = state.settings();
+    let listener = listener::bind(port, settings.reuse_port)?;
+    info!("listening on {}", listener.local_addr().unwrap());
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    let mut server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                stopped.await.ok();
+            })
+            .await
+    });
+    tokio::select! {
+        result = &mut server => return Ok(result??),
+        () = listener::terminated() => {}
+    }
+    // No longer accepting, so a new server on the port gets all new connections
+    let _ = stop.send(());
+    let drain = Duration::from_secs(settings.drain_timeout);
+    if tokio::time::timeout(drain, server).await.is_err() {
+        warn!("Closing the connections still open after draining");
+    }
+    Ok(())
+}
+
+impl AppState {
+    fn new(args: Args, settings: Settings) -> Result<Self, Box<dyn Error>> {
+        let presence = match &settings.history {
+            Some(path) => presence::load(path)?,
+            None => HashMap::new(),
+        };
+        let audit = match &settings.audit_log {
+            Some(path) => audit::load(path),
+            None => Vec::new(),
+        };
+        Ok(AppState {
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            published: Arc::new(RwLock::new(Arc::new(HashMap::new()))),
+            archive: Arc::new(Mutex::new(HashMap::new())),
+            deleted: Arc::new(Mutex::new(HashMap::new())),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(presence)),
+            signatures: Arc::new(Mutex::new(HashMap::new())),
+            overrides: Arc::new(Mutex::new(HashMap::new())),
+            calendars: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(metrics::Metrics::default()),
+            watchdog: Arc::new(watchdog::Watchdog::default()),
+            deliveries: Arc::new(Mutex::new(BTreeMap::new())),
+            sessions: Arc::new(Mutex::new(oidc::Sessions::default())),
+            audit: Arc::new(Mutex::new(audit)),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            #[cfg(feature = "nats")]
+            receipts: broadcast::channel(nats::RECEIPT_BACKLOG).0,
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            args: Arc::new(args),
+            http: reqwest::Client::new(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        })
+    }
+}
+
+/// Every endpoint, as configured at startup
+fn router(state: AppState) -> Router {
+    let settings = state.settings();
+    let mut app = Router::new()
         .route("/", get(teapot))
-        .route("/heartbeat", post(heartbeat))
-        .route("/status", get(status))
+        .route(&settings.status_path, get(status))
+        .route(&format!("{}.json", settings.status_path), get(status_json))
+        .route("/u/:name/status", get(user_status))
+        .route("/u/:name/status.json", get(user_status_json))
+        .route("/incidents", get(presence::incidents))
+        .route("/u/:name/incidents", get(presence::user_incidents))
+        .route("/uptime.svg", get(uptime::uptime))
+        .route("/u/:name/uptime.svg", get(uptime::user_uptime))
+        .route("/roster", get(federation::roster))
+        .route("/roster.html", get(board::page))
+        .route("/roster/events", get(board::events))
+        .route("/badge.svg", get(badge::svg))
+        .route("/u/:name/badge.svg", get(badge::user_svg))
+        .route("/badge.png", get(badge::png))
+        .route("/u/:name/badge.png", get(badge::user_png))
+        .route("/widget.js", get(widget::script))
+        .route("/widget.css", get(widget::style))
+        .route("/pubkey.asc", get(server_pubkey))
+        .route("/capabilities", get(capabilities))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics::metrics))
+        .route("/admin/reload", post(admin_reload))
+        .route("/notify/test", post(notify::test))
+        .route("/notify/status", get(notify::status))
+        .route("/dashboard", get(dashboard::page))
+        .route("/dashboard/data", get(dashboard::data))
+        .route("/login", get(oidc::login))
+        .route("/login/callback", get(oidc::callback))
+        .route("/logout", get(oidc::logout).post(oidc::logout));
+    // Mirrors only serve what they get from their primary
+    if settings.mirror.is_none() {
+        app = app
+            .route(
+                &settings.heartbeat_path,
+                post(heartbeat).route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ip_filter::filter_heartbeats,
+                )),
+            )
+            .route(
+                "/ping",
+                get(|| async { PING_USAGE }).post(ping).route_layer(
+                    middleware::from_fn_with_state(state.clone(), ip_filter::filter_heartbeats),
+                ),
+            )
+            .route(
+                "/beat",
+                get(beat).route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ip_filter::filter_heartbeats,
+                )),
+            )
+            .route(
+                "/external/:source",
+                post(external::signal).route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ip_filter::filter_heartbeats,
+                )),
+            )
+            .route("/admin/clients", get(admin::clients).post(admin::import))
+            .route("/admin/rename", post(admin::rename))
+            .route("/admin/delete", post(admin::delete))
+            .route("/admin/override", post(admin::set_override))
+            .route("/admin/share", post(share::create))
+            .route("/admin/audit", get(audit::entries))
+            .route("/history", delete(presence::purge))
+            .route("/cluster/sync", get(cluster::snapshot).post(cluster::sync));
+    }
+    if let Some(base_path) = &state.args.base_path {
+        app = Router::new().nest(base_path, app);
+    }
+    drop(settings);
+    app.fallback(|| async { ApiError::from(StatusCode::NOT_FOUND) })
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_request,
+        ))
+        // Leaves out tiny bodies and images other than SVG
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(reject_cross_site))
+        .layer(middleware::from_fn(telemetry::trace_request))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            access_log::log_request,
+        ))
         .with_state(state)
-        .fallback(|| async { StatusCode::NOT_FOUND });
+}
 
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", args.port.unwrap())).await?;
-    println!("info: listening on {}", listener.local_addr().unwrap());
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
-    Ok(())
+#[cfg(unix)]
+async fn reload_on_sighup(state: AppState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        if let Err(e) = state.reload("SIGHUP") {
+            error!("Reload failed, keeping old configuration: {}", e);
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// The password of a request with basic authentication, whatever the user name
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded).ok()?).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
+}
+
+/// Refuse requests that change something and come from another site with credentials the
+/// browser adds by itself, a cached admin password or the session cookie, so that pages the admin
+/// visits cannot call the admin endpoints on their behalf. Bearer tokens are never added this way.
+async fn reject_cross_site(req: Request, next: Next) -> Response {
+    let headers = req.headers();
+    let ambient = basic_password(headers).is_some() || headers.contains_key(header::COOKIE);
+    if !req.method().is_safe() && ambient && cross_site(headers) {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "cross_site",
+            "Requests from other sites are not accepted",
+        )
+        .into_response();
+    }
+    next.run(req).await
+}
+
+/// Whether the browser tells that a request was made by a page of another origin
+fn cross_site(headers: &HeaderMap) -> bool {
+    let value = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(site) = value("sec-fetch-site") {
+        return site != "same-origin" && site != "none";
+    }
+    // Older browsers only send the origin, which has to name this host
+    match (value(header::ORIGIN.as_str()), value(header::HOST.as_str())) {
+        (Some(origin), Some(host)) => origin
+            .split_once("://")
+            .is_none_or(|(_, authority)| authority != host),
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// Check the bearer token of an admin request, or the password a browser sent for it, or the
+/// session of an admin logged in with the identity provider
+fn authorize_admin(headers: &HeaderMap, state: &AppState) -> Result<(), StatusCode> {
+    let settings = state.settings();
+    if settings.admin_token.is_none() && settings.oidc.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let token = settings.admin_token.as_deref();
+    let sent = bearer_token(headers)
+        .map(str::to_string)
+        .or_else(|| basic_password(headers));
+    if (token.is_some() && sent.as_deref() == token) || oidc::admin(state, headers).is_some() {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+async fn admin_reload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<&'static str, ApiError> {
+    authorize_admin(&headers, &state)?;
+    state.reload(&audit::actor(&state, &headers)).map_err(|e| {
+        error!("Reload failed, keeping old configuration: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "reload_failed",
+            "Reload failed, keeping old configuration",
+        )
+        .detail(e.to_string())
+    })?;
+    Ok("Configuration reloaded")
 }
 
 async fn teapot() -> impl IntoResponse {
@@ -106,53 +1522,785 @@ async fn teapot() -> impl IntoResponse {
 async fn heartbeat(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<AppState>,
+    headers: HeaderMap,
+    trace: Option<Extension<SpanContext>>,
     Json(info): Json<HeartBeat>,
-) -> Result<&'static str, StatusCode> {
-    if let Some(public_key) = &*state.public_key {
-        if let Some(signature) = info.signature {
-            let signature: Vec<_> = signature
-                .into_iter()
-                .map(|s| Mpi::from_raw(hex::decode(s).unwrap()))
-                .collect();
-            public_key
-                .verify_signature(
-                    HashAlgorithm::default(),
-                    &info.timestamp.to_string().into_bytes(),
-                    &signature,
+) -> Result<Response, ApiError> {
+    // Scripts using /ping and /beat have no version to check
+    if let Some(min) = &state.settings().min_client_version {
+        client_version::check(min, info.client_version.as_deref())?;
+    }
+    let token = bearer_token(&headers).map(str::to_string);
+    accept(addr, state, &headers, token, trace, info).await
+}
+
+#[derive(Deserialize)]
+struct PingQuery {
+    token: Option<String>,
+    device: Option<String>,
+    message: Option<String>,
+}
+
+/// A heartbeat without a body for scripts, like `curl -X POST .../ping?token=...`
+async fn ping(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    trace: Option<Extension<SpanContext>>,
+    Query(query): Query<PingQuery>,
+) -> Result<Response, ApiError> {
+    let token = query
+        .token
+        .or_else(|| bearer_token(&headers).map(str::to_string));
+    let info = HeartBeat {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        signature: None,
+        hash: None,
+        detached_signature: None,
+        device: query.device,
+        message: query.message,
+        place: None,
+        latency_ms: None,
+        interval: None,
+        client_version: None,
+        uptime: None,
+        metrics: None,
+        extra: None,
+    };
+    accept(addr, state, &headers, token, trace, info).await
+}
+
+#[derive(Deserialize)]
+struct BeatQuery {
+    token: Option<String>,
+    device: Option<String>,
+    message: Option<String>,
+}
+
+/// A heartbeat for phone automation apps, which is one GET with the token of the device
+async fn beat(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BeatQuery>,
+) -> Result<Response, ApiError> {
+    let Some(token) = query.token else {
+        return Ok(BEAT_USAGE.into_response());
+    };
+    let settings = state.settings();
+    let (user, device) = settings
+        .users
+        .iter()
+        .find_map(|(name, u)| {
+            u.device_tokens
+                .iter()
+                .find(|(_, t)| **t == token)
+                .map(|(device, _)| (name.as_str(), device.clone()))
+        })
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "token_invalid",
+                "Unknown device token",
+            )
+        })?;
+    if query.device.as_ref().is_some_and(|d| *d != device) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "device_mismatch",
+            format!("The token is for device {}", device),
+        ));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let last_seen = state
+        .snapshot()
+        .get(user)
+        .and_then(|devices| devices.get(&device))
+        .map(|d| d.last_seen);
+    if let Some(wait) = last_seen.and_then(|l| (l + BEAT_MIN_INTERVAL).checked_sub(now)) {
+        if wait > 0 {
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, wait.to_string())],
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "beat_too_soon",
+                    format!("At most one beat every {}s", BEAT_MIN_INTERVAL),
+                ),
+            )
+                .into_response());
+        }
+    }
+    let info = HeartBeat {
+        timestamp: now,
+        signature: None,
+        hash: None,
+        detached_signature: None,
+        device: Some(device),
+        message: query.message,
+        place: None,
+        latency_ms: None,
+        interval: None,
+        client_version: None,
+        uptime: None,
+        metrics: None,
+        extra: None,
+    };
+    let user = user.to_string();
+    drop(settings);
+    record(addr, &state, &headers, &user, None, info)
+}
+
+/// Authenticate a heartbeat and update the presence of its device
+async fn accept(
+    addr: SocketAddr,
+    state: AppState,
+    headers: &HeaderMap,
+    token: Option<String>,
+    trace: Option<Extension<SpanContext>>,
+    mut info: HeartBeat,
+) -> Result<Response, ApiError> {
+    let settings = state.settings();
+    // Re-armoring a detached signature must not make it look like a new one
+    if let Some(text) = &info.detached_signature {
+        let signature = parse_detached(text)
+            .and_then(|s| s.to_bytes().map_err(|e| e.to_string()))
+            .map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "signature_malformed",
+                    "Detached signature cannot be read",
                 )
-                .map_err(|e| match e {
-                    pgp::errors::Error::SignatureError(_) => StatusCode::UNAUTHORIZED,
-                    _ => StatusCode::BAD_REQUEST,
-                })?;
-        } else {
-            return Err(StatusCode::UNAUTHORIZED);
+                .detail(e)
+            })?;
+        info.detached_signature = Some(BASE64.encode(signature));
+    }
+    let signature = info
+        .signature
+        .clone()
+        .or_else(|| info.detached_signature.clone().map(|s| vec![s]));
+    let mut span = Span::new("authenticate", SpanKind::Internal, trace.map(|t| t.0));
+    span.set("auth.token", token.is_some());
+    span.set("auth.signature", signature.is_some());
+    let verifies = signature.is_some() && token.is_none();
+    // Checking signatures is CPU-bound, so it must not hold up other requests
+    let result = tokio::task::spawn_blocking({
+        let (settings, info, token) = (settings.clone(), info.clone(), token.clone());
+        move || {
+            let start = Instant::now();
+            let result = settings
+                .authenticate(&info, token.as_deref())
+                .map(|(user, key)| (user.to_string(), key));
+            (result, start.elapsed())
         }
+    })
+    .await;
+    let (result, elapsed) =
+        result.map_err(|_| ApiError::from(StatusCode::INTERNAL_SERVER_ERROR))?;
+    if verifies {
+        state.metrics.observe_verification(elapsed);
     }
+    let (user, key) = result.inspect_err(|e| span.fail(e.code()))?;
+    drop(span);
+    record(addr, &state, headers, &user, key, info)
+}
+
+/// Update the presence of the device an authenticated heartbeat came from
+fn record(
+    addr: SocketAddr,
+    state: &AppState,
+    headers: &HeaderMap,
+    user: &str,
+    key: Option<String>,
+    info: HeartBeat,
+) -> Result<Response, ApiError> {
+    let settings = state.settings();
+    let signature = info
+        .signature
+        .clone()
+        .or_else(|| info.detached_signature.clone().map(|s| vec![s]));
 
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    if now - info.timestamp > TIMEOUT {
-        return Err(StatusCode::BAD_REQUEST);
+    if now.abs_diff(info.timestamp) > TIMEOUT {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "timestamp_stale",
+            format!("Timestamp is more than {}s off the server's clock", TIMEOUT),
+        )
+        .detail(format!("server time {}", now)));
+    }
+    let device = info
+        .device
+        .clone()
+        .unwrap_or_else(|| settings.anonymizer.stored(addr.ip()));
+    if device.is_empty() || device.len() > MAX_DEVICE_NAME_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "device_invalid",
+            format!("Device name must be 1 to {} bytes", MAX_DEVICE_NAME_LEN),
+        ));
+    }
+    if info
+        .message
+        .as_ref()
+        .is_some_and(|m| m.len() > MAX_MESSAGE_LEN)
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "message_too_long",
+            format!("Message must be at most {} bytes", MAX_MESSAGE_LEN),
+        ));
+    }
+    if info.interval == Some(0) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "interval_invalid",
+            "Interval must be at least 1 second",
+        ));
+    }
+    if info.place.as_ref().is_some_and(|p| p.len() > MAX_PLACE_LEN) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "place_too_long",
+            format!("Place must be at most {} bytes", MAX_PLACE_LEN),
+        ));
+    }
+    let extra_len = info
+        .extra
+        .as_ref()
+        .map_or(0, |e| serde_json::to_string(e).unwrap().len());
+    if extra_len > MAX_EXTRA_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "extra_too_large",
+            format!("Custom fields must be at most {} bytes", MAX_EXTRA_LEN),
+        ));
+    }
+
+    let mut clients = state.lock_clients();
+    let devices = clients.devices_mut(user);
+    if !devices.contains_key(&device) {
+        let archived = state
+            .archive
+            .lock()
+            .unwrap()
+            .get_mut(user)
+            .and_then(|archived| archived.remove(&device));
+        if let Some(mut archived) = archived {
+            info!("Device {} is back from the archive", device);
+            archived.archived = None;
+            devices.insert(device.clone(), archived);
+        }
+    }
+    if let Some(last) = devices.get(&device).map(|d| d.timestamp) {
+        if info.timestamp <= last {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "heartbeat_outdated",
+                "A heartbeat at least as recent was already accepted from this device",
+            )
+            .detail(format!("last timestamp {}", last)));
+        }
+    }
+    if let Some(signature) = signature {
+        let mut signatures = state.signatures.lock().unwrap();
+        signatures.retain(|_, &mut t| now.abs_diff(t) <= TIMEOUT);
+        if signatures.insert(signature, info.timestamp).is_some() {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "heartbeat_replayed",
+                "This signature was already used",
+            ));
+        }
+    }
+    let device_state = match devices.get(&device) {
+        None => DeviceState::New,
+        Some(d) if !d.online(now) => DeviceState::Resumed,
+        Some(_) => DeviceState::Online,
+    };
+    let entry = devices.entry(device.clone()).or_default();
+    entry.record_interval(now);
+    if !entry.online(now) {
+        entry.online_since = now;
+    }
+    entry.last_seen = now;
+    entry.timestamp = info.timestamp;
+    let interval = info.interval.map(|i| i.min(MAX_INTERVAL));
+    entry.interval = interval;
+    entry.message = info.message.clone();
+    entry.place = info.place.clone();
+    entry.key = key;
+    let booted_at = info.uptime.map(|uptime| now.saturating_sub(uptime));
+    let rebooted = matches!(
+        (entry.booted_at, booted_at),
+        (Some(before), Some(after)) if after > before + REBOOT_SLACK
+    );
+    if booted_at.is_some() {
+        entry.booted_at = booted_at;
+    }
+    entry.metrics = info.metrics.clone();
+    entry.extra = info.extra.clone();
+    #[cfg(feature = "geoip")]
+    if let Some(geoip) = &settings.geoip {
+        entry.location = geoip.location(addr.ip());
+        entry.network = geoip.network(addr.ip());
+    }
+    if let Some(latency) = info.latency_ms {
+        if entry.latency_ms.len() == LATENCY_SAMPLES {
+            entry.latency_ms.pop_front();
+        }
+        entry.latency_ms.push_back(latency);
     }
+    let flaky = entry.update_flaky(settings.flaky_threshold);
+    drop(clients);
+    state.metrics.count_heartbeat(user);
+
+    #[cfg(feature = "nats")]
+    if settings.nats.is_some() {
+        let _ = state.receipts.send(nats::Receipt {
+            user: user.to_string(),
+            device: device.clone(),
+            timestamp: now,
+            client_timestamp: info.timestamp,
+            message: info.message.clone(),
+            place: info.place.clone(),
+            interval: info.interval,
+            device_state,
+        });
+    }
+
+    if settings.relay.is_some() {
+        let relayed = HeartBeat {
+            device: Some(device.clone()),
+            ..info
+        };
+        tokio::spawn(relay::forward(state.clone(), relayed));
+    }
+
+    match flaky {
+        Some(true) => notify::notify(
+            state,
+            notify::Event::new("device_flaky", user, Some(&device), "is flaky"),
+        ),
+        Some(false) => notify::notify(
+            state,
+            notify::Event::new("device_stable", user, Some(&device), "is stable again"),
+        ),
+        None => {}
+    }
+    if rebooted {
+        info!("Device {} rebooted", device);
+        notify::notify(
+            state,
+            notify::Event::new("device_rebooted", user, Some(&device), "rebooted"),
+        );
+    }
+    let accepts_json = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    if !accepts_json {
+        return Ok("Heartbeat received".into_response());
+    }
+    Ok(Json(HeartbeatResponse {
+        status: "received".to_string(),
+        server_time: now,
+        // The interval the device is now held to
+        next_interval: interval.unwrap_or(HEARTBEAT_INTERVAL),
+        device,
+        device_state,
+    })
+    .into_response())
+}
 
-    let mut clients = state.clients.lock().unwrap();
-    clients.insert(addr.ip(), now);
-    Ok("Heartbeat received")
+#[derive(Deserialize)]
+struct StatusQuery {
+    /// Comma-separated users whose states to answer with at once
+    users: Option<String>,
+    #[serde(flatten)]
+    viewer: ViewerQuery,
 }
 
-async fn status(State(state): State<AppState>) -> &'static str {
+async fn status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<StatusQuery>,
+) -> Result<Response, ApiError> {
+    Ok(match &query.users {
+        // For group pages on other sites, like the widget
+        Some(users) => (
+            [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+            bulk_status(&state, users, &headers, &query.viewer)?,
+        )
+            .into_response(),
+        None => plain_status(&state, DEFAULT_USER, &headers, &query.viewer)?.into_response(),
+    })
+}
+
+/// The states of several users, like `{"alice": "ONLINE", "bob": "OFFLINE"}`, leaving out
+/// those that are unknown or that the viewer may not see
+fn bulk_status(
+    state: &AppState,
+    users: &str,
+    headers: &HeaderMap,
+    query: &ViewerQuery,
+) -> Result<Json<BTreeMap<String, Cow<'static, str>>>, ApiError> {
+    let names: Vec<_> = users
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .collect();
+    if names.len() > MAX_BULK_USERS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "too_many_users",
+            format!("At most {} users can be asked for at once", MAX_BULK_USERS),
+        ));
+    }
+    let mut states = BTreeMap::new();
+    for name in names {
+        let (privacy, now) = match request_access(state, name, headers, query) {
+            Ok(access) => access,
+            Err(StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED) => continue,
+            Err(status) => return Err(status.into()),
+        };
+        let report = decided_report(state, name, now, privacy);
+        states.insert(name.to_string(), report.status);
+    }
+    Ok(Json(states))
+}
+
+async fn status_json(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ViewerQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    status_report(&state, DEFAULT_USER, &headers, &query)
+}
+
+async fn user_status(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<ViewerQuery>,
+) -> Result<String, ApiError> {
+    if name == DEFAULT_USER {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    plain_status(&state, &name, &headers, &query)
+}
+
+async fn user_status_json(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<ViewerQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    if name == DEFAULT_USER {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    status_report(&state, &name, &headers, &query)
+}
+
+/// How much the requester may see of a user, and the current time
+fn request_access(
+    state: &AppState,
+    user: &str,
+    headers: &HeaderMap,
+    query: &ViewerQuery,
+) -> Result<(Privacy, u64), StatusCode> {
+    let credentials = ViewerCredentials::from_request(headers, query)?;
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let mut clients = state.clients.lock().unwrap();
-    for (_, last_seen) in clients.iter() {
-        if last_seen + OFFLINE_TIMEOUT >= now {
-            return "ONLINE";
+    let privacy = state.settings().access(user, &credentials, now)?;
+    Ok((privacy, now))
+}
+
+fn plain_status(
+    state: &AppState,
+    user: &str,
+    headers: &HeaderMap,
+    query: &ViewerQuery,
+) -> Result<String, ApiError> {
+    let (privacy, now) = request_access(state, user, headers, query)?;
+    let report = decided_report(state, user, now, privacy);
+    Ok(match &state.settings().status_template {
+        Some(t) => template::render(t, &report),
+        None => report.status.to_string(),
+    })
+}
+
+/// The report of a user, whose state only their primary device decides if they have one
+fn decided_report(state: &AppState, user: &str, now: u64, privacy: Privacy) -> StatusReport {
+    let mut report = state.report(user, now, privacy);
+    let settings = state.settings();
+    if let Some(primary) = settings
+        .users
+        .get(user)
+        .and_then(|u| u.primary_device.as_ref())
+        .filter(|_| state.status_override(user, now).is_none())
+    {
+        let online = state
+            .snapshot()
+            .get(user)
+            .and_then(|devices| devices.get(primary))
+            .is_some_and(|d| d.online(now));
+        let status = match online {
+            true => "ONLINE",
+            false => state.offline_status(user, now),
+        };
+        report.online = status == "ONLINE";
+        report.status = match report.online {
+            true => calendar::busy_status(state, user, now).map_or(status.into(), Cow::Owned),
+            false => status.into(),
+        };
+    }
+    report
+}
+
+/// The status as JSON, signed with the server key (if configured) so that
+/// consumers can detect tampering by proxies in between
+fn status_report(
+    state: &AppState,
+    user: &str,
+    headers: &HeaderMap,
+    query: &ViewerQuery,
+) -> Result<impl IntoResponse, ApiError> {
+    let (privacy, now) = request_access(state, user, headers, query)?;
+    let settings = state.settings();
+    let report = state.report(user, now, privacy);
+    let body = serde_json::to_vec(&report).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    // Read by the widget on other sites; credentials are only ever sent explicitly
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+    if let Some(key) = &settings.server_key {
+        let signature = detached_signature(key, HashAlgorithm::default(), &body)
+            .and_then(|s| Ok(s.to_bytes()?))
+            .map_err(|e| {
+                error!("Cannot sign status: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        headers.insert("x-signature", BASE64.encode(signature).parse().unwrap());
+    }
+    Ok((headers, body))
+}
+
+/// What clients need to know to talk to this server
+#[derive(Serialize)]
+struct Capabilities {
+    /// Accepted for signatures, clients sign with SHA256 unless told otherwise
+    hash_algorithms: Vec<String>,
+    /// Older clients are rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_client_version: Option<String>,
+}
+
+async fn capabilities(State(state): State<AppState>) -> Json<Capabilities> {
+    let settings = state.settings();
+    Json(Capabilities {
+        hash_algorithms: settings.hash_names(),
+        min_client_version: settings.min_client_version.clone(),
+    })
+}
+
+/// What is deployed, from `build.rs`
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    /// Short hash, `unknown` if not built from a git checkout
+    git_commit: &'static str,
+    /// Like `2024-09-01T12:00:00Z`
+    build_date: String,
+    /// Cargo features the binary was built with
+    features: Vec<&'static str>,
+}
+
+async fn version() -> Json<Version> {
+    let build_date = env!("BUILD_DATE").parse().unwrap();
+    let features = [
+        ("tray", cfg!(feature = "tray")),
+        ("scripting", cfg!(feature = "scripting")),
+        ("plugins", cfg!(feature = "plugins")),
+        ("geoip", cfg!(feature = "geoip")),
+        ("matrix", cfg!(feature = "matrix")),
+        ("nats", cfg!(feature = "nats")),
+    ];
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("BUILD_GIT_COMMIT"),
+        build_date: chrono::DateTime::from_timestamp(build_date, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string(),
+        features: features
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect(),
+    })
+}
+
+/// The public key matching the signatures on `/status.json`
+async fn server_pubkey(State(state): State<AppState>) -> Result<String, ApiError> {
+    let settings = state.settings();
+    let key = settings.server_key.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    armored_public_key(key).map_err(|e| {
+        error!("Cannot export server key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::Ipv4Addr;
+
+    pub(super) fn state() -> AppState {
+        let args = Args::parse_from(["online_status", "--server"]);
+        let settings = Settings::load(&args).unwrap();
+        AppState::new(args, settings).unwrap()
+    }
+
+    fn heartbeat(device: &str, timestamp: u64, signature: Option<&str>) -> HeartBeat {
+        HeartBeat {
+            timestamp,
+            signature: signature.map(|s| vec![s.to_string()]),
+            hash: None,
+            detached_signature: None,
+            device: Some(device.to_string()),
+            message: None,
+            place: None,
+            latency_ms: None,
+            interval: None,
+            client_version: None,
+            uptime: None,
+            metrics: None,
+            extra: None,
+        }
+    }
+
+    /// The code of the error a heartbeat is rejected with, if it is
+    fn rejected(state: &AppState, info: HeartBeat) -> Option<&'static str> {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 4000));
+        record(addr, state, &HeaderMap::new(), DEFAULT_USER, None, info)
+            .err()
+            .map(|e| e.code())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn outdated_heartbeats() {
+        let state = state();
+        let now = now();
+        assert_eq!(rejected(&state, heartbeat("laptop", now - 1, None)), None);
+        assert_eq!(
+            rejected(&state, heartbeat("laptop", now - 1, None)),
+            Some("heartbeat_outdated")
+        );
+        assert_eq!(
+            rejected(&state, heartbeat("laptop", now - 2, None)),
+            Some("heartbeat_outdated")
+        );
+        assert_eq!(rejected(&state, heartbeat("laptop", now, None)), None);
+        // Each device has its own
+        assert_eq!(rejected(&state, heartbeat("phone", now - 1, None)), None);
+    }
+
+    #[test]
+    fn replayed_heartbeats() {
+        let state = state();
+        let now = now();
+        assert_eq!(
+            rejected(&state, heartbeat("laptop", now - 1, Some("a1b2"))),
+            None
+        );
+        // The same signature, sent again as another device
+        assert_eq!(
+            rejected(&state, heartbeat("phone", now - 1, Some("a1b2"))),
+            Some("heartbeat_replayed")
+        );
+        assert_eq!(
+            rejected(&state, heartbeat("phone", now, Some("c3d4"))),
+            None
+        );
+    }
+
+    #[test]
+    fn snapshots_share_unchanged_users() {
+        let state = state();
+        let now = now();
+        let rejected = |user: &str, device: &str| {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 4000));
+            let info = heartbeat(device, now, None);
+            record(addr, &state, &HeaderMap::new(), user, None, info).err()
+        };
+        assert!(rejected("alice", "laptop").is_none());
+        assert!(rejected("bob", "laptop").is_none());
+        let before = state.snapshot();
+        assert!(rejected("alice", "phone").is_none());
+        let after = state.snapshot();
+        assert!(Arc::ptr_eq(&before["bob"], &after["bob"]));
+        assert!(!Arc::ptr_eq(&before["alice"], &after["alice"]));
+        assert_eq!(after["alice"].len(), 2);
+        assert_eq!(before["alice"].len(), 1);
+    }
+
+    #[test]
+    fn stale_heartbeats() {
+        let state = state();
+        let now = now();
+        assert_eq!(
+            rejected(&state, heartbeat("laptop", now - TIMEOUT - 1, None)),
+            Some("timestamp_stale")
+        );
+        assert_eq!(
+            rejected(&state, heartbeat("laptop", now + TIMEOUT + 1, None)),
+            Some("timestamp_stale")
+        );
+    }
+
+    #[test]
+    fn cross_site_requests() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
         };
+        assert!(!cross_site(&headers(&[])));
+        assert!(!cross_site(&headers(&[("sec-fetch-site", "same-origin")])));
+        assert!(!cross_site(&headers(&[("sec-fetch-site", "none")])));
+        assert!(cross_site(&headers(&[("sec-fetch-site", "cross-site")])));
+        assert!(cross_site(&headers(&[("sec-fetch-site", "same-site")])));
+        let host = ("host", "status.example.com");
+        assert!(!cross_site(&headers(&[
+            host,
+            ("origin", "https://status.example.com")
+        ])));
+        assert!(cross_site(&headers(&[
+            host,
+            ("origin", "https://evil.example")
+        ])));
+        assert!(cross_site(&headers(&[host, ("origin", "null")])));
     }
-    clients.retain(|_, last_seen| now - *last_seen <= ZOMBIE_TIMEOUT);
-    "OFFLINE"
 }
@@ -0,0 +1,72 @@
+//! One line per request: remote address, method, path, status and latency
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::Instant,
+};
+
+use super::AppState;
+
+#[derive(Debug)]
+pub(super) enum AccessLog {
+    Stdout,
+    File(Mutex<File>),
+}
+
+impl AccessLog {
+    /// Open the log, reopening the file on every reload so it can be rotated
+    pub(super) fn open(path: &Path) -> io::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(AccessLog::Stdout);
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::File(Mutex::new(file)))
+    }
+
+    fn write(&self, line: &str) {
+        match self {
+            AccessLog::Stdout => println!("{}", line),
+            AccessLog::File(file) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    error!("Cannot write access log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+pub(super) async fn log_request(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let settings = state.settings();
+    let Some(log) = &settings.access_log else {
+        return next.run(req).await;
+    };
+    // The query is left out, it may contain viewer tokens
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    log.write(&format!(
+        "access: {} {} {} {} {} {:.1}ms",
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        settings.anonymizer.logged(addr.ip()),
+        method,
+        path,
+        res.status().as_u16(),
+        start.elapsed().as_secs_f64() * 1000.0
+    ));
+    res
+}
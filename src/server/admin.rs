@@ -0,0 +1,297 @@
+//! Admin endpoints for managing the known devices and overriding the status
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    audit, authorize_admin,
+    page::{Page, TOTAL_COUNT},
+    ApiError, AppState, ClientMap, Device, DEFAULT_USER,
+};
+
+/// A status set by the admin instead of the computed one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct StatusOverride {
+    pub(super) status: String,
+    /// Until when, forever if unset
+    pub(super) until: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct DeviceRequest {
+    #[serde(default = "default_user")]
+    user: String,
+    device: String,
+    /// New name when renaming
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct OverrideRequest {
+    #[serde(default = "default_user")]
+    user: String,
+    /// Clears the override if unset
+    status: Option<String>,
+    until: Option<u64>,
+}
+
+fn default_user() -> String {
+    DEFAULT_USER.to_string()
+}
+
+fn device_not_found(request: &DeviceRequest) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "device_not_found",
+        format!("No device {} is known", request.device),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum ClientState {
+    Online,
+    Offline,
+    /// Not seen for so long that the device no longer counts
+    Archived,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ClientsQuery {
+    user: Option<String>,
+    device: Option<String>,
+    state: Option<ClientState>,
+    /// Only devices last seen at this time or later
+    since: Option<u64>,
+    /// Only devices last seen before this time
+    until: Option<u64>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+/// The known devices of every user, archived ones included, sorted by user and device name
+pub(super) async fn clients(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ClientsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut clients = ClientMap::clone(&state.snapshot());
+    for (user, devices) in state.archive.lock().unwrap().iter() {
+        let known = Arc::make_mut(clients.entry(user.clone()).or_default());
+        for (name, device) in devices {
+            known.entry(name.clone()).or_insert_with(|| device.clone());
+        }
+    }
+    let sorted: BTreeMap<_, BTreeMap<_, _>> = clients
+        .iter()
+        .filter(|(user, _)| query.user.as_ref().is_none_or(|u| u == *user))
+        .map(|(user, devices)| (user, devices.iter().collect()))
+        .collect();
+    let matching = sorted
+        .into_iter()
+        .flat_map(|(user, devices)| devices.into_iter().map(move |(name, d)| (user, name, d)))
+        .filter(|(_, name, _)| query.device.as_ref().is_none_or(|n| n == *name))
+        .filter(|(.., d)| match query.state {
+            Some(ClientState::Online) => d.online(now),
+            Some(ClientState::Offline) => !d.online(now) && d.archived.is_none(),
+            Some(ClientState::Archived) => d.archived.is_some(),
+            None => true,
+        })
+        .filter(|(.., d)| query.since.is_none_or(|since| d.last_seen >= since))
+        .filter(|(.., d)| query.until.is_none_or(|until| d.last_seen < until));
+    let (page, total) = query.page.apply(matching);
+    let mut listed: BTreeMap<String, BTreeMap<String, _>> = BTreeMap::new();
+    for (user, name, device) in page {
+        listed
+            .entry(user.clone())
+            .or_default()
+            .insert(name.clone(), device.clone());
+    }
+    Ok(([(TOTAL_COUNT, total.to_string())], Json(listed)))
+}
+
+pub(super) async fn rename(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<DeviceRequest>,
+) -> Result<&'static str, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let Some(name) = request.name.clone() else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "name_missing",
+            "The new name is missing",
+        ));
+    };
+    let mut clients = state.lock_clients();
+    let devices = clients
+        .get_devices_mut(&request.user)
+        .ok_or_else(|| device_not_found(&request))?;
+    if devices.contains_key(&name) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "device_exists",
+            format!("A device {} already exists", name),
+        ));
+    }
+    let device = devices
+        .remove(&request.device)
+        .ok_or_else(|| device_not_found(&request))?;
+    devices.insert(name.clone(), device);
+    drop(clients);
+    state.bury(&request.user, &request.device);
+    info!("Renamed device {} to {}", request.device, name);
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "rename",
+        Some(&request.user),
+        Some(&request.device),
+        None,
+        Some(json!({ "device": name })),
+    );
+    Ok("Device renamed")
+}
+
+pub(super) async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<DeviceRequest>,
+) -> Result<&'static str, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let removed = state
+        .lock_clients()
+        .get_devices_mut(&request.user)
+        .and_then(|devices| devices.remove(&request.device));
+    let archived = state
+        .archive
+        .lock()
+        .unwrap()
+        .get_mut(&request.user)
+        .and_then(|devices| devices.remove(&request.device));
+    let device = removed
+        .or(archived)
+        .ok_or_else(|| device_not_found(&request))?;
+    state.bury(&request.user, &request.device);
+    info!("Deleted device {}", request.device);
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "delete",
+        Some(&request.user),
+        Some(&request.device),
+        serde_json::to_value(device).ok(),
+        None,
+    );
+    Ok("Device deleted")
+}
+
+/// Set or clear the status shown instead of the computed one
+pub(super) async fn set_override(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<OverrideRequest>,
+) -> Result<&'static str, ApiError> {
+    let settings = state.settings();
+    authorize_admin(&headers, &state)?;
+    if !settings.users.contains_key(&request.user) {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let mut overrides = state.overrides.lock().unwrap();
+    let before = overrides.get(&request.user).cloned();
+    let (after, answer) = match request.status {
+        Some(status) => {
+            info!("Overriding status with {}", status);
+            let after = StatusOverride {
+                status,
+                until: request.until,
+            };
+            overrides.insert(request.user.clone(), after.clone());
+            (Some(after), "Status overridden")
+        }
+        None => {
+            overrides.remove(&request.user);
+            info!("Status override cleared");
+            (None, "Status override cleared")
+        }
+    };
+    drop(overrides);
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "override",
+        Some(&request.user),
+        None,
+        before.and_then(|o| serde_json::to_value(o).ok()),
+        after.and_then(|o| serde_json::to_value(o).ok()),
+    );
+    Ok(answer)
+}
+
+#[derive(Serialize)]
+pub(super) struct ImportResult {
+    imported: usize,
+}
+
+/// Add devices exported from another server, like by `export-bundle`, keeping those known
+/// already
+pub(super) async fn import(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(imported): Json<BTreeMap<String, BTreeMap<String, Device>>>,
+) -> Result<Json<ImportResult>, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let settings = state.settings();
+    let mut count = 0;
+    {
+        let mut clients = state.lock_clients();
+        let mut archive = state.archive.lock().unwrap();
+        for (user, devices) in imported {
+            if !settings.users.contains_key(&user) {
+                warn!("Not importing the devices of unknown user {:?}", user);
+                continue;
+            }
+            for (name, device) in devices {
+                let known = clients.get(&user).is_some_and(|d| d.contains_key(&name))
+                    || archive.get(&user).is_some_and(|d| d.contains_key(&name));
+                if known {
+                    continue;
+                }
+                let target = match device.archived {
+                    Some(_) => archive.entry(user.clone()).or_default(),
+                    None => clients.devices_mut(&user),
+                };
+                target.insert(name, device);
+                count += 1;
+            }
+        }
+    }
+    info!("Imported {} devices", count);
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "import",
+        None,
+        None,
+        None,
+        Some(json!({ "devices": count })),
+    );
+    Ok(Json(ImportResult { imported: count }))
+}
@@ -0,0 +1,141 @@
+//! Alert rules on the system metrics devices report: a `metric_alert` event once a metric stays
+//! above its threshold for the rule's minutes, and a `metric_resolved` one once it is back below
+//!
+//! Offline devices keep the state they were last in, as their metrics are not current.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{notify, presence, AppState, User};
+use crate::{
+    config::{AlertConfig, Metric},
+    SystemMetrics,
+};
+
+pub(super) fn load(
+    rules: Vec<AlertConfig>,
+    users: &HashMap<String, User>,
+) -> Result<Vec<AlertConfig>, Box<dyn Error>> {
+    if let Some(user) = rules
+        .iter()
+        .find_map(|r| r.user.as_ref().filter(|u| !users.contains_key(*u)))
+    {
+        return Err(format!("Alert for unknown user {}", user).into());
+    }
+    if rules.iter().any(|r| !r.above.is_finite()) {
+        return Err("Alert thresholds must be numbers".into());
+    }
+    Ok(rules)
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Load => "load",
+            Metric::MemoryPercent => "memory_percent",
+            Metric::DiskPercent => "disk_percent",
+        }
+    }
+
+    fn value(self, metrics: &SystemMetrics) -> Option<f64> {
+        match self {
+            Metric::Load => metrics.load,
+            Metric::MemoryPercent => metrics.memory_percent,
+            Metric::DiskPercent => metrics.disk_percent,
+        }
+    }
+}
+
+/// A rule by what it checks, so that state survives reloads that keep it
+#[derive(PartialEq, Eq, Hash)]
+struct Rule {
+    metric: Metric,
+    above: u64,
+    minutes: u64,
+}
+
+/// A metric of a device over a rule's threshold
+struct Breach {
+    since: u64,
+    fired: bool,
+}
+
+pub(super) async fn watch(state: AppState) {
+    let mut breaches: HashMap<(Rule, String, String), Breach> = HashMap::new();
+    loop {
+        let settings = state.settings();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let clients = state.snapshot();
+        for rule in &settings.alert {
+            let devices = clients
+                .iter()
+                .filter(|(user, _)| rule.user.as_ref().is_none_or(|u| u == *user))
+                .flat_map(|(user, devices)| devices.iter().map(move |(name, d)| (user, name, d)))
+                .filter(|(_, name, d)| {
+                    rule.device.as_ref().is_none_or(|device| device == *name) && d.online(now)
+                });
+            for (user, device, d) in devices {
+                let key = (
+                    Rule {
+                        metric: rule.metric,
+                        above: rule.above.to_bits(),
+                        minutes: rule.minutes,
+                    },
+                    user.clone(),
+                    device.clone(),
+                );
+                let metric = rule.metric.name();
+                let value = d.metrics.as_ref().and_then(|m| rule.metric.value(m));
+                match value.filter(|&value| value > rule.above) {
+                    Some(value) => {
+                        let breach = breaches.entry(key).or_insert(Breach {
+                            since: now,
+                            fired: false,
+                        });
+                        if !breach.fired && now - breach.since >= rule.minutes * 60 {
+                            let what = match rule.minutes {
+                                0 => format!("has {} {}, above {}", metric, value, rule.above),
+                                minutes => format!(
+                                    "has {} {}, above {} for {} minutes",
+                                    metric, value, rule.above, minutes
+                                ),
+                            };
+                            notify::notify(
+                                &state,
+                                notify::Event::new("metric_alert", user, Some(device), &what),
+                            );
+                            breach.fired = true;
+                        }
+                    }
+                    None => {
+                        if breaches.remove(&key).is_some_and(|b| b.fired) {
+                            let what = match value {
+                                Some(value) => format!("has {} back at {}", metric, value),
+                                None => format!("no longer reports {}", metric),
+                            };
+                            notify::notify(
+                                &state,
+                                notify::Event::new("metric_resolved", user, Some(device), &what),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        // Forgetting devices that are gone, like when archived
+        breaches.retain(|(_, user, device), _| {
+            clients
+                .get(user)
+                .is_some_and(|devices| devices.contains_key(device))
+        });
+        drop(settings);
+        time::sleep(time::Duration::from_secs(presence::WATCH_INTERVAL)).await;
+    }
+}
@@ -0,0 +1,53 @@
+//! Keeping client addresses out of storage and logs
+//!
+//! Devices that do not send a name are known by their address, which is then
+//! replaced with a salted hash, so they can still be told apart.
+
+use sha2::{Digest, Sha256};
+use std::{net::IpAddr, sync::OnceLock};
+
+use crate::config::IpAddresses;
+
+#[derive(Debug)]
+pub(super) struct Anonymizer {
+    mode: IpAddresses,
+    salt: String,
+}
+
+impl Anonymizer {
+    pub(super) fn new(mode: IpAddresses, salt: Option<String>) -> Self {
+        // Shared by reloads, so the hashes stay the same without a configured salt
+        static RANDOM_SALT: OnceLock<String> = OnceLock::new();
+        let salt = salt.unwrap_or_else(|| {
+            RANDOM_SALT
+                .get_or_init(|| hex::encode(rand::random::<[u8; 16]>()))
+                .clone()
+        });
+        Anonymizer { mode, salt }
+    }
+
+    fn hash(&self, ip: IpAddr) -> String {
+        let digest = Sha256::new()
+            .chain_update(&self.salt)
+            .chain_update(ip.to_canonical().to_string())
+            .finalize();
+        format!("ip-{}", &hex::encode(digest)[..16])
+    }
+
+    /// How an address is stored, e.g. as the name of a device
+    pub(super) fn stored(&self, ip: IpAddr) -> String {
+        match self.mode {
+            IpAddresses::Keep => ip.to_string(),
+            IpAddresses::Hash | IpAddresses::Redact => self.hash(ip),
+        }
+    }
+
+    /// How an address appears in logs
+    pub(super) fn logged(&self, ip: IpAddr) -> String {
+        match self.mode {
+            IpAddresses::Keep => ip.to_string(),
+            IpAddresses::Hash => self.hash(ip),
+            IpAddresses::Redact => "-".to_string(),
+        }
+    }
+}
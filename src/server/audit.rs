@@ -0,0 +1,218 @@
+//! Who changed what through the admin API or by reloading the configuration, with the state
+//! before and after, for accountability on shared servers
+//!
+//! Entries are kept in memory and, with `audit_log`, appended to that file as JSON lines,
+//! which are read back on startup.
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use pgp::types::KeyTrait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    authorize_admin, oidc,
+    page::{Page, TOTAL_COUNT},
+    ApiError, AppState, Settings,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct AuditEntry {
+    timestamp: u64,
+    /// `admin_token`, the admin logged in with the identity provider, or `SIGHUP`
+    actor: String,
+    /// What was done, like `override` or `delete`
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<Value>,
+}
+
+/// The entries of an earlier run
+pub(super) fn load(path: &Path) -> Vec<AuditEntry> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping audit log entry in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Who made an admin request that was let in
+pub(super) fn actor(state: &AppState, headers: &HeaderMap) -> String {
+    oidc::admin(state, headers).unwrap_or_else(|| "admin_token".to_string())
+}
+
+/// Remember that `actor` did `action` to a user's `device`
+pub(super) fn record(
+    state: &AppState,
+    actor: String,
+    action: &str,
+    user: Option<&str>,
+    device: Option<&str>,
+    before: Option<Value>,
+    after: Option<Value>,
+) {
+    let entry = AuditEntry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        actor,
+        action: action.to_string(),
+        user: user.map(str::to_string),
+        device: device.map(str::to_string),
+        before,
+        after,
+    };
+    // Held while writing, so that pruning cannot rewrite the file in between
+    let mut audit = state.audit.lock().unwrap();
+    if let Some(path) = &state.settings().audit_log {
+        let line = serde_json::to_string(&entry).unwrap();
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = written {
+            error!("Cannot write audit log {}: {}", path.display(), e);
+        }
+    }
+    audit.push(entry);
+}
+
+/// Forget entries older than `cutoff`, also in the `audit_log` file
+pub(super) fn prune(state: &AppState, cutoff: u64) {
+    let mut audit = state.audit.lock().unwrap();
+    let count = audit.len();
+    audit.retain(|e| e.timestamp >= cutoff);
+    if audit.len() == count {
+        return;
+    }
+    if let Some(path) = &state.settings().audit_log {
+        let lines: String = audit
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap() + "\n")
+            .collect();
+        if let Err(e) = fs::write(path, lines) {
+            error!("Cannot write audit log {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The keys and number of tokens of every user, to tell key changes on reload
+fn keys(settings: &Settings) -> BTreeMap<&str, Value> {
+    settings
+        .users
+        .iter()
+        .map(|(name, user)| {
+            let fingerprints: Vec<_> = user
+                .public_keys
+                .iter()
+                .map(|key| hex::encode_upper(key.fingerprint()))
+                .collect();
+            let keys = serde_json::json!({
+                "pubkeys": fingerprints,
+                "tokens": user.tokens.len(),
+                "device_tokens": user.device_tokens.len(),
+            });
+            (name.as_str(), keys)
+        })
+        .collect()
+}
+
+/// Record a reload, and the users whose keys or tokens it changed, added or removed
+pub(super) fn reloaded(state: &AppState, actor: &str, old: &Settings, new: &Settings) {
+    let (before, after) = (keys(old), keys(new));
+    let users = |keys: &BTreeMap<&str, Value>| Value::from_iter(keys.keys().copied());
+    record(
+        state,
+        actor.to_string(),
+        "reload",
+        None,
+        None,
+        Some(serde_json::json!({ "users": users(&before) })),
+        Some(serde_json::json!({ "users": users(&after) })),
+    );
+    for (user, keys) in &after {
+        if before.get(user) != Some(keys) {
+            record(
+                state,
+                actor.to_string(),
+                "keys",
+                Some(user),
+                None,
+                before.get(user).cloned(),
+                Some(keys.clone()),
+            );
+        }
+    }
+    for (user, keys) in &before {
+        if !after.contains_key(user) {
+            record(
+                state,
+                actor.to_string(),
+                "keys",
+                Some(user),
+                None,
+                Some(keys.clone()),
+                None,
+            );
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct AuditQuery {
+    action: Option<String>,
+    user: Option<String>,
+    /// Only entries at or after this time
+    since: Option<u64>,
+    /// Only entries before this time
+    until: Option<u64>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+/// The audit log, most recent first
+pub(super) async fn entries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let audit = state.audit.lock().unwrap();
+    let matching = audit
+        .iter()
+        .rev()
+        .filter(|e| query.action.as_ref().is_none_or(|a| *a == e.action))
+        .filter(|e| query.user.is_none() || query.user == e.user)
+        .filter(|e| query.since.is_none_or(|since| e.timestamp >= since))
+        .filter(|e| query.until.is_none_or(|until| e.timestamp < until))
+        .cloned();
+    let (entries, total) = query.page.apply(matching);
+    Ok(([(TOTAL_COUNT, total.to_string())], Json(entries)))
+}
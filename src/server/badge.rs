@@ -0,0 +1,372 @@
+//! Status badges like those of CI services, as SVG or, for sites that do not allow SVG,
+//! as PNG drawn with a built-in pixel font
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+
+use super::{decided_report, request_access, ApiError, AppState, ViewerQuery, DEFAULT_USER};
+
+const LABEL: &str = "status";
+const LABEL_COLOR: [u8; 3] = [0x55, 0x55, 0x55];
+const ONLINE_COLOR: [u8; 3] = [0x3f, 0xb9, 0x50];
+const OFFLINE_COLOR: [u8; 3] = [0x9f, 0x9f, 0x9f];
+const HEIGHT: u32 = 20;
+const RADIUS: u32 = 3;
+/// Around the text of each part
+const PADDING: u32 = 6;
+/// Advance of a glyph of `FONT`, including the gap after it
+const CHAR_WIDTH: u32 = 6;
+/// Top of the glyphs
+const TEXT_TOP: u32 = 7;
+const MAX_SCALE: u32 = 8;
+const MAX_LABEL_LEN: usize = 32;
+/// Stops of the shine of `plastic` badges, from top to bottom: offset, color, opacity
+const PLASTIC: [(f64, [u8; 3], f64); 4] = [
+    (0.0, [0xff, 0xff, 0xff], 0.7),
+    (0.1, [0xaa, 0xaa, 0xaa], 0.1),
+    (0.9, [0x00, 0x00, 0x00], 0.3),
+    (1.0, [0x00, 0x00, 0x00], 0.5),
+];
+
+#[derive(Deserialize)]
+pub(super) struct BadgeQuery {
+    /// Pixels per badge pixel, only for PNG
+    scale: Option<u32>,
+    label: Option<String>,
+    /// Like `3fb950` or `#3fb950`
+    online_color: Option<String>,
+    offline_color: Option<String>,
+    /// `flat` (default) or `plastic`
+    style: Option<String>,
+    #[serde(flatten)]
+    viewer: ViewerQuery,
+}
+
+struct Badge {
+    label: String,
+    value: String,
+    color: [u8; 3],
+    plastic: bool,
+}
+
+impl Badge {
+    fn label_width(&self) -> u32 {
+        text_width(&self.label) + 2 * PADDING
+    }
+
+    fn width(&self) -> u32 {
+        self.label_width() + text_width(&self.value) + 2 * PADDING
+    }
+
+    fn svg(&self) -> String {
+        let (width, label_width) = (self.width(), self.label_width());
+        let value_width = width - label_width;
+        let (label, value) = (escape(&self.label), escape(&self.value));
+        let text_y = TEXT_TOP + 7;
+        let (gradient, shine) = match self.plastic {
+            true => {
+                let stops: String = PLASTIC
+                    .iter()
+                    .map(|(offset, color, opacity)| {
+                        format!(
+                            r#"<stop offset="{}" stop-color="{}" stop-opacity="{}"/>"#,
+                            offset,
+                            hex(*color),
+                            opacity
+                        )
+                    })
+                    .collect();
+                (
+                    format!(
+                        r#"<linearGradient id="s" x2="0" y2="100%">{}</linearGradient>"#,
+                        stops
+                    ),
+                    format!(r#"<rect width="{width}" height="{HEIGHT}" fill="url(#s)"/>"#),
+                )
+            }
+            false => Default::default(),
+        };
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{HEIGHT}" role="img" aria-label="{label}: {value}"><title>{label}: {value}</title>{gradient}<clipPath id="r"><rect width="{width}" height="{HEIGHT}" rx="{RADIUS}"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="{HEIGHT}" fill="{}"/><rect x="{label_width}" width="{value_width}" height="{HEIGHT}" fill="{}"/>{shine}</g><g fill="#fff" font-family="Verdana,DejaVu Sans,sans-serif" font-size="11" text-anchor="middle"><text x="{}" y="{text_y}" textLength="{}">{label}</text><text x="{}" y="{text_y}" textLength="{}">{value}</text></g></svg>"##,
+            hex(LABEL_COLOR),
+            hex(self.color),
+            label_width / 2,
+            text_width(&self.label),
+            label_width + value_width / 2,
+            text_width(&self.value),
+        )
+    }
+
+    fn png(&self, scale: u32) -> Vec<u8> {
+        let (width, label_width) = (self.width(), self.label_width());
+        let mut pixels = vec![0; (width * HEIGHT * scale * scale * 4) as usize];
+        let row = width * scale;
+        for y in 0..HEIGHT * scale {
+            for x in 0..row {
+                if !inside_rounded(x, y, row, HEIGHT * scale, RADIUS * scale) {
+                    continue;
+                }
+                let mut color = if x < label_width * scale {
+                    LABEL_COLOR
+                } else {
+                    self.color
+                };
+                if self.plastic {
+                    color = shine(color, (y as f64 + 0.5) / (HEIGHT * scale) as f64);
+                }
+                let i = ((y * row + x) * 4) as usize;
+                pixels[i..i + 4].copy_from_slice(&[color[0], color[1], color[2], 0xff]);
+            }
+        }
+        let texts = [(PADDING, &self.label), (label_width + PADDING, &self.value)];
+        for (left, text) in texts {
+            for (n, c) in text.chars().enumerate() {
+                let glyph = glyph(c);
+                for (column, bits) in glyph.iter().enumerate() {
+                    for line in 0..7 {
+                        if bits & (1 << line) == 0 {
+                            continue;
+                        }
+                        let x0 = (left + n as u32 * CHAR_WIDTH + column as u32) * scale;
+                        let y0 = (TEXT_TOP + line) * scale;
+                        for y in y0..y0 + scale {
+                            for x in x0..x0 + scale {
+                                let i = ((y * row + x) * 4) as usize;
+                                pixels[i..i + 3].copy_from_slice(&[0xff; 3]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, row, HEIGHT * scale);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        // Cannot fail when writing to memory with matching dimensions
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+}
+
+fn badge(
+    state: &AppState,
+    name: &str,
+    headers: &HeaderMap,
+    query: &BadgeQuery,
+) -> Result<Badge, ApiError> {
+    let label = query.label.as_deref().unwrap_or(LABEL);
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "label_too_long",
+            format!("Label must be at most {} characters", MAX_LABEL_LEN),
+        ));
+    }
+    let online_color = query
+        .online_color
+        .as_deref()
+        .map_or(Ok(ONLINE_COLOR), parse_color)?;
+    let offline_color = query
+        .offline_color
+        .as_deref()
+        .map_or(Ok(OFFLINE_COLOR), parse_color)?;
+    let plastic = match query.style.as_deref() {
+        None | Some("flat") => false,
+        Some("plastic") => true,
+        Some(style) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "style_invalid",
+                "Style must be flat or plastic",
+            )
+            .detail(format!("got {:?}", style)))
+        }
+    };
+    let (privacy, now) = request_access(state, name, headers, &query.viewer)?;
+    let report = decided_report(state, name, now, privacy);
+    Ok(Badge {
+        label: label.to_string(),
+        color: if report.status == "ONLINE" {
+            online_color
+        } else {
+            offline_color
+        },
+        value: report.status.into_owned(),
+        plastic,
+    })
+}
+
+/// A color given as hex digits, with or without `#`
+fn parse_color(color: &str) -> Result<[u8; 3], ApiError> {
+    let digits = color.strip_prefix('#').unwrap_or(color);
+    let channel = |i: usize| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok();
+    match (digits.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "color_invalid",
+            "Colors must be given as six hex digits, like 3fb950",
+        )
+        .detail(format!("got {:?}", color))),
+    }
+}
+
+pub(super) async fn svg(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BadgeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user_svg(
+        State(state),
+        Path(DEFAULT_USER.to_string()),
+        headers,
+        Query(query),
+    )
+    .await
+}
+
+pub(super) async fn user_svg(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<BadgeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let badge = badge(&state, &name, &headers, &query)?;
+    Ok((
+        // Image proxies would otherwise keep showing an old status
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        badge.svg(),
+    ))
+}
+
+pub(super) async fn png(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BadgeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user_png(
+        State(state),
+        Path(DEFAULT_USER.to_string()),
+        headers,
+        Query(query),
+    )
+    .await
+}
+
+pub(super) async fn user_png(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<BadgeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let badge = badge(&state, &name, &headers, &query)?;
+    let scale = query.scale.unwrap_or(1).clamp(1, MAX_SCALE);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        badge.png(scale),
+    ))
+}
+
+fn text_width(text: &str) -> u32 {
+    // Without the gap after the last glyph
+    (text.chars().count() as u32 * CHAR_WIDTH).saturating_sub(1)
+}
+
+/// Whether the center of pixel (x, y) is inside a rectangle with rounded corners
+fn inside_rounded(x: u32, y: u32, width: u32, height: u32, radius: u32) -> bool {
+    let r = radius as f64;
+    let (cx, cy) = (x as f64 + 0.5, y as f64 + 0.5);
+    let dx = (r - cx).max(cx - (width as f64 - r)).max(0.0);
+    let dy = (r - cy).max(cy - (height as f64 - r)).max(0.0);
+    dx * dx + dy * dy <= r * r
+}
+
+/// `color` under the shine of `plastic` badges, `t` of the way down
+fn shine(color: [u8; 3], t: f64) -> [u8; 3] {
+    let stop = PLASTIC
+        .iter()
+        .rposition(|(offset, ..)| *offset <= t)
+        .unwrap_or(0);
+    let (start, from, from_opacity) = PLASTIC[stop];
+    let (end, to, to_opacity) = PLASTIC[(stop + 1).min(PLASTIC.len() - 1)];
+    let f = if end > start {
+        (t - start) / (end - start)
+    } else {
+        0.0
+    };
+    let opacity = from_opacity + (to_opacity - from_opacity) * f;
+    std::array::from_fn(|i| {
+        let over = from[i] as f64 + (to[i] as f64 - from[i] as f64) * f;
+        (color[i] as f64 * (1.0 - opacity) + over * opacity).round() as u8
+    })
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+pub(super) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Columns of the glyph, lowest bit at the top; `?` for characters outside of ASCII
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        ' '..='~' => FONT[c as usize - ' ' as usize],
+        _ => FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// The classic 5x7 font of character LCDs, from space to tilde
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5f, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], [0x24, 0x2a, 0x7f, 0x2a, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50], [0x00, 0x05, 0x03, 0x00, 0x00], [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00], [0x14, 0x08, 0x3e, 0x08, 0x14], [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3e, 0x51, 0x49, 0x45, 0x3e], [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46], [0x21, 0x41, 0x45, 0x4b, 0x31], [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3c, 0x4a, 0x49, 0x49, 0x30], [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x06, 0x49, 0x49, 0x29, 0x1e], [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00], [0x08, 0x14, 0x22, 0x41, 0x00], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x51, 0x09, 0x06], [0x32, 0x49, 0x79, 0x41, 0x3e],
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], [0x7f, 0x49, 0x49, 0x49, 0x36], [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], [0x7f, 0x49, 0x49, 0x49, 0x41], [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], [0x7f, 0x08, 0x08, 0x08, 0x7f], [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01], [0x7f, 0x08, 0x14, 0x22, 0x41], [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], [0x7f, 0x04, 0x08, 0x10, 0x7f], [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06], [0x3e, 0x41, 0x51, 0x21, 0x5e], [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31], [0x01, 0x01, 0x7f, 0x01, 0x01], [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], [0x3f, 0x40, 0x38, 0x40, 0x3f], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07], [0x61, 0x51, 0x49, 0x45, 0x43], [0x00, 0x7f, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x7f, 0x00], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x01, 0x02, 0x04, 0x00], [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7f, 0x48, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x20], [0x38, 0x44, 0x44, 0x48, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x08, 0x7e, 0x09, 0x01, 0x02], [0x0c, 0x52, 0x52, 0x52, 0x3e],
+    [0x7f, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7d, 0x40, 0x00], [0x20, 0x40, 0x44, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7f, 0x40, 0x00], [0x7c, 0x04, 0x18, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0x7c, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7c], [0x7c, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3f, 0x44, 0x40, 0x20], [0x3c, 0x40, 0x40, 0x20, 0x7c], [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], [0x44, 0x28, 0x10, 0x28, 0x44], [0x0c, 0x50, 0x50, 0x50, 0x3c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x7f, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x10, 0x08, 0x08, 0x10, 0x08],
+];
@@ -0,0 +1,59 @@
+//! `/roster.html`, a "who's online" board of everyone on `/roster`, kept current with
+//! server-sent events from `/roster/events`
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html,
+    },
+};
+use futures_util::{stream, Stream};
+use std::{convert::Infallible, fmt::Write};
+use tokio::time;
+
+use super::{badge::escape, federation, AppState};
+
+const PAGE: &str = include_str!("board.html");
+/// Seconds between two looks for changes
+const BOARD_INTERVAL: u64 = 5;
+
+pub(super) async fn page(State(state): State<AppState>) -> Html<String> {
+    let mut rows = String::new();
+    for entry in federation::entries(&state) {
+        let _ = writeln!(
+            rows,
+            r#"<tr><td>{}</td><td class="server">{}</td><td class="status{}">{}</td></tr>"#,
+            escape(&entry.name),
+            escape(entry.server.as_deref().unwrap_or_default()),
+            if entry.status == "ONLINE" {
+                " online"
+            } else {
+                ""
+            },
+            escape(&entry.status),
+        );
+    }
+    Html(PAGE.replace("<!-- rows -->", &rows))
+}
+
+/// The whole roster whenever someone's status changes
+pub(super) async fn events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let changes = stream::unfold((state, None), |(state, last)| async move {
+        loop {
+            let entries = federation::entries(&state);
+            let statuses: Vec<_> = entries
+                .iter()
+                .map(|e| (e.name.clone(), e.server.clone(), e.status.clone()))
+                .collect();
+            if last.as_ref() != Some(&statuses) {
+                let event = Event::default().json_data(&entries).unwrap();
+                return Some((Ok(event), (state, Some(statuses))));
+            }
+            time::sleep(time::Duration::from_secs(BOARD_INTERVAL)).await;
+        }
+    });
+    Sse::new(changes).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,251 @@
+//! `online_status export-bundle` and `import-bundle`: moving a deployment to a new host as one
+//! JSON file with the configuration, the public keys, the known devices and the history
+//!
+//! Devices are only kept in the memory of the running server, so they are exported from and
+//! imported into one given with `-c`. Private keys and other files the configuration points
+//! to are not bundled, only listed, and have to be copied by hand.
+
+use pgp::types::KeyTrait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{audit::AuditEntry, presence, Device, DEFAULT_USER};
+use crate::{
+    config::{load_server_config, Args, ServerConfig},
+    exit,
+    keys::read_public_key,
+    ErrorResponse,
+};
+
+/// Format of the bundle, newer ones are refused
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PublicKey {
+    /// User or viewer the key is configured for, empty for the default user
+    owner: String,
+    path: PathBuf,
+    fingerprint: String,
+    user_ids: Vec<String>,
+    /// The key file as it is
+    armored: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    created: u64,
+    /// The configuration file as it is
+    config: String,
+    public_keys: Vec<PublicKey>,
+    /// Files the configuration points to that are not bundled, like private keys
+    not_included: Vec<PathBuf>,
+    /// Known devices by user, archived ones included, if exported from a running server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clients: Option<BTreeMap<String, BTreeMap<String, Device>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<HashMap<String, presence::Presence>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    audit: Vec<AuditEntry>,
+}
+
+fn public_keys(config: &ServerConfig) -> Result<Vec<PublicKey>, Box<dyn Error>> {
+    let owners = [(DEFAULT_USER, &config.pubkeys)]
+        .into_iter()
+        .chain(config.users.iter().map(|(n, u)| (n.as_str(), &u.pubkeys)))
+        .chain(config.viewers.iter().map(|(n, v)| (n.as_str(), &v.pubkeys)));
+    let mut keys = Vec::new();
+    for (owner, paths) in owners {
+        for path in paths {
+            let key = read_public_key(path)?;
+            keys.push(PublicKey {
+                owner: owner.to_string(),
+                path: path.clone(),
+                fingerprint: hex::encode_upper(key.fingerprint()),
+                user_ids: key
+                    .details
+                    .users
+                    .iter()
+                    .map(|u| u.id.id().to_string())
+                    .collect(),
+                armored: fs::read_to_string(path)?,
+            });
+        }
+    }
+    Ok(keys)
+}
+
+fn not_included(config: &ServerConfig) -> Vec<PathBuf> {
+    [
+        config.server_key.as_ref(),
+        config.relay.as_ref().and_then(|r| r.privkey.as_ref()),
+        config.status_script.as_ref(),
+        config.plugins.as_ref(),
+        config.geoip.as_ref(),
+        config.geoip_asn.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect()
+}
+
+async fn admin_error(res: reqwest::Response) -> Box<dyn Error> {
+    let status = res.status();
+    let message = match res.json::<ErrorResponse>().await {
+        Ok(e) => format!("{} ({}: {})", status, e.code, e.message),
+        Err(_) => status.to_string(),
+    };
+    exit::status(status, message)
+}
+
+pub(crate) async fn export_main(args: Args, output: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let Some(path) = &args.config else {
+        return Err("Give the server's --config to export".into());
+    };
+    let config_text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let config = load_server_config(&args).map_err(exit::config)?;
+    let clients = match &args.client {
+        Some(_) => {
+            let request = reqwest::Client::new().get(args.server_url("/admin/clients"));
+            let request = match &args.token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            };
+            let res = request.send().await?;
+            if !res.status().is_success() {
+                return Err(admin_error(res).await);
+            }
+            Some(res.json().await?)
+        }
+        None => {
+            warn!("Not exporting the devices, give -c with the running server to");
+            None
+        }
+    };
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        config: config_text,
+        public_keys: public_keys(&config).map_err(exit::config)?,
+        not_included: not_included(&config),
+        clients,
+        history: config.history.as_deref().map(presence::load).transpose()?,
+        audit: config
+            .audit_log
+            .as_deref()
+            .map(super::audit::load)
+            .unwrap_or_default(),
+    };
+    let data = serde_json::to_string_pretty(&bundle)?;
+    match output {
+        Some(output) => {
+            fs::write(&output, data).map_err(|e| format!("{}: {}", output.display(), e))?;
+            info!("Exported to {}", output.display());
+        }
+        None => println!("{}", data),
+    }
+    for path in &bundle.not_included {
+        warn!("Not exported, copy it by hand: {}", path.display());
+    }
+    Ok(())
+}
+
+/// Whether `content` can be written to `path`, and still has to be
+fn needs_writing(path: &Path, content: &[u8], force: bool) -> Result<bool, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(existing) if existing == content => Ok(false),
+        Ok(_) if !force => {
+            Err(format!("{} exists, give --force to overwrite it", path.display()).into())
+        }
+        _ => Ok(true),
+    }
+}
+
+pub(crate) async fn import_main(
+    args: Args,
+    bundle: PathBuf,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let data = fs::read_to_string(&bundle).map_err(|e| format!("{}: {}", bundle.display(), e))?;
+    let bundle: Bundle =
+        serde_json::from_str(&data).map_err(|e| format!("{}: {}", bundle.display(), e))?;
+    if bundle.version > BUNDLE_VERSION {
+        return Err(format!("Bundle version {} is too new", bundle.version).into());
+    }
+    if args.client.is_some() {
+        return import_clients(&args, bundle).await;
+    }
+    let Some(config_path) = args.config.clone() else {
+        return Err("Give the --config to write the configuration to".into());
+    };
+    let config: ServerConfig = toml::from_str(&bundle.config)?;
+
+    let mut files = vec![(config_path, bundle.config.into_bytes())];
+    files.extend(
+        bundle
+            .public_keys
+            .into_iter()
+            .map(|key| (key.path, key.armored.into_bytes())),
+    );
+    if let (Some(path), Some(history)) = (&config.history, &bundle.history) {
+        files.push((path.clone(), serde_json::to_vec(history)?));
+    }
+    if let (Some(path), false) = (&config.audit_log, bundle.audit.is_empty()) {
+        let lines: String = bundle
+            .audit
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+            .collect();
+        files.push((path.clone(), lines.into_bytes()));
+    }
+    // Nothing is written unless all of it can be
+    let mut writes = Vec::new();
+    for (path, content) in files {
+        if needs_writing(&path, &content, force)? {
+            writes.push((path, content));
+        }
+    }
+    for (path, content) in writes {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        info!("Wrote {}", path.display());
+    }
+    for path in bundle.not_included.iter().filter(|p| !p.exists()) {
+        warn!("Missing, copy it from the old host: {}", path.display());
+    }
+    if bundle.clients.is_some() {
+        info!("Start the server and import again with -c to restore the devices");
+    }
+    Ok(())
+}
+
+async fn import_clients(args: &Args, bundle: Bundle) -> Result<(), Box<dyn Error>> {
+    let Some(clients) = bundle.clients else {
+        return Err("The bundle has no devices, export it with -c".into());
+    };
+    let request = reqwest::Client::new()
+        .post(args.server_url("/admin/clients"))
+        .json(&clients);
+    let request = match &args.token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let res = request.send().await?;
+    if !res.status().is_success() {
+        return Err(admin_error(res).await);
+    }
+    println!("{}", res.text().await?);
+    Ok(())
+}
@@ -0,0 +1,604 @@
+//! Calendars making their user busy, like `BUSY (in a meeting)`, during events while online
+//!
+//! Feeds are fetched as iCalendar and only the times of events are kept. Events repeating
+//! daily or weekly are understood, other repetitions count only the first time. Times in a
+//! named time zone follow it, also across daylight saving time; events in a zone that is not in
+//! the tz database, like a Windows name, are skipped. Times without a zone are the server's.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, Weekday};
+use chrono_tz::Tz;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{watchdog, AppState, User, DEFAULT_USER};
+use crate::{config::CalendarConfig, TIMEOUT};
+
+const DEFAULT_CALENDAR_INTERVAL: u64 = 300;
+/// How often to look for calendars that are due
+const CHECK_INTERVAL: u64 = 30;
+const DEFAULT_BUSY_STATUS: &str = "BUSY";
+const DEFAULT_BUSY_LABEL: &str = "in a meeting";
+/// Most periods between repetitions, and repetitions, a rule may have, so that feeds cannot
+/// make the arithmetic overflow; rules beyond count only the first time
+const MAX_RULE_INTERVAL: i64 = 1000;
+const MAX_RULE_COUNT: i64 = 100_000;
+
+/// The events of a calendar, and when it was fetched
+#[derive(Debug, Default)]
+pub(super) struct Fetched {
+    at: u64,
+    events: Vec<Event>,
+}
+
+/// What the times of an event are in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Utc,
+    /// The server's local time
+    Floating,
+    Named(Tz),
+}
+
+#[derive(Debug)]
+struct Event {
+    start: NaiveDateTime,
+    zone: Zone,
+    duration: Duration,
+    rule: Option<Rule>,
+    /// Starts of repetitions that were cancelled
+    exdates: Vec<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// Weeks between repetitions, or days for daily rules
+    interval: i64,
+    /// Days of weekly rules, none for daily ones
+    weekdays: Vec<Weekday>,
+    count: Option<i64>,
+    until: Option<NaiveDateTime>,
+}
+
+/// Check that the calendars belong to known users
+pub(super) fn load(
+    calendars: Vec<CalendarConfig>,
+    users: &HashMap<String, User>,
+) -> Result<Vec<CalendarConfig>, Box<dyn Error>> {
+    for calendar in &calendars {
+        reqwest::Url::parse(&calendar.url)
+            .map_err(|e| format!("Invalid calendar URL {}: {}", calendar.url, e))?;
+        if let Some(user) = &calendar.user {
+            if !users.contains_key(user) {
+                return Err(
+                    format!("Calendar {} belongs to unknown user {}", calendar.url, user).into(),
+                );
+            }
+        }
+    }
+    Ok(calendars)
+}
+
+pub(super) async fn poll(state: AppState) {
+    loop {
+        let settings = state.settings();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        watchdog::lock(&state.calendars)
+            .retain(|url, _| settings.calendars.iter().any(|c| c.url == *url));
+        for calendar in &settings.calendars {
+            let interval = calendar.interval.unwrap_or(DEFAULT_CALENDAR_INTERVAL);
+            let due = watchdog::lock(&state.calendars)
+                .get(&calendar.url)
+                .is_none_or(|f| f.at + interval <= now);
+            if !due {
+                continue;
+            }
+            let res = state
+                .http
+                .get(&calendar.url)
+                .timeout(time::Duration::from_secs(TIMEOUT))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            let events = match res {
+                Ok(res) => match res.text().await {
+                    Ok(ics) => parse(&ics),
+                    Err(e) => {
+                        warn!("Cannot read calendar {}: {}", calendar.url, e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!("Cannot fetch calendar {}: {}", calendar.url, e);
+                    continue;
+                }
+            };
+            watchdog::lock(&state.calendars)
+                .insert(calendar.url.clone(), Fetched { at: now, events });
+        }
+        drop(settings);
+        time::sleep(time::Duration::from_secs(CHECK_INTERVAL)).await;
+    }
+}
+
+/// What an online user is instead, if one of their calendars has an event now
+pub(super) fn busy_status(state: &AppState, user: &str, now: u64) -> Option<String> {
+    let settings = state.settings();
+    let fetched = watchdog::lock(&state.calendars);
+    settings
+        .calendars
+        .iter()
+        .filter(|c| c.user.as_deref().unwrap_or(DEFAULT_USER) == user)
+        .find(|c| {
+            fetched
+                .get(&c.url)
+                .is_some_and(|f| f.events.iter().any(|e| e.busy_at(now)))
+        })
+        .map(|c| {
+            format!(
+                "{} ({})",
+                c.status.as_deref().unwrap_or(DEFAULT_BUSY_STATUS),
+                c.label.as_deref().unwrap_or(DEFAULT_BUSY_LABEL)
+            )
+        })
+}
+
+impl Event {
+    fn busy_at(&self, now: u64) -> bool {
+        let Some(now) = DateTime::from_timestamp(now as i64, 0) else {
+            return false;
+        };
+        let now = match self.zone {
+            Zone::Utc => now.naive_utc(),
+            Zone::Floating => now.with_timezone(&Local).naive_local(),
+            Zone::Named(tz) => now.with_timezone(&tz).naive_local(),
+        };
+        // Times too far out to add up to are no meeting
+        self.starts_before(now)
+            .unwrap_or_default()
+            .into_iter()
+            .any(|start| {
+                start
+                    .checked_add_signed(self.duration)
+                    .is_some_and(|end| now < end)
+                    && !self.exdates.contains(&start)
+            })
+    }
+
+    /// Starts of the repetitions that may still last at `now`, none if they overflow
+    fn starts_before(&self, now: NaiveDateTime) -> Option<Vec<NaiveDateTime>> {
+        if now < self.start {
+            return Some(Vec::new());
+        }
+        let Some(rule) = &self.rule else {
+            return Some(vec![self.start]);
+        };
+        let period = match rule.weekdays.is_empty() {
+            true => Duration::try_days(rule.interval)?,
+            false => Duration::try_weeks(rule.interval)?,
+        };
+        // Start of the first period, and of the repetitions within each period
+        let (first, offsets) = match rule.weekdays.is_empty() {
+            true => (self.start, vec![Duration::zero()]),
+            false => {
+                let monday = self.start.checked_sub_signed(Duration::days(
+                    self.start.weekday().num_days_from_monday() as i64,
+                ))?;
+                let mut offsets: Vec<_> = rule
+                    .weekdays
+                    .iter()
+                    .map(|d| Duration::days(d.num_days_from_monday() as i64))
+                    .collect();
+                offsets.sort();
+                offsets.dedup();
+                (monday, offsets)
+            }
+        };
+        let skipped = offsets
+            .iter()
+            .filter(|&&o| first.checked_add_signed(o).is_some_and(|s| s < self.start))
+            .count() as i64;
+        let last = (now - first).num_seconds() / period.num_seconds();
+        let back = self.duration.num_seconds() / period.num_seconds() + 1;
+        let mut starts = Vec::new();
+        for n in (last - back).max(0)..=last {
+            let periods = period.checked_mul(i32::try_from(n).ok()?)?;
+            for (i, offset) in offsets.iter().enumerate() {
+                let start = first
+                    .checked_add_signed(periods)?
+                    .checked_add_signed(*offset)?;
+                let ordinal = n * offsets.len() as i64 + i as i64 - skipped;
+                if start < self.start
+                    || start > now
+                    || rule.count.is_some_and(|count| ordinal >= count)
+                    || rule.until.is_some_and(|until| start > until)
+                {
+                    continue;
+                }
+                starts.push(start);
+            }
+        }
+        Some(starts)
+    }
+}
+
+/// Events that make their user busy: not cancelled, not free time and not all day
+fn parse(ics: &str) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut properties: Option<Vec<(String, String, String)>> = None;
+    for line in unfold(ics) {
+        let Some((name, params, value)) = split_property(&line) else {
+            continue;
+        };
+        match (name.as_str(), value.as_str()) {
+            ("BEGIN", "VEVENT") => properties = Some(Vec::new()),
+            ("END", "VEVENT") => {
+                if let Some(event) = properties.take().and_then(|p| event(&p)) {
+                    events.push(event);
+                }
+            }
+            _ => {
+                if let Some(properties) = &mut properties {
+                    properties.push((name, params, value));
+                }
+            }
+        }
+    }
+    events
+}
+
+fn event(properties: &[(String, String, String)]) -> Option<Event> {
+    let get = |name: &str| properties.iter().find(|(n, ..)| n == name);
+    if get("STATUS").is_some_and(|(.., v)| v == "CANCELLED")
+        || get("TRANSP").is_some_and(|(.., v)| v == "TRANSPARENT")
+    {
+        return None;
+    }
+    let (_, params, value) = get("DTSTART")?;
+    let (start, utc) = parse_time(params, value)?;
+    let zone = match (utc, tzid(params)) {
+        (true, _) => Zone::Utc,
+        (false, None) => Zone::Floating,
+        (false, Some(name)) => match name.parse() {
+            Ok(tz) => Zone::Named(tz),
+            Err(_) => {
+                warn!("Skipping calendar event in unknown time zone {}", name);
+                return None;
+            }
+        },
+    };
+    let duration = match (get("DTEND"), get("DURATION")) {
+        (Some((_, params, value)), _) => parse_time(params, value)?.0 - start,
+        (None, Some((.., value))) => parse_duration(value)?,
+        (None, None) => return None,
+    };
+    if duration <= Duration::zero() {
+        return None;
+    }
+    let exdates = properties
+        .iter()
+        .filter(|(n, ..)| n == "EXDATE")
+        .flat_map(|(_, params, value)| value.split(',').filter_map(|v| parse_time(params, v)))
+        .map(|(time, _)| time)
+        .collect();
+    Some(Event {
+        start,
+        zone,
+        duration,
+        rule: get("RRULE").and_then(|(.., value)| parse_rule(value, start)),
+        exdates,
+    })
+}
+
+/// Join lines continued with a leading space or tab
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Name, parameters and value of a line like `DTSTART;TZID=Europe/Berlin:20240102T090000`
+fn split_property(line: &str) -> Option<(String, String, String)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        (c == ':' && !quoted).then_some(i)
+    })?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((
+        name.to_ascii_uppercase(),
+        params.to_string(),
+        value.trim().to_string(),
+    ))
+}
+
+/// The `TZID` parameter, like `Europe/Berlin`
+fn tzid(params: &str) -> Option<&str> {
+    params
+        .split(';')
+        .find_map(|p| p.strip_prefix("TZID="))
+        .map(|tz| tz.trim_matches('"'))
+}
+
+/// A date and time, and whether it is UTC; none for whole days
+fn parse_time(params: &str, value: &str) -> Option<(NaiveDateTime, bool)> {
+    if params.split(';').any(|p| p == "VALUE=DATE") {
+        return None;
+    }
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    Some((time, utc))
+}
+
+/// Like `PT1H30M` or `P1D`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.strip_prefix('+').unwrap_or(value).strip_prefix('P')?;
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    let mut time = false;
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => time = true,
+            _ => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                let part = match (c, time) {
+                    ('W', false) => Duration::try_weeks(n)?,
+                    ('D', false) => Duration::try_days(n)?,
+                    ('H', true) => Duration::try_hours(n)?,
+                    ('M', true) => Duration::try_minutes(n)?,
+                    ('S', true) => Duration::try_seconds(n)?,
+                    _ => return None,
+                };
+                duration = duration.checked_add(&part)?;
+            }
+        }
+    }
+    Some(duration)
+}
+
+/// Daily and weekly rules like `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;UNTIL=20241231T000000Z`
+fn parse_rule(value: &str, start: NaiveDateTime) -> Option<Rule> {
+    let parts: HashMap<_, _> = value.split(';').filter_map(|p| p.split_once('=')).collect();
+    let weekly = match *parts.get("FREQ")? {
+        "DAILY" => false,
+        "WEEKLY" => true,
+        _ => return None,
+    };
+    let weekdays = match (weekly, parts.get("BYDAY")) {
+        (false, _) => Vec::new(),
+        (true, None) => vec![start.weekday()],
+        (true, Some(days)) => days
+            .split(',')
+            .filter_map(|d| weekday(d.trim_start_matches(|c: char| !c.is_ascii_alphabetic())))
+            .collect(),
+    };
+    if weekly && weekdays.is_empty() {
+        return None;
+    }
+    let until = parts.get("UNTIL").and_then(|until| match until.len() {
+        8 => NaiveDate::parse_from_str(until, "%Y%m%d")
+            .ok()?
+            .and_hms_opt(23, 59, 59),
+        _ => parse_time("", until).map(|(time, _)| time),
+    });
+    let interval = match parts.get("INTERVAL") {
+        Some(interval) => interval.parse().ok()?,
+        None => 1,
+    };
+    let count = match parts.get("COUNT") {
+        Some(count) => Some(count.parse().ok()?),
+        None => None,
+    };
+    if !(1..=MAX_RULE_INTERVAL).contains(&interval)
+        || count.is_some_and(|count| !(0..=MAX_RULE_COUNT).contains(&count))
+    {
+        return None;
+    }
+    Some(Rule {
+        interval,
+        weekdays,
+        count,
+        until,
+    })
+}
+
+fn weekday(day: &str) -> Option<Weekday> {
+    Some(match day {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> u64 {
+        DateTime::parse_from_rfc3339(time).unwrap().timestamp() as u64
+    }
+
+    /// Whether any event of a calendar with these lines in one VEVENT is busy at `time`
+    fn busy(lines: &[&str], time: &str) -> bool {
+        let ics = format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            lines.join("\r\n")
+        );
+        parse(&ics).iter().any(|e| e.busy_at(at(time)))
+    }
+
+    const HOUR: [&str; 2] = ["DTSTART:20240101T090000Z", "DTEND:20240101T100000Z"];
+
+    #[test]
+    fn single_event() {
+        assert!(busy(&HOUR, "2024-01-01T09:30:00Z"));
+        assert!(!busy(&HOUR, "2024-01-01T10:00:00Z"));
+        assert!(!busy(&HOUR, "2024-01-08T09:30:00Z"));
+    }
+
+    #[test]
+    fn weekly_by_day() {
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=WEEKLY;BYDAY=MO,WE"];
+        assert!(busy(&lines, "2024-01-03T09:30:00Z"));
+        assert!(busy(&lines, "2024-01-08T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-02T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-03T10:30:00Z"));
+    }
+
+    #[test]
+    fn interval() {
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=WEEKLY;INTERVAL=2"];
+        assert!(!busy(&lines, "2024-01-08T09:30:00Z"));
+        assert!(busy(&lines, "2024-01-15T09:30:00Z"));
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=DAILY;INTERVAL=3"];
+        assert!(!busy(&lines, "2024-01-03T09:30:00Z"));
+        assert!(busy(&lines, "2024-01-04T09:30:00Z"));
+    }
+
+    #[test]
+    fn count_and_until() {
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=DAILY;COUNT=2"];
+        assert!(busy(&lines, "2024-01-02T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-03T09:30:00Z"));
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=WEEKLY;BYDAY=MO,TU;COUNT=3"];
+        assert!(busy(&lines, "2024-01-08T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-09T09:30:00Z"));
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=DAILY;UNTIL=20240102T235959Z"];
+        assert!(busy(&lines, "2024-01-02T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-03T09:30:00Z"));
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=DAILY;UNTIL=20240102"];
+        assert!(busy(&lines, "2024-01-02T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-03T09:30:00Z"));
+    }
+
+    #[test]
+    fn exdate() {
+        let lines = [
+            HOUR[0],
+            HOUR[1],
+            "RRULE:FREQ=DAILY",
+            "EXDATE:20240102T090000Z,20240104T090000Z",
+        ];
+        assert!(!busy(&lines, "2024-01-02T09:30:00Z"));
+        assert!(busy(&lines, "2024-01-03T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-04T09:30:00Z"));
+    }
+
+    #[test]
+    fn across_midnight() {
+        let lines = [
+            "DTSTART:20240101T230000Z",
+            "DURATION:PT2H",
+            "RRULE:FREQ=WEEKLY",
+        ];
+        assert!(!busy(&lines, "2024-01-01T00:30:00Z"));
+        assert!(busy(&lines, "2024-01-02T00:30:00Z"));
+        assert!(busy(&lines, "2024-01-09T00:30:00Z"));
+        assert!(!busy(&lines, "2024-01-09T01:30:00Z"));
+    }
+
+    #[test]
+    fn free_and_cancelled() {
+        assert!(!busy(
+            &[HOUR[0], HOUR[1], "STATUS:CANCELLED"],
+            "2024-01-01T09:30:00Z"
+        ));
+        assert!(!busy(
+            &[HOUR[0], HOUR[1], "TRANSP:TRANSPARENT"],
+            "2024-01-01T09:30:00Z"
+        ));
+        assert!(busy(
+            &[HOUR[0], HOUR[1], "TRANSP:OPAQUE"],
+            "2024-01-01T09:30:00Z"
+        ));
+        let all_day = ["DTSTART;VALUE=DATE:20240101", "DTEND;VALUE=DATE:20240102"];
+        assert!(!busy(&all_day, "2024-01-01T09:30:00Z"));
+    }
+
+    #[test]
+    fn folded_lines() {
+        let lines = [HOUR[0], HOUR[1], "RRULE:FREQ=WEEKLY;BY\r\n DAY=MO,\r\n\tWE"];
+        assert!(busy(&lines, "2024-01-03T09:30:00Z"));
+        assert!(!busy(&lines, "2024-01-04T09:30:00Z"));
+    }
+
+    #[test]
+    fn time_zones() {
+        // 9:00 in New York is 14:00 UTC in winter and 13:00 UTC in summer
+        let lines = [
+            "DTSTART;TZID=America/New_York:20240101T090000",
+            "DTEND;TZID=America/New_York:20240101T100000",
+            "RRULE:FREQ=WEEKLY",
+        ];
+        assert!(busy(&lines, "2024-01-08T14:30:00Z"));
+        assert!(!busy(&lines, "2024-01-08T09:30:00Z"));
+        assert!(busy(&lines, "2024-07-01T13:30:00Z"));
+        let lines = [
+            "DTSTART;TZID=\"W. Europe Standard Time\":20240101T090000",
+            "DTEND;TZID=\"W. Europe Standard Time\":20240101T100000",
+        ];
+        assert!(parse(&format!("BEGIN:VEVENT\n{}\nEND:VEVENT\n", lines.join("\n"))).is_empty());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H30M"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("P1DT1S"), Some(Duration::seconds(86401)));
+        assert_eq!(parse_duration("P2W"), Some(Duration::weeks(2)));
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn hostile_rules() {
+        // None of these may panic, and repetitions the server cannot work out are not meetings
+        for rule in [
+            "RRULE:FREQ=WEEKLY;INTERVAL=999999999999",
+            "RRULE:FREQ=DAILY;INTERVAL=9223372036854775807",
+            "RRULE:FREQ=DAILY;INTERVAL=-1",
+            "RRULE:FREQ=DAILY;COUNT=99999999999999",
+        ] {
+            let lines = [HOUR[0], HOUR[1], rule];
+            assert!(busy(&lines, "2024-01-01T09:30:00Z"), "{}", rule);
+            assert!(!busy(&lines, "2024-01-02T09:30:00Z"), "{}", rule);
+        }
+        let far = [
+            "DTSTART:00010101T090000Z",
+            "DURATION:PT1H",
+            "RRULE:FREQ=DAILY;INTERVAL=1000",
+        ];
+        assert!(!busy(&far, "2024-01-02T09:30:00Z"));
+        let long = [HOUR[0], "DURATION:P9999999999999W", "RRULE:FREQ=DAILY"];
+        assert!(!busy(&long, "2024-01-02T09:30:00Z"));
+        let endless = [
+            "DTSTART:20240101T090000Z",
+            "DURATION:P1000000W",
+            "RRULE:FREQ=WEEKLY;INTERVAL=1000",
+        ];
+        assert!(!busy(&endless, "1970-01-01T00:00:00Z"));
+        assert!(busy(&endless, "2024-06-01T00:00:00Z"));
+    }
+}
@@ -0,0 +1,105 @@
+//! The server's own presence, as a device of one of its users, so that it shows up like any
+//! other and can be watched from another server it sends heartbeats to
+//!
+//! No heartbeats are made while the watchdog finds the state wedged, so that a server which
+//! still answers but no longer works goes offline too.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{AppState, User, DEFAULT_USER, MAX_DEVICE_NAME_LEN, MAX_MESSAGE_LEN};
+use crate::{config::CanaryConfig, HeartBeat, HEARTBEAT_INTERVAL, TIMEOUT};
+
+const DEFAULT_DEVICE: &str = "server";
+
+pub(super) fn load(
+    canary: CanaryConfig,
+    users: &HashMap<String, User>,
+) -> Result<CanaryConfig, Box<dyn Error>> {
+    let device = canary.device.as_deref().unwrap_or(DEFAULT_DEVICE);
+    if device.is_empty() || device.len() > MAX_DEVICE_NAME_LEN {
+        return Err(format!(
+            "Canary device name {:?} must be 1 to {} bytes",
+            device, MAX_DEVICE_NAME_LEN
+        )
+        .into());
+    }
+    if canary
+        .message
+        .as_ref()
+        .is_some_and(|m| m.len() > MAX_MESSAGE_LEN)
+    {
+        return Err(format!("Canary message must be at most {} bytes", MAX_MESSAGE_LEN).into());
+    }
+    if let Some(user) = canary.user.as_ref().filter(|u| !users.contains_key(*u)) {
+        return Err(format!("Canary device of unknown user {}", user).into());
+    }
+    Ok(canary)
+}
+
+pub(super) async fn beat(state: AppState) {
+    loop {
+        let settings = state.settings();
+        if let Some(canary) = settings
+            .canary
+            .as_ref()
+            .filter(|_| !state.watchdog.wedged())
+        {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let device = canary.device.as_deref().unwrap_or(DEFAULT_DEVICE);
+            let user = canary.user.as_deref().unwrap_or(DEFAULT_USER);
+            {
+                let mut clients = state.lock_clients();
+                let entry = clients
+                    .devices_mut(user)
+                    .entry(device.to_string())
+                    .or_default();
+                if !entry.online(now) {
+                    entry.online_since = now;
+                }
+                entry.last_seen = now;
+                entry.timestamp = now;
+                entry.interval = Some(HEARTBEAT_INTERVAL);
+                entry.message = canary.message.clone();
+            }
+
+            if let Some(upstream) = &canary.upstream {
+                let heartbeat = HeartBeat {
+                    timestamp: now,
+                    signature: None,
+                    hash: None,
+                    detached_signature: None,
+                    device: Some(device.to_string()),
+                    message: canary.message.clone(),
+                    place: None,
+                    latency_ms: None,
+                    interval: Some(HEARTBEAT_INTERVAL),
+                    client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                    uptime: None,
+                    metrics: None,
+                    extra: None,
+                };
+                let mut req = state
+                    .http
+                    .post(upstream)
+                    .json(&heartbeat)
+                    .timeout(time::Duration::from_secs(TIMEOUT));
+                if let Some(token) = &canary.token {
+                    req = req.bearer_auth(token);
+                }
+                if let Err(e) = req.send().await.and_then(|r| r.error_for_status()) {
+                    error!("Sending the canary heartbeat to {} failed: {}", upstream, e);
+                }
+            }
+        }
+        drop(settings);
+        time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+    }
+}
@@ -0,0 +1,91 @@
+//! Validating the server configuration for `online_status check`
+
+use reqwest::Url;
+use std::{net::TcpListener, time::Duration};
+
+use super::{notify::Event, presence, Settings};
+use crate::{
+    config::{load_server_config, Args},
+    TIMEOUT,
+};
+
+/// One result per check, describing what was checked or what is wrong
+pub(crate) async fn check_server(args: &Args, send_test: bool) -> Vec<Result<String, String>> {
+    let mut results = Vec::new();
+    let settings = match Settings::load(args) {
+        Ok(settings) => settings,
+        Err(e) => return vec![Err(e.to_string())],
+    };
+    results.push(Ok(format!(
+        "Configuration and keys, {} user(s) and {} viewer(s)",
+        settings.users.len(),
+        settings.viewers.len()
+    )));
+    if let Some(path) = &settings.history {
+        results.push(
+            presence::load(path)
+                .map(|_| format!("History {}", path.display()))
+                .map_err(|e| e.to_string()),
+        );
+    }
+    let port = args.port.unwrap();
+    results.push(
+        TcpListener::bind(("0.0.0.0", port))
+            .map(|_| format!("Binding 0.0.0.0:{}", port))
+            .map_err(|e| format!("Cannot bind 0.0.0.0:{}: {}", port, e)),
+    );
+
+    // Settings::load succeeded, so this cannot fail
+    let config = load_server_config(args).unwrap();
+    let mut urls: Vec<(String, &str)> = config
+        .peers
+        .iter()
+        .map(|peer| (format!("Peer {}", peer.name), peer.url.as_str()))
+        .collect();
+    if let Some(relay) = &config.relay {
+        urls.push(("Relay".to_string(), &relay.url));
+    }
+    if let Some(cluster) = &config.cluster {
+        urls.extend(
+            cluster
+                .nodes
+                .iter()
+                .map(|n| ("Cluster node".to_string(), n.as_str())),
+        );
+    }
+    if let Some(mirror) = &config.mirror {
+        urls.push(("Primary".to_string(), &mirror.primary));
+    }
+    urls.extend(
+        config
+            .notify
+            .iter()
+            .map(|n| ("Webhook".to_string(), n.url.as_str())),
+    );
+    for (what, url) in urls {
+        results.push(
+            Url::parse(url)
+                .map(|_| format!("{} {}", what, url))
+                .map_err(|e| format!("{} {}: {}", what, url, e)),
+        );
+    }
+
+    if send_test {
+        let http = reqwest::Client::new();
+        let event = Event::test();
+        for hook in &config.notify {
+            let sent = http
+                .post(&hook.url)
+                .json(&event)
+                .timeout(Duration::from_secs(TIMEOUT))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            results.push(
+                sent.map(|_| format!("Test notification to {}", hook.url))
+                    .map_err(|e| format!("Notifying {} failed: {}", hook.url, e)),
+            );
+        }
+    }
+    results
+}
@@ -0,0 +1,90 @@
+//! Rejecting heartbeats of clients older than `min_client_version`
+
+use axum::http::StatusCode;
+
+use super::error::ApiError;
+
+/// A version like `1.2.3`, compared number by number, ignoring suffixes like `-beta`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Version(Vec<u64>);
+
+impl Version {
+    pub(super) fn parse(text: &str) -> Result<Version, String> {
+        let release = text.split(['-', '+']).next().unwrap_or_default();
+        release
+            .split('.')
+            .map(|part| {
+                part.parse()
+                    .map_err(|_| format!("Invalid version {}", text))
+            })
+            .collect::<Result<Vec<u64>, _>>()
+            .map(|mut numbers| {
+                while numbers.last() == Some(&0) {
+                    numbers.pop();
+                }
+                Version(numbers)
+            })
+    }
+}
+
+/// Whether a client announcing `version` may send heartbeats, clients too old to announce it
+/// may not
+pub(super) fn check(min: &str, version: Option<&str>) -> Result<(), ApiError> {
+    let outdated = |what: String| {
+        Err(
+            ApiError::new(StatusCode::UPGRADE_REQUIRED, "client_outdated", what)
+                .detail(format!("Upgrade online_status to {} or newer", min)),
+        )
+    };
+    let Some(version) = version else {
+        return outdated(format!(
+            "This server needs clients of version {} or newer",
+            min
+        ));
+    };
+    match (Version::parse(version), Version::parse(min)) {
+        (Ok(version), Ok(min_version)) if version >= min_version => Ok(()),
+        _ => outdated(format!("Client version {} is older than {}", version, min)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(version("1.2.3"), Version(vec![1, 2, 3]));
+        assert_eq!(version("1.2.0"), version("1.2"));
+        assert_eq!(version("2.0.0"), version("2"));
+        assert_eq!(version("1.2.3-beta.1"), version("1.2.3"));
+        assert_eq!(version("1.2.3+build.5"), version("1.2.3"));
+        assert!(Version::parse("").is_err());
+        assert!(Version::parse("1.x").is_err());
+        assert!(Version::parse("v1.2").is_err());
+        assert!(Version::parse("1..2").is_err());
+    }
+
+    #[test]
+    fn compare() {
+        assert!(version("1.10.0") > version("1.9.9"));
+        assert!(version("1.2.1") > version("1.2"));
+        assert!(version("2") > version("1.99"));
+        assert!(version("0.0.9") < version("0.1"));
+    }
+
+    #[test]
+    fn outdated() {
+        assert!(check("1.2.0", Some("1.2.0")).is_ok());
+        assert!(check("1.2.0", Some("1.2")).is_ok());
+        assert!(check("1.2.0", Some("1.3.0-rc.1")).is_ok());
+        for version in [Some("1.1.9"), Some("garbage"), None] {
+            let error = check("1.2.0", version).unwrap_err();
+            assert_eq!(error.code(), "client_outdated");
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Sharing the known clients between several instances of the server
+//!
+//! Every node periodically sends its whole client map to each other node,
+//! which merges it and answers with its own. Entries are merged by keeping
+//! the most recent heartbeat, so the maps converge as long as any two nodes
+//! can talk to each other. Devices deleted or renamed through the admin API
+//! are sent along as tombstones, which win over copies not seen since.
+
+use axum::{extract::State, http::HeaderMap, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{bearer_token, share::constant_time_eq, ApiError, AppState, ClientMap, Device};
+use crate::{HEARTBEAT_INTERVAL, TIMEOUT, ZOMBIE_TIMEOUT};
+
+const DEFAULT_GOSSIP_INTERVAL: u64 = 10;
+/// How long deletions are remembered, by when the other nodes have archived their copies
+const TOMBSTONE_TIMEOUT: u64 = 2 * ZOMBIE_TIMEOUT;
+
+/// When devices were deleted, by user and device name
+pub(super) type Tombstones = HashMap<String, HashMap<String, u64>>;
+
+/// What nodes send each other
+#[derive(Serialize, Deserialize)]
+pub(super) struct Gossip {
+    clients: ClientMap,
+    #[serde(default)]
+    deleted: Tombstones,
+}
+
+impl AppState {
+    /// Remember that a device was deleted, so that other nodes delete it too
+    pub(super) fn bury(&self, user: &str, device: &str) {
+        if self.settings().cluster.is_none() {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.deleted
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default()
+            .insert(device.to_string(), now);
+    }
+
+    fn gossip(&self) -> Gossip {
+        Gossip {
+            clients: ClientMap::clone(&self.snapshot()),
+            deleted: self.deleted.lock().unwrap().clone(),
+        }
+    }
+
+    /// Merge the client map of another node into ours
+    fn merge(&self, remote: Gossip) {
+        let settings = self.settings();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut deleted = self.deleted.lock().unwrap();
+        for (user, devices) in remote.deleted {
+            let local = deleted.entry(user).or_default();
+            for (name, at) in devices {
+                let local = local.entry(name).or_insert(at);
+                *local = (*local).max(at);
+            }
+        }
+        for devices in deleted.values_mut() {
+            devices.retain(|_, at| now.saturating_sub(*at) <= TOMBSTONE_TIMEOUT);
+        }
+        deleted.retain(|_, devices| !devices.is_empty());
+        // Not seen since it was deleted
+        let buried = |user: &str, name: &str, device: &Device| {
+            deleted
+                .get(user)
+                .and_then(|devices| devices.get(name))
+                .is_some_and(|&at| device.last_seen <= at)
+        };
+
+        let mut clients = self.lock_clients();
+        for user in deleted.keys() {
+            let stale = clients
+                .get(user)
+                .is_some_and(|devices| devices.iter().any(|(n, d)| buried(user, n, d)));
+            if let Some(devices) = clients.get_devices_mut(user).filter(|_| stale) {
+                devices.retain(|n, d| !buried(user, n, d));
+            }
+        }
+        for (user, devices) in remote.clients {
+            if !settings.users.contains_key(&user) {
+                continue;
+            }
+            let local = clients.devices_mut(&user);
+            for (name, device) in Arc::unwrap_or_clone(devices) {
+                if buried(&user, &name, &device) {
+                    continue;
+                }
+                match local.get(&name) {
+                    Some(existing) if existing.last_seen >= device.last_seen => {}
+                    _ => {
+                        local.insert(name, device);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(super) async fn gossip(state: AppState) {
+    loop {
+        let settings = state.settings();
+        let Some(cluster) = &settings.cluster else {
+            drop(settings);
+            time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+            continue;
+        };
+        for node in &cluster.nodes {
+            let gossip = state.gossip();
+            let res = state
+                .http
+                .post(format!("{}/cluster/sync", node.trim_end_matches('/')))
+                .bearer_auth(&cluster.secret)
+                .json(&gossip)
+                .timeout(time::Duration::from_secs(TIMEOUT))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match res {
+                Ok(res) => match res.json::<Gossip>().await {
+                    Ok(remote) => state.merge(remote),
+                    Err(e) => warn!("Invalid clients from node {}: {}", node, e),
+                },
+                Err(e) => warn!("Cannot gossip with node {}: {}", node, e),
+            }
+        }
+        let interval = cluster.interval.unwrap_or(DEFAULT_GOSSIP_INTERVAL);
+        drop(settings);
+        time::sleep(time::Duration::from_secs(interval)).await;
+    }
+}
+
+/// Our client map, for mirrors
+pub(super) async fn snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ClientMap>, ApiError> {
+    let settings = state.settings();
+    let cluster = settings.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if !bearer_token(&headers).is_some_and(|token| constant_time_eq(token, &cluster.secret)) {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    }
+    let snapshot = ClientMap::clone(&state.snapshot());
+    Ok(Json(snapshot))
+}
+
+/// Receive the client map of another node and answer with ours
+pub(super) async fn sync(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(remote): Json<Gossip>,
+) -> Result<Json<Gossip>, ApiError> {
+    let settings = state.settings();
+    let cluster = settings.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if !bearer_token(&headers).is_some_and(|token| constant_time_eq(token, &cluster.secret)) {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    }
+    state.merge(remote);
+    Ok(Json(state.gossip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{tests::state, DEFAULT_USER};
+
+    fn device(last_seen: u64) -> Device {
+        Device {
+            last_seen,
+            ..Device::default()
+        }
+    }
+
+    fn gossip(devices: &[(&str, u64)], deleted: &[(&str, u64)]) -> Gossip {
+        let devices = devices
+            .iter()
+            .map(|&(name, last_seen)| (name.to_string(), device(last_seen)))
+            .collect();
+        let deleted = deleted
+            .iter()
+            .map(|&(name, at)| (name.to_string(), at))
+            .collect();
+        Gossip {
+            clients: HashMap::from([(DEFAULT_USER.to_string(), Arc::new(devices))]),
+            deleted: HashMap::from([(DEFAULT_USER.to_string(), deleted)]),
+        }
+    }
+
+    fn devices(state: &AppState) -> Vec<(String, u64)> {
+        let mut devices: Vec<_> = state.snapshot()[DEFAULT_USER]
+            .iter()
+            .map(|(name, d)| (name.clone(), d.last_seen))
+            .collect();
+        devices.sort();
+        devices
+    }
+
+    #[test]
+    fn newest_wins() {
+        let state = state();
+        state.merge(gossip(&[("laptop", 100), ("phone", 100)], &[]));
+        state.merge(gossip(&[("laptop", 200), ("phone", 50)], &[]));
+        assert_eq!(
+            devices(&state),
+            [("laptop".to_string(), 200), ("phone".to_string(), 100)]
+        );
+    }
+
+    #[test]
+    fn tombstones() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let state = state();
+        state.merge(gossip(&[("laptop", now - 100), ("phone", now - 100)], &[]));
+        // Deleted elsewhere, while another node still has the laptop
+        state.merge(gossip(&[("laptop", now - 100)], &[("laptop", now - 50)]));
+        assert_eq!(devices(&state), [("phone".to_string(), now - 100)]);
+        // And a node that has not heard of the deletion yet
+        state.merge(gossip(&[("laptop", now - 100)], &[]));
+        assert_eq!(devices(&state), [("phone".to_string(), now - 100)]);
+        assert!(state.gossip().deleted[DEFAULT_USER].contains_key("laptop"));
+        // Until it sends a heartbeat again
+        state.merge(gossip(&[("laptop", now - 10)], &[]));
+        assert_eq!(
+            devices(&state),
+            [
+                ("laptop".to_string(), now - 10),
+                ("phone".to_string(), now - 100)
+            ]
+        );
+        // Forgotten after a while
+        state.merge(gossip(&[], &[("tablet", now - TOMBSTONE_TIMEOUT - 1)]));
+        assert!(!state.gossip().deleted[DEFAULT_USER].contains_key("tablet"));
+    }
+}
@@ -0,0 +1,129 @@
+//! `/dashboard`, a page for the admin showing every user's devices, availability and
+//! incidents, and how the notification sinks are doing
+//!
+//! The page is compiled into the binary and polls `/dashboard/data`. Browsers ask for the
+//! `admin_token` as the password of a basic authentication prompt, and send it along with
+//! the polls, unless admins log in with the identity provider.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Json,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    authorize_admin, decided_report,
+    notify::{self, Delivery},
+    oidc,
+    presence::Incident,
+    uptime, ApiError, AppState, StatusReport, DEFAULT_USER,
+};
+use crate::config::Privacy;
+
+const PAGE: &str = include_str!("dashboard.html");
+/// Days of availability charted per user
+const CHART_DAYS: u64 = 30;
+/// Most recent incidents listed per user
+const INCIDENTS: usize = 10;
+
+#[derive(Serialize)]
+pub(super) struct DashboardUser {
+    name: String,
+    #[serde(flatten)]
+    report: StatusReport,
+    /// Share of each UTC day that was not part of an incident, oldest first
+    uptime: Vec<Option<f64>>,
+    /// Most recent first
+    incidents: Vec<Incident>,
+}
+
+#[derive(Serialize)]
+pub(super) struct Dashboard {
+    /// Who is logged in with the identity provider, if anyone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    admin: Option<String>,
+    users: Vec<DashboardUser>,
+    sinks: BTreeMap<String, Option<Delivery>>,
+}
+
+/// The answer asking the browser for the admin token, unless it sent the right one
+fn challenge(headers: &HeaderMap, state: &AppState) -> Option<Response> {
+    match authorize_admin(headers, state) {
+        Ok(()) => None,
+        Err(StatusCode::UNAUTHORIZED) => Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, r#"Basic realm="online_status""#)],
+                ApiError::from(StatusCode::UNAUTHORIZED),
+            )
+                .into_response(),
+        ),
+        Err(status) => Some(ApiError::from(status).into_response()),
+    }
+}
+
+pub(super) async fn page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if state.settings().oidc.is_some() && authorize_admin(&headers, &state).is_err() {
+        return Redirect::to("login").into_response();
+    }
+    if let Some(response) = challenge(&headers, &state) {
+        return response;
+    }
+    ([(header::CACHE_CONTROL, "no-store")], Html(PAGE)).into_response()
+}
+
+pub(super) async fn data(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Dashboard>, Response> {
+    if let Some(response) = challenge(&headers, &state) {
+        return Err(response);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let settings = state.settings();
+    let mut users: Vec<DashboardUser> = settings
+        .users
+        .keys()
+        .map(|user| {
+            let (uptime, incidents) = {
+                let presence = state.presence.lock().unwrap();
+                let presence = presence.get(user);
+                let incidents = presence
+                    .into_iter()
+                    .flat_map(|p| p.incidents.iter().rev())
+                    .take(INCIDENTS)
+                    .cloned()
+                    .collect();
+                (uptime::daily_uptime(presence, now, CHART_DAYS), incidents)
+            };
+            DashboardUser {
+                name: match user.as_str() {
+                    DEFAULT_USER => settings
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| "default".to_string()),
+                    user => user.to_string(),
+                },
+                report: decided_report(&state, user, now, Privacy::Messages),
+                uptime,
+                incidents,
+            }
+        })
+        .collect();
+    drop(settings);
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(Dashboard {
+        admin: oidc::admin(&state, &headers),
+        users,
+        sinks: notify::sinks(&state),
+    }))
+}
@@ -0,0 +1,151 @@
+//! Dead man's switches: running a command, calling a webhook or sending a mail once a user has
+//! been offline for a configured number of hours, like a check-in they missed
+//!
+//! Each switch fires once per time offline, and again after the user was back online.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, process::Command, time};
+
+use super::{decided_report, notify, AppState, User, DEFAULT_USER};
+use crate::{
+    config::{DeadManConfig, Privacy},
+    HEARTBEAT_INTERVAL, TIMEOUT,
+};
+
+const HOUR: u64 = 60 * 60;
+
+pub(super) fn load(
+    switches: Vec<DeadManConfig>,
+    users: &HashMap<String, User>,
+) -> Result<Vec<DeadManConfig>, Box<dyn Error>> {
+    for switch in &switches {
+        if let Some(user) = switch.user.as_ref().filter(|u| !users.contains_key(*u)) {
+            return Err(format!("Dead man's switch of unknown user {}", user).into());
+        }
+        if switch.hours == 0 {
+            return Err("Dead man's switches need at least 1 hour".into());
+        }
+        if switch.command.is_empty() && switch.webhook.is_none() && switch.email.is_none() {
+            return Err(format!(
+                "Dead man's switch after {} hours has no command, webhook or email",
+                switch.hours
+            )
+            .into());
+        }
+    }
+    Ok(switches)
+}
+
+/// Since when a user has been offline, if they are
+pub(super) fn offline_since(state: &AppState, user: &str, now: u64) -> Option<u64> {
+    let report = decided_report(state, user, now, Privacy::Devices);
+    if report.status != "OFFLINE" {
+        return None;
+    }
+    let incident = state.presence.lock().unwrap().get(user).and_then(|p| {
+        p.incidents
+            .last()
+            .filter(|i| i.end.is_none())
+            .map(|i| i.start)
+    });
+    // Without any device seen since the restart, the history may still know
+    Some(report.offline_since.or(incident).unwrap_or(state.started))
+}
+
+pub(super) async fn watch(state: AppState) {
+    // The user and hours of every switch that fired since the user was last online
+    let mut fired: HashSet<(String, u64)> = HashSet::new();
+    loop {
+        let settings = state.settings();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut offline = HashMap::new();
+        for switch in &settings.dead_man {
+            let user = switch.user.as_deref().unwrap_or(DEFAULT_USER);
+            let since = *offline
+                .entry(user)
+                .or_insert_with(|| offline_since(&state, user, now));
+            let Some(since) = since else {
+                fired.retain(|(u, _)| u != user);
+                continue;
+            };
+            if now.saturating_sub(since) < switch.hours * HOUR
+                || !fired.insert((user.to_string(), switch.hours))
+            {
+                continue;
+            }
+            let event = notify::Event::new(
+                "dead_man",
+                user,
+                None,
+                &format!("has been offline for {} hours", switch.hours),
+            );
+            notify::notify(&state, event.clone());
+            tokio::spawn(fire(state.clone(), switch.clone(), event, since));
+        }
+        drop(settings);
+        time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+    }
+}
+
+async fn fire(state: AppState, switch: DeadManConfig, event: notify::Event, since: u64) {
+    if let Some((program, args)) = switch.command.split_first() {
+        let status = Command::new(program)
+            .args(args)
+            .env("ONLINE_STATUS_USER", &event.user)
+            .env("ONLINE_STATUS_OFFLINE_SINCE", since.to_string())
+            .env("ONLINE_STATUS_MESSAGE", &event.message)
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => error!("Dead man's switch command {} failed: {}", program, status),
+            Err(e) => error!("Cannot run dead man's switch command {}: {}", program, e),
+        }
+    }
+    if let Some(url) = &switch.webhook {
+        let res = state
+            .http
+            .post(url)
+            .json(&event)
+            .timeout(time::Duration::from_secs(TIMEOUT))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = res {
+            error!("Calling dead man's switch webhook {} failed: {}", url, e);
+        }
+    }
+    if let Some(to) = &switch.email {
+        if let Err(e) = mail(to, &event.message).await {
+            error!("Mailing {} failed: {}", to, e);
+        }
+    }
+}
+
+/// Send a mail through the local `sendmail`
+async fn mail(to: &str, message: &str) -> Result<(), Box<dyn Error>> {
+    let mut sendmail = Command::new("sendmail")
+        .args(["-i", "--", to])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mail = format!(
+        "To: {}\nSubject: online_status: {}\n\n{}.\n",
+        to, message, message
+    );
+    let mut stdin = sendmail.stdin.take().unwrap();
+    stdin.write_all(mail.as_bytes()).await?;
+    drop(stdin);
+    let status = sendmail.wait().await?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("sendmail exited with {}", status).into()),
+    }
+}
@@ -0,0 +1,102 @@
+//! Escalating the longer a user stays offline: each tier's webhook gets an `escalated` event
+//! after its number of minutes, and a `recovered` one once the user is back
+//!
+//! Tiers wait while the user's quiet hours last. A status set through the admin API, like
+//! `VACATION`, holds them all off, as the user is not `OFFLINE` then.
+
+use chrono::{Local, TimeZone, Timelike};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{dead_man::offline_since, notify::Event, presence, AppState, User, DEFAULT_USER};
+use crate::{config::EscalationConfig, TIMEOUT};
+
+pub(super) fn load(
+    tiers: Vec<EscalationConfig>,
+    users: &HashMap<String, User>,
+) -> Result<Vec<EscalationConfig>, Box<dyn Error>> {
+    if let Some(user) = tiers
+        .iter()
+        .find_map(|t| t.user.as_ref().filter(|u| !users.contains_key(*u)))
+    {
+        return Err(format!("Escalation for unknown user {}", user).into());
+    }
+    Ok(tiers)
+}
+
+fn send(state: &AppState, url: &str, event: Event) {
+    info!("Escalation to {}: {}", url, event.message);
+    let req = state
+        .http
+        .post(url)
+        .json(&event)
+        .timeout(time::Duration::from_secs(TIMEOUT));
+    let url = url.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = req.send().await.and_then(|r| r.error_for_status()) {
+            error!("Escalating to {} failed: {}", url, e);
+        }
+    });
+}
+
+pub(super) async fn watch(state: AppState) {
+    // The user, minutes and URL of every tier reached, with since when the user was offline
+    let mut reached: HashMap<(String, u64, String), u64> = HashMap::new();
+    loop {
+        let settings = state.settings();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let local = Local.timestamp_opt(now as i64, 0).unwrap();
+        let minute = local.hour() * 60 + local.minute();
+
+        let users: HashSet<&str> = settings
+            .escalation
+            .iter()
+            .map(|t| t.user.as_deref().unwrap_or(DEFAULT_USER))
+            .collect();
+        for user in users {
+            let Some(since) = offline_since(&state, user, now) else {
+                reached.retain(|(u, _, url), since| {
+                    if u != user {
+                        return true;
+                    }
+                    let minutes = now.saturating_sub(*since) / 60;
+                    let what = format!("is back online after {} minutes", minutes);
+                    send(&state, url, Event::new("recovered", user, None, &what));
+                    false
+                });
+                continue;
+            };
+            let quiet = settings
+                .users
+                .get(user)
+                .and_then(|u| u.quiet_hours)
+                .is_some_and(|q| q.contains(minute));
+            let tiers = settings
+                .escalation
+                .iter()
+                .filter(|t| t.user.as_deref().unwrap_or(DEFAULT_USER) == user);
+            for tier in tiers.filter(|_| !quiet) {
+                let key = (user.to_string(), tier.minutes, tier.url.clone());
+                if now.saturating_sub(since) < tier.minutes * 60 || reached.contains_key(&key) {
+                    continue;
+                }
+                let what = format!("has been offline for {} minutes", tier.minutes);
+                send(
+                    &state,
+                    &tier.url,
+                    Event::new("escalated", user, None, &what),
+                );
+                reached.insert(key, since);
+            }
+        }
+        drop(settings);
+        time::sleep(time::Duration::from_secs(presence::WATCH_INTERVAL)).await;
+    }
+}
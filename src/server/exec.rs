@@ -0,0 +1,94 @@
+//! Running commands on events, for scripts instead of the built-in integrations
+//!
+//! Arguments are templates like `{{user}} is {{event}}`, filled in per event, and the program
+//! runs without a shell, so values cannot inject commands. What it prints ends up in the log.
+
+use std::{error::Error, process::Stdio};
+use tokio::{process::Command, time};
+
+use super::{
+    notify::{self, Event},
+    AppState,
+};
+use crate::{config::ExecConfig, TIMEOUT};
+
+const PLACEHOLDERS: [&str; 5] = ["event", "user", "device", "message", "timestamp"];
+
+pub(super) fn load(hooks: Vec<ExecConfig>) -> Result<Vec<ExecConfig>, Box<dyn Error>> {
+    for hook in &hooks {
+        let Some(program) = hook.command.first() else {
+            return Err("Exec hook without a command".into());
+        };
+        for arg in &hook.command {
+            let mut rest = arg.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else {
+                    return Err(format!("Unterminated placeholder in exec hook {}", program).into());
+                };
+                let name = rest[start + 2..start + end].trim();
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(
+                        format!("Unknown placeholder in exec hook {}: {}", program, name).into(),
+                    );
+                }
+                rest = &rest[start + end + 2..];
+            }
+        }
+    }
+    Ok(hooks)
+}
+
+/// Name of the hook in `/notify/status`
+pub(super) fn sink(hook: &ExecConfig) -> String {
+    format!("exec {}", hook.command.join(" "))
+}
+
+fn render(arg: &str, event: &Event) -> String {
+    let value = |name: &str| match name {
+        "event" => event.event.to_string(),
+        "user" => event.user.clone(),
+        "device" => event.device.clone().unwrap_or_default(),
+        "message" => event.message.clone(),
+        "timestamp" => event.timestamp.to_string(),
+        _ => String::new(),
+    };
+    let mut out = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").unwrap();
+        out.push_str(&rest[..start]);
+        out.push_str(&value(rest[start + 2..start + end].trim()));
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Run a hook for `event`, log what it printed and remember how it went
+pub(super) async fn run(state: &AppState, hook: &ExecConfig, event: &Event) -> Result<(), String> {
+    let program = &hook.command[0];
+    let timeout = hook.timeout.unwrap_or(TIMEOUT);
+    let child = Command::new(program)
+        .args(hook.command[1..].iter().map(|arg| render(arg, event)))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let result = match time::timeout(time::Duration::from_secs(timeout), child).await {
+        Err(_) => Err(format!("killed after {}s", timeout)),
+        Ok(Err(e)) => Err(format!("cannot run: {}", e)),
+        Ok(Ok(output)) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                info!("{}: {}", program, line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                warn!("{}: {}", program, line);
+            }
+            match output.status.success() {
+                true => Ok(()),
+                false => Err(format!("exited with {}", output.status)),
+            }
+        }
+    };
+    notify::delivered(state, sink(hook), event.event, result.clone());
+    result
+}
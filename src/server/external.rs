@@ -0,0 +1,139 @@
+//! Presence reported by other systems, like router scripts or phone automation apps, at
+//! `POST /external/<source>`
+//!
+//! Each source is a virtual device of its user: `{"online": true}` (or an empty body) counts
+//! as a heartbeat from it, `{"online": false}` takes it away at once.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    bearer_token, ApiError, AppState, User, DEFAULT_USER, MAX_DEVICE_NAME_LEN, MAX_MESSAGE_LEN,
+};
+use crate::config::ExternalSource;
+
+pub(super) fn load(
+    sources: Vec<ExternalSource>,
+    users: &HashMap<String, User>,
+) -> Result<Vec<ExternalSource>, Box<dyn Error>> {
+    let mut names = HashSet::new();
+    for source in &sources {
+        if source.source.is_empty() || source.source.len() > MAX_DEVICE_NAME_LEN {
+            return Err(format!(
+                "External source name {:?} must be 1 to {} bytes",
+                source.source, MAX_DEVICE_NAME_LEN
+            )
+            .into());
+        }
+        if !names.insert(&source.source) {
+            return Err(format!("External source {} is listed twice", source.source).into());
+        }
+        if let Some(user) = &source.user {
+            if !users.contains_key(user) {
+                return Err(format!(
+                    "External source {} belongs to unknown user {}",
+                    source.source, user
+                )
+                .into());
+            }
+        }
+    }
+    Ok(sources)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Signal {
+    #[serde(default = "online")]
+    online: bool,
+    message: Option<String>,
+}
+
+fn online() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub(super) struct SignalQuery {
+    /// Instead of the `Authorization` header, for apps that cannot set one
+    token: Option<String>,
+}
+
+pub(super) async fn signal(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<SignalQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<&'static str, ApiError> {
+    let settings = state.settings();
+    let source = settings
+        .external
+        .iter()
+        .find(|s| s.source == name)
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "source_unknown",
+                format!("No external source {}", name),
+            )
+        })?;
+    let token = query.token.as_deref().or_else(|| bearer_token(&headers));
+    if token != Some(source.token.as_str()) {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "token_invalid",
+            "Unknown token",
+        ));
+    }
+    let signal = match body.is_empty() {
+        true => Signal {
+            online: true,
+            message: None,
+        },
+        false => serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "body_invalid", "Invalid signal")
+                .detail(e.to_string())
+        })?,
+    };
+    if signal
+        .message
+        .as_ref()
+        .is_some_and(|m| m.len() > MAX_MESSAGE_LEN)
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "message_too_long",
+            format!("Message must be at most {} bytes", MAX_MESSAGE_LEN),
+        ));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let user = source.user.as_deref().unwrap_or(DEFAULT_USER);
+    let mut clients = state.lock_clients();
+    let devices = clients.devices_mut(user);
+    if !signal.online {
+        devices.remove(&source.source);
+        return Ok("Signal received");
+    }
+    let entry = devices.entry(source.source.clone()).or_default();
+    if !entry.online(now) {
+        entry.online_since = now;
+    }
+    entry.last_seen = now;
+    entry.timestamp = now;
+    entry.message = signal.message;
+    Ok("Signal received")
+}
@@ -0,0 +1,134 @@
+//! Polling other online_status servers and combining everyone on `/roster`
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+use super::{AppState, DEFAULT_USER};
+use crate::{config::Privacy, HEARTBEAT_INTERVAL, TIMEOUT};
+
+/// Last known status of a peer
+#[derive(Debug, Clone)]
+pub(super) struct PeerStatus {
+    url: String,
+    status: String,
+    updated: u64,
+}
+
+#[derive(Deserialize)]
+struct RemoteReport {
+    status: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct RosterEntry {
+    pub(super) name: String,
+    /// URL of the peer, or none for users of this server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) server: Option<String>,
+    pub(super) status: String,
+    updated: u64,
+}
+
+pub(super) async fn poll_peers(state: AppState) {
+    loop {
+        let settings = state.settings();
+        for peer in &settings.peers {
+            let mut req = state
+                .http
+                .get(&peer.url)
+                .timeout(time::Duration::from_secs(TIMEOUT));
+            if let Some(token) = &peer.token {
+                req = req.bearer_auth(token);
+            }
+            let status = match req.send().await.and_then(|r| r.error_for_status()) {
+                Ok(res) => match res.json::<RemoteReport>().await {
+                    Ok(report) => report.status,
+                    Err(e) => {
+                        warn!("Invalid status from peer {}: {}", peer.name, e);
+                        "UNKNOWN".to_string()
+                    }
+                },
+                Err(e) => {
+                    warn!("Cannot fetch status of peer {}: {}", peer.name, e);
+                    "UNKNOWN".to_string()
+                }
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            state.peers.lock().unwrap().insert(
+                peer.name.clone(),
+                PeerStatus {
+                    url: peer.url.clone(),
+                    status,
+                    updated: now,
+                },
+            );
+        }
+        // Forget peers removed from the config on reload
+        state
+            .peers
+            .lock()
+            .unwrap()
+            .retain(|name, _| settings.peers.iter().any(|p| &p.name == name));
+        let interval = settings.peer_interval.unwrap_or(HEARTBEAT_INTERVAL);
+        drop(settings);
+        time::sleep(time::Duration::from_secs(interval)).await;
+    }
+}
+
+pub(super) async fn roster(State(state): State<AppState>) -> Json<Vec<RosterEntry>> {
+    Json(entries(&state))
+}
+
+/// Everyone on this server whose status is public, followed by the peers
+pub(super) fn entries(state: &AppState) -> Vec<RosterEntry> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let settings = state.settings();
+    let mut local: Vec<_> = settings
+        .users
+        .iter()
+        .filter(|(_, user)| user.privacy != Privacy::Private)
+        .map(|(name, _)| {
+            let display = if name == DEFAULT_USER {
+                settings
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| "default".to_string())
+            } else {
+                name.clone()
+            };
+            RosterEntry {
+                name: display,
+                server: None,
+                status: state
+                    .report(name, now, Privacy::Aggregate)
+                    .status
+                    .to_string(),
+                updated: now,
+            }
+        })
+        .collect();
+    local.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let peers = state.peers.lock().unwrap();
+    let mut remote: Vec<_> = peers
+        .iter()
+        .map(|(name, peer)| RosterEntry {
+            name: name.clone(),
+            server: Some(peer.url.clone()),
+            status: peer.status.clone(),
+            updated: peer.updated,
+        })
+        .collect();
+    remote.sort_by(|a, b| a.name.cmp(&b.name));
+
+    local.extend(remote);
+    local
+}
@@ -0,0 +1,44 @@
+//! Coarse location and network of devices, looked up in local MaxMind databases
+
+use maxminddb::{geoip2, Reader};
+use std::{error::Error, net::IpAddr, path::Path};
+
+#[derive(Debug)]
+pub(super) struct GeoIp {
+    /// GeoIP2/GeoLite2 City or Country database
+    city: Option<Reader<Vec<u8>>>,
+    /// GeoIP2/GeoLite2 ASN database
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub(super) fn load(city: Option<&Path>, asn: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let open = |path: &Path| {
+            Reader::open_readfile(path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        Ok(GeoIp {
+            city: city.map(open).transpose()?,
+            asn: asn.map(open).transpose()?,
+        })
+    }
+
+    /// Where an address is, like `Berlin, DE`
+    pub(super) fn location(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::City = self.city.as_ref()?.lookup(ip).ok()?.decode().ok()??;
+        let country = record.country.iso_code;
+        match (record.city.names.english, country) {
+            (Some(city), Some(country)) => Some(format!("{}, {}", city, country)),
+            (city, country) => city.or(country).map(str::to_string),
+        }
+    }
+
+    /// Which network an address belongs to, like `AS3320 Deutsche Telekom AG`
+    pub(super) fn network(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Asn = self.asn.as_ref()?.lookup(ip).ok()?.decode().ok()??;
+        let number = record.autonomous_system_number?;
+        Some(match record.autonomous_system_organization {
+            Some(organization) => format!("AS{} {}", number, organization),
+            None => format!("AS{}", number),
+        })
+    }
+}
@@ -0,0 +1,122 @@
+//! Keeping a GitHub account's profile status in line with a user's
+//!
+//! A status is only set once it has lasted `debounce` seconds, and at most every
+//! `min_interval` seconds, so a flaky connection does not turn into a stream of updates.
+
+use reqwest::header;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time;
+
+use super::{
+    sink::{PresenceSink, SinkError},
+    AppState, Settings, StatusReport, DEFAULT_USER,
+};
+use crate::{config::GithubConfig, TIMEOUT};
+
+const GITHUB_GRAPHQL: &str = "https://api.github.com/graphql";
+const CHANGE_USER_STATUS: &str = "mutation($input: ChangeUserStatusInput!) {
+    changeUserStatus(input: $input) { clientMutationId }
+}";
+const DEFAULT_DEBOUNCE: u64 = 120;
+const DEFAULT_MIN_INTERVAL: u64 = 300;
+/// Longest status message GitHub takes
+const MAX_MESSAGE_LEN: usize = 80;
+
+#[derive(Hash)]
+pub(super) struct GithubStatus {
+    /// None clears the status
+    message: Option<String>,
+    emoji: Option<String>,
+    /// Shown as "Busy"
+    limited_availability: bool,
+}
+
+#[derive(Deserialize)]
+struct GraphqlResponse {
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Deserialize)]
+struct GraphqlError {
+    message: String,
+}
+
+impl PresenceSink for GithubConfig {
+    const NAME: &'static str = "GitHub";
+    type Status = GithubStatus;
+
+    fn configured(settings: &Settings) -> Option<Self> {
+        settings.github.clone()
+    }
+
+    fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(DEFAULT_USER)
+    }
+
+    fn account(&self) -> &str {
+        &self.token
+    }
+
+    fn debounce(&self) -> u64 {
+        self.debounce.unwrap_or(DEFAULT_DEBOUNCE)
+    }
+
+    fn min_interval(&self) -> u64 {
+        self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL)
+    }
+
+    fn status(&self, report: StatusReport) -> GithubStatus {
+        let (message, emoji, limited_availability) = match &*report.status {
+            "OFFLINE" => (None, None, false),
+            "ONLINE" => match report.message {
+                Some(message) => (
+                    Some(message),
+                    self.message_emoji.as_deref().or(Some(":speech_balloon:")),
+                    false,
+                ),
+                None => (None, None, false),
+            },
+            status => (
+                Some(status.to_string()),
+                self.busy_emoji.as_deref().or(Some(":no_entry:")),
+                true,
+            ),
+        };
+        GithubStatus {
+            message: message.map(|m| m.chars().take(MAX_MESSAGE_LEN).collect()),
+            emoji: emoji.map(str::to_string),
+            limited_availability,
+        }
+    }
+
+    async fn set(&self, state: &AppState, status: &GithubStatus) -> Result<(), SinkError> {
+        let body = json!({
+            "query": CHANGE_USER_STATUS,
+            "variables": {
+                "input": {
+                    "message": status.message,
+                    "emoji": status.emoji,
+                    "limitedAvailability": status.limited_availability,
+                }
+            }
+        });
+        let res: GraphqlResponse = state
+            .http
+            .post(GITHUB_GRAPHQL)
+            .bearer_auth(&self.token)
+            .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .json(&body)
+            .timeout(time::Duration::from_secs(TIMEOUT))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match res.errors.first() {
+            Some(e) => Err(e.message.clone().into()),
+            None => Ok(()),
+        }
+    }
+}
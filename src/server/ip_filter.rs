@@ -0,0 +1,95 @@
+//! Dropping heartbeats from unexpected networks before even parsing them
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use super::{ApiError, AppState};
+
+#[derive(Debug, Default)]
+pub(super) struct IpFilter {
+    /// If not empty, only these networks may send heartbeats
+    pub(super) allow: Vec<IpNet>,
+    /// These may not, even if allowed
+    pub(super) deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+}
+
+pub(super) async fn filter_heartbeats(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !state.settings().heartbeat_filter.permits(addr.ip()) {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "address_denied",
+            "Heartbeats are not accepted from this address",
+        )
+        .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let nets = |nets: &[&str]| nets.iter().map(|n| n.parse().unwrap()).collect();
+        IpFilter {
+            allow: nets(allow),
+            deny: nets(deny),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn everyone_by_default() {
+        let filter = IpFilter::default();
+        assert!(filter.permits(ip("203.0.113.7")));
+        assert!(filter.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = filter(&["10.0.0.0/8", "2001:db8::/32"], &["10.0.66.0/24"]);
+        assert!(filter.permits(ip("10.1.2.3")));
+        assert!(filter.permits(ip("2001:db8::1")));
+        assert!(!filter.permits(ip("10.0.66.1")));
+        assert!(!filter.permits(ip("192.168.1.1")));
+        assert!(!filter.permits(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn deny_only() {
+        let filter = filter(&[], &["192.0.2.0/24"]);
+        assert!(!filter.permits(ip("192.0.2.1")));
+        assert!(filter.permits(ip("192.0.3.1")));
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        // As dual-stack sockets see IPv4 clients
+        let filter = filter(&["10.0.0.0/8"], &["10.0.66.0/24"]);
+        assert!(filter.permits(ip("::ffff:10.1.2.3")));
+        assert!(!filter.permits(ip("::ffff:10.0.66.1")));
+        assert!(!filter.permits(ip("::ffff:192.168.1.1")));
+    }
+}
@@ -0,0 +1,263 @@
+//! An IRC bot that announces events in a channel and answers `!status <user>` there
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::broadcast::error::RecvError,
+    time,
+};
+
+use super::{decided_report, notify, AppState, ViewerCredentials, DEFAULT_USER};
+use crate::config::IrcConfig;
+
+/// Seconds between two looks for a changed configuration
+const IRC_CHECK_INTERVAL: u64 = 60;
+/// Seconds to wait before connecting again after a lost connection
+const IRC_RETRY_INTERVAL: u64 = 30;
+/// Seconds a channel or nick gets no second answer to `!status`, so that the bot cannot be
+/// made to flood the channel and be kicked by the server for it
+const STATUS_COOLDOWN: u64 = 10;
+
+type IrcError = Box<dyn Error + Send + Sync>;
+
+pub(super) async fn run(state: AppState) {
+    loop {
+        let Some(irc) = state.settings().irc.clone() else {
+            time::sleep(time::Duration::from_secs(IRC_CHECK_INTERVAL)).await;
+            continue;
+        };
+        match connect(&state, &irc).await {
+            // The configuration changed
+            Ok(()) => continue,
+            Err(e) => error!("IRC connection to {} failed: {}", irc.server, e),
+        }
+        time::sleep(time::Duration::from_secs(IRC_RETRY_INTERVAL)).await;
+    }
+}
+
+async fn connect(state: &AppState, irc: &IrcConfig) -> Result<(), IrcError> {
+    let port = irc.port.unwrap_or(if irc.plain { 6667 } else { 6697 });
+    let stream = TcpStream::connect((irc.server.as_str(), port)).await?;
+    if irc.plain {
+        return session(state, irc, stream).await;
+    }
+    let connector = tokio_native_tls::native_tls::TlsConnector::new()?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(&irc.server, stream)
+        .await?;
+    session(state, irc, stream).await
+}
+
+/// Talk to the server until the connection is lost or the configuration changes
+async fn session(
+    state: &AppState,
+    irc: &IrcConfig,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+) -> Result<(), IrcError> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let mut events = state.events.subscribe();
+    let mut check = time::interval(time::Duration::from_secs(IRC_CHECK_INTERVAL));
+    check.tick().await;
+
+    let mut nick = irc.nick.clone();
+    let mut cooldown = Cooldown::default();
+    if let Some(password) = &irc.password {
+        send(&mut writer, &format!("PASS {}", password)).await?;
+    }
+    send(&mut writer, &format!("NICK {}", nick)).await?;
+    send(
+        &mut writer,
+        &format!("USER {} 0 * :online_status", irc.nick),
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = line?.ok_or("connection closed")?;
+                let message = Message::parse(&line);
+                match message.command {
+                    "PING" => send(&mut writer, &format!("PONG :{}", message.trailing())).await?,
+                    // Welcome
+                    "001" => {
+                        info!("Connected to IRC server {} as {}", irc.server, nick);
+                        send(&mut writer, &format!("JOIN {}", irc.channel)).await?;
+                    }
+                    // Nickname in use
+                    "433" => {
+                        nick.push('_');
+                        send(&mut writer, &format!("NICK {}", nick)).await?;
+                    }
+                    "PRIVMSG" => {
+                        let target = message.params.first().copied().unwrap_or_default();
+                        let Some(query) = message.trailing().strip_prefix("!status") else {
+                            continue;
+                        };
+                        if !query.is_empty() && !query.starts_with(' ') {
+                            continue;
+                        }
+                        // Replies to private messages go back to the sender
+                        let from = message.nick().unwrap_or(target);
+                        let to = match target.eq_ignore_ascii_case(&nick) {
+                            true => from,
+                            false => target,
+                        };
+                        if !cooldown.allows([to, from], Instant::now()) {
+                            continue;
+                        }
+                        let reply = status_reply(state, query.trim());
+                        send(&mut writer, &format!("PRIVMSG {} :{}", to, reply)).await?;
+                    }
+                    _ => {}
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("IRC bot missed {} events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                if notify::announced(&irc.events, &event) {
+                    let text = event.message.replace(['\r', '\n'], " ");
+                    send(&mut writer, &format!("PRIVMSG {} :{}", irc.channel, text)).await?;
+                }
+            }
+            _ = check.tick() => {
+                if state.settings().irc.as_ref() != Some(irc) {
+                    let _ = send(&mut writer, "QUIT :Reconfigured").await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// The answer to `!status <name>`, as much as the user shows anyone
+fn status_reply(state: &AppState, name: &str) -> String {
+    let user = if name.is_empty() { DEFAULT_USER } else { name };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let privacy = state
+        .settings()
+        .access(user, &ViewerCredentials::anonymous(), now);
+    match privacy {
+        Ok(privacy) => {
+            let report = decided_report(state, user, now, privacy);
+            let status = report.status.to_lowercase();
+            match report.message {
+                Some(message) => format!("{} is {} ({})", name_of(user), status, message),
+                None => format!("{} is {}", name_of(user), status),
+            }
+            .replace(['\r', '\n'], " ")
+        }
+        Err(_) if state.settings().users.contains_key(user) => {
+            format!("{} keeps their status private", name_of(user))
+        }
+        Err(_) => format!("No user {}", user),
+    }
+}
+
+fn name_of(user: &str) -> &str {
+    if user == DEFAULT_USER {
+        "Default user"
+    } else {
+        user
+    }
+}
+
+/// When `!status` was last answered in every channel and to every nick
+#[derive(Default)]
+struct Cooldown(HashMap<String, Instant>);
+
+impl Cooldown {
+    /// Whether to answer in the channel or to the nick of `targets` now, taking note if so
+    fn allows(&mut self, targets: [&str; 2], now: Instant) -> bool {
+        let cooldown = time::Duration::from_secs(STATUS_COOLDOWN);
+        self.0.retain(|_, at| now.duration_since(*at) < cooldown);
+        let targets = targets.map(str::to_ascii_lowercase);
+        if targets.iter().any(|target| self.0.contains_key(target)) {
+            return false;
+        }
+        for target in targets {
+            self.0.insert(target, now);
+        }
+        true
+    }
+}
+
+async fn send(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> Result<(), IrcError> {
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    Ok(())
+}
+
+/// A line from the server, like `:nick!user@host PRIVMSG #channel :text`
+struct Message<'a> {
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(line: &'a str) -> Self {
+        let mut rest = line;
+        let prefix = rest.strip_prefix(':').map(|r| {
+            let (prefix, r) = r.split_once(' ').unwrap_or((r, ""));
+            rest = r;
+            prefix
+        });
+        let mut params = Vec::new();
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing);
+                break;
+            }
+            let (param, r) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param);
+            rest = r;
+        }
+        Message {
+            prefix,
+            command,
+            params,
+        }
+    }
+
+    /// The last parameter, which is the text of messages
+    fn trailing(&self) -> &'a str {
+        self.params.last().copied().unwrap_or_default()
+    }
+
+    fn nick(&self) -> Option<&'a str> {
+        self.prefix.map(|p| p.split('!').next().unwrap_or(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown() {
+        let mut cooldown = Cooldown::default();
+        let start = Instant::now();
+        let later = |seconds| start + time::Duration::from_secs(seconds);
+        assert!(cooldown.allows(["#friends", "bob"], start));
+        // Neither in the same channel nor from the same nick elsewhere
+        assert!(!cooldown.allows(["#friends", "carol"], later(1)));
+        assert!(!cooldown.allows(["Bob", "Bob"], later(1)));
+        assert!(cooldown.allows(["#family", "carol"], later(1)));
+        assert!(cooldown.allows(["#friends", "bob"], later(STATUS_COOLDOWN)));
+    }
+}
@@ -0,0 +1,123 @@
+//! Presence of devices on the local network, for a server running on the
+//! home router or NAS
+//!
+//! A device whose MAC address is in the ARP table, or holds a current DHCP
+//! lease, counts as having sent a heartbeat, so phones and other devices
+//! without a client can make their user online.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{AppState, User};
+use crate::{config::LanConfig, HEARTBEAT_INTERVAL};
+
+const DEFAULT_LAN_INTERVAL: u64 = 30;
+const DEFAULT_ARP_TABLE: &str = "/proc/net/arp";
+
+/// Check the devices and bring their MAC addresses into the form the sources use
+pub(super) fn load(
+    mut lan: LanConfig,
+    users: &HashMap<String, User>,
+) -> Result<LanConfig, Box<dyn Error>> {
+    if lan.arp_table.is_none() && lan.dhcp_leases.is_none() {
+        lan.arp_table = Some(DEFAULT_ARP_TABLE.into());
+    }
+    for device in &mut lan.devices {
+        device.mac = normalize_mac(&device.mac)
+            .ok_or_else(|| format!("Invalid MAC address {:?}", device.mac))?;
+        if let Some(user) = &device.user {
+            if !users.contains_key(user) {
+                return Err(format!(
+                    "LAN device {} belongs to unknown user {}",
+                    device.name, user
+                )
+                .into());
+            }
+        }
+    }
+    Ok(lan)
+}
+
+pub(super) async fn watch(state: AppState) {
+    loop {
+        let settings = state.settings();
+        let Some(lan) = &settings.lan else {
+            drop(settings);
+            time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+            continue;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut present = HashSet::new();
+        if let Some(path) = &lan.arp_table {
+            match fs::read_to_string(path) {
+                Ok(table) => present.extend(arp_macs(&table)),
+                Err(e) => warn!("Cannot read ARP table {}: {}", path.display(), e),
+            }
+        }
+        if let Some(path) = &lan.dhcp_leases {
+            match fs::read_to_string(path) {
+                Ok(leases) => present.extend(lease_macs(&leases, now)),
+                Err(e) => warn!("Cannot read DHCP leases {}: {}", path.display(), e),
+            }
+        }
+
+        for device in lan.devices.iter().filter(|d| present.contains(&d.mac)) {
+            let mut clients = state.lock_clients();
+            let entry = clients
+                .devices_mut(device.user.as_deref().unwrap_or_default())
+                .entry(device.name.clone())
+                .or_default();
+            if !entry.online(now) {
+                entry.online_since = now;
+            }
+            entry.last_seen = now;
+        }
+
+        let interval = lan.interval.unwrap_or(DEFAULT_LAN_INTERVAL);
+        drop(settings);
+        time::sleep(time::Duration::from_secs(interval)).await;
+    }
+}
+
+/// Lower-case, colon-separated, from either colons or dashes
+fn normalize_mac(mac: &str) -> Option<String> {
+    let parts: Vec<_> = mac.split([':', '-']).collect();
+    let valid = parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()));
+    valid.then(|| parts.join(":").to_ascii_lowercase())
+}
+
+/// Complete entries of `/proc/net/arp`: IP address, HW type, flags, HW address, mask, device
+fn arp_macs(table: &str) -> impl Iterator<Item = String> + '_ {
+    table.lines().skip(1).filter_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [_, _, flags, mac, ..] if flags != "0x0" => normalize_mac(mac),
+            _ => None,
+        }
+    })
+}
+
+/// Unexpired dnsmasq leases: expiry (0 for never), MAC, IP address, host name, client ID
+fn lease_macs(leases: &str, now: u64) -> impl Iterator<Item = String> + '_ {
+    leases.lines().filter_map(move |line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [expiry, mac, ..] => match expiry.parse::<u64>() {
+                Ok(expiry) if expiry == 0 || expiry > now => normalize_mac(mac),
+                _ => None,
+            },
+            _ => None,
+        }
+    })
+}
@@ -0,0 +1,97 @@
+//! Binding the server's port and leaving it again, for redeploys without downtime
+//!
+//! With `reuse_port`, a new server (like in the next container, sharing the host's network)
+//! can bind the port while the old one still answers. A socket passed in by systemd or
+//! another supervisor (`LISTEN_FDS`) is taken over instead of binding at all. On SIGTERM or
+//! SIGINT, even as PID 1 of a container, the server stops accepting connections and gives
+//! those open `drain_timeout` seconds to finish.
+
+use std::{error::Error, io, net::SocketAddr};
+use tokio::net::{TcpListener, TcpSocket};
+
+pub(super) const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
+/// Connections waiting to be accepted, like with `TcpListener::bind`
+const BACKLOG: u32 = 1024;
+
+/// The socket handed over by the supervisor, by the `sd_listen_fds` convention
+#[cfg(unix)]
+fn inherited() -> io::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    /// First file descriptor passed, after stdin, stdout and stderr
+    const LISTEN_FDS_START: i32 = 3;
+    let ours = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = std::env::var("LISTEN_FDS").ok();
+    if !ours || count.is_none() {
+        return Ok(None);
+    }
+    if count.as_deref() != Some("1") {
+        return Err(io::Error::other(
+            "Only one socket can be passed in LISTEN_FDS",
+        ));
+    }
+    // The variables are left alone, since changing the environment is not safe once other threads
+    // run. Children do not take the socket over anyway: LISTEN_PID names this process, and the
+    // descriptor is closed when they start.
+    // SAFETY: the supervisor passed this descriptor as an open socket, and nothing else owns it
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn inherited() -> io::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+pub(super) fn check_reuse_port(reuse_port: bool) -> Result<(), Box<dyn Error>> {
+    if reuse_port && !cfg!(unix) {
+        return Err("reuse_port is only supported on Unix".into());
+    }
+    Ok(())
+}
+
+pub(super) fn bind(port: u16, reuse_port: bool) -> io::Result<TcpListener> {
+    if let Some(listener) = inherited()? {
+        info!("Taking over the socket passed by the supervisor");
+        return TcpListener::from_std(listener);
+    }
+    let socket = TcpSocket::new_v4()?;
+    #[cfg(unix)]
+    {
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(reuse_port)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+    socket.listen(BACKLOG)
+}
+
+/// When the server is asked to stop
+pub(super) async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("SIGTERM received, shutting down"),
+                    _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+                }
+                return;
+            }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_ok() {
+        info!("Interrupted, shutting down");
+    } else {
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,196 @@
+//! A Matrix bot that announces events in a room and can mirror a user's status as the
+//! presence of its own account, through matrix-sdk
+
+use matrix_sdk::{
+    authentication::matrix::MatrixSession,
+    ruma::{
+        api::client::presence::set_presence, events::room::message::RoomMessageEventContent,
+        presence::PresenceState, OwnedUserId, RoomId,
+    },
+    Client, SessionMeta, SessionTokens,
+};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::broadcast::error::RecvError, time};
+
+use super::{decided_report, notify, AppState, ViewerCredentials};
+use crate::{config::MatrixConfig, HEARTBEAT_INTERVAL, TIMEOUT};
+
+/// Device of the session when the access token belongs to none
+const DEFAULT_DEVICE_ID: &str = "ONLINE_STATUS";
+
+type MatrixError = Box<dyn Error + Send + Sync>;
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+    device_id: Option<String>,
+}
+
+/// The client logged in to the bot account, and the homeserver and access token it is for
+struct Bot {
+    homeserver: String,
+    access_token: String,
+    client: Client,
+}
+
+impl Bot {
+    fn is_for(&self, matrix: &MatrixConfig) -> bool {
+        self.homeserver == matrix.homeserver && self.access_token == matrix.access_token
+    }
+}
+
+pub(super) async fn run(state: AppState) {
+    let mut events = state.events.subscribe();
+    let mut presence = time::interval(time::Duration::from_secs(HEARTBEAT_INTERVAL));
+    let mut bot: Option<Bot> = None;
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Matrix bot missed {} events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let Some(matrix) = state.settings().matrix.clone() else {
+                    continue;
+                };
+                if matrix.presence.as_ref() == Some(&event.user) {
+                    presence.reset_immediately();
+                }
+                let Some(room) = &matrix.room else {
+                    continue;
+                };
+                if !notify::announced(&matrix.events, &event) {
+                    continue;
+                }
+                let client = match login(&state, &matrix, &mut bot).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Cannot log in to Matrix: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = send(&client, room, &event.message).await {
+                    error!("Sending to Matrix room {} failed: {}", room, e);
+                }
+            }
+            _ = presence.tick() => {
+                let Some(matrix) = state.settings().matrix.clone() else {
+                    continue;
+                };
+                let Some(user) = &matrix.presence else {
+                    continue;
+                };
+                let client = match login(&state, &matrix, &mut bot).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Cannot log in to Matrix: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = set_presence(&state, &client, user).await {
+                    error!("Setting the Matrix presence failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The client of the bot account, made again when the configuration names another one
+async fn login(
+    state: &AppState,
+    matrix: &MatrixConfig,
+    bot: &mut Option<Bot>,
+) -> Result<Client, MatrixError> {
+    if let Some(bot) = bot.as_ref().filter(|bot| bot.is_for(matrix)) {
+        return Ok(bot.client.clone());
+    }
+    let client = Client::builder()
+        .homeserver_url(&matrix.homeserver)
+        .http_client(state.http.clone())
+        .build()
+        .await?;
+    // The SDK only takes up a session whose account and device it knows, and an access token
+    // does not tell them by itself
+    let who = whoami(state, matrix).await?;
+    let session = MatrixSession {
+        meta: SessionMeta {
+            user_id: OwnedUserId::try_from(who.user_id)?,
+            device_id: who.device_id.as_deref().unwrap_or(DEFAULT_DEVICE_ID).into(),
+        },
+        tokens: SessionTokens {
+            access_token: matrix.access_token.clone(),
+            refresh_token: None,
+        },
+    };
+    client.restore_session(session).await?;
+    *bot = Some(Bot {
+        homeserver: matrix.homeserver.clone(),
+        access_token: matrix.access_token.clone(),
+        client: client.clone(),
+    });
+    Ok(client)
+}
+
+async fn whoami(state: &AppState, matrix: &MatrixConfig) -> Result<WhoAmI, MatrixError> {
+    let mut url = reqwest::Url::parse(&matrix.homeserver)?;
+    url.path_segments_mut()
+        .map_err(|_| "Invalid homeserver URL")?
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3", "account", "whoami"]);
+    let res = state
+        .http
+        .get(url)
+        .bearer_auth(&matrix.access_token)
+        .timeout(time::Duration::from_secs(TIMEOUT))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.json().await?)
+}
+
+async fn send(client: &Client, room: &str, text: &str) -> Result<(), MatrixError> {
+    let room_id = <&RoomId>::try_from(room)?;
+    // Rooms are only known once joined, which the bot may have done before it started
+    let room = match client.get_room(room_id) {
+        Some(room) => room,
+        None => client.join_room_by_id(room_id).await?,
+    };
+    room.send(RoomMessageEventContent::notice_plain(text))
+        .await?;
+    Ok(())
+}
+
+/// Make the presence of the bot account what anonymous viewers see of `user`
+async fn set_presence(state: &AppState, client: &Client, user: &str) -> Result<(), MatrixError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let privacy = state
+        .settings()
+        .access(user, &ViewerCredentials::anonymous(), now)
+        .map_err(|_| format!("The status of {:?} is not public", user))?;
+    let report = decided_report(state, user, now, privacy);
+    // Statuses like "BUSY (in a meeting)" are told in the status message
+    let (presence, status_msg) = match &*report.status {
+        "ONLINE" => (PresenceState::Online, report.message),
+        "OFFLINE" => (PresenceState::Offline, None),
+        status => (
+            PresenceState::Unavailable,
+            Some(report.message.unwrap_or(status.to_string())),
+        ),
+    };
+    let id = client.user_id().ok_or("Not logged in")?.to_owned();
+    let mut request = set_presence::v3::Request::new(id, presence);
+    request.status_msg = Some(status_msg.unwrap_or_default());
+    client.send(request).await?;
+    Ok(())
+}
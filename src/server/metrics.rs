@@ -0,0 +1,211 @@
+//! Prometheus metrics on `/metrics`: how long requests and signature checks take, how many
+//! heartbeats were accepted, and the system metrics online devices report
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{signature_cache::SignatureCache, AppState, ClientMap};
+use crate::SystemMetrics;
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    verification: Mutex<Histogram>,
+    /// By method and route
+    requests: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// Accepted heartbeats, by user
+    heartbeats: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    /// Record how long checking a heartbeat's signature took
+    pub(super) fn observe_verification(&self, duration: Duration) {
+        self.verification.lock().unwrap().observe(duration);
+    }
+
+    pub(super) fn count_heartbeat(&self, user: &str) {
+        *self
+            .heartbeats
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default() += 1;
+    }
+
+    /// Heartbeats accepted so far, by user
+    pub(super) fn heartbeats(&self) -> BTreeMap<String, u64> {
+        self.heartbeats.lock().unwrap().clone()
+    }
+
+    fn render(&self, cache: &SignatureCache, clients: &ClientMap) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP online_status_signature_verification_seconds Time spent checking heartbeat signatures\n");
+        out.push_str("# TYPE online_status_signature_verification_seconds histogram\n");
+        self.verification.lock().unwrap().render(
+            &mut out,
+            "online_status_signature_verification_seconds",
+            "",
+        );
+        for (name, count) in [("hits", &cache.hits), ("misses", &cache.misses)] {
+            let name = format!("online_status_signature_cache_{}_total", name);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, count.load(Ordering::Relaxed));
+        }
+        out.push_str(
+            "# HELP online_status_request_duration_seconds Time spent handling requests\n",
+        );
+        out.push_str("# TYPE online_status_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in self.requests.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",route=\"{}\",", method, route);
+            histogram.render(&mut out, "online_status_request_duration_seconds", &labels);
+        }
+        out.push_str("# HELP online_status_heartbeats_total Accepted heartbeats\n");
+        out.push_str("# TYPE online_status_heartbeats_total counter\n");
+        for (user, count) in self.heartbeats.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "online_status_heartbeats_total{{user=\"{}\"}} {}",
+                escape(user),
+                count
+            );
+        }
+        render_devices(&mut out, clients);
+        out
+    }
+}
+
+/// Label values are quoted
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Gauges of what online devices report with `--system-metrics`, leaving out offline ones so
+/// their last values do not linger
+fn render_devices(out: &mut String, clients: &ClientMap) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    type Value = fn(&SystemMetrics) -> Option<f64>;
+    let gauges: [(&str, &str, Value); 3] = [
+        ("load1", "Load average over one minute", |m| m.load),
+        ("memory_used_percent", "Share of memory in use", |m| {
+            m.memory_percent
+        }),
+        ("disk_used_percent", "Share of the disk in use", |m| {
+            m.disk_percent
+        }),
+    ];
+    let mut devices: Vec<_> = clients
+        .iter()
+        .flat_map(|(user, devices)| devices.iter().map(move |(name, d)| (user, name, d)))
+        .filter(|(_, _, d)| d.online(now))
+        .filter_map(|(user, name, d)| Some((user, name, d.metrics.as_ref()?)))
+        .collect();
+    devices.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    for (gauge, help, value) in gauges {
+        let name = format!("online_status_device_{}", gauge);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (user, device, metrics) in &devices {
+            if let Some(value) = value(metrics) {
+                let _ = writeln!(
+                    out,
+                    "{}{{user=\"{}\",device=\"{}\"}} {}",
+                    name,
+                    escape(user),
+                    escape(device),
+                    value
+                );
+            }
+        }
+    }
+}
+
+/// Time every request, labelled with its route rather than its path, to keep
+/// the number of series bounded
+pub(super) async fn track_request(
+    State(state): State<AppState>,
+    route: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().to_string();
+    let route = route.map_or("unmatched".to_string(), |r| r.as_str().to_string());
+    let start = Instant::now();
+    let res = next.run(req).await;
+    state
+        .metrics
+        .requests
+        .lock()
+        .unwrap()
+        .entry((method, route))
+        .or_default()
+        .observe(start.elapsed());
+    res
+}
+
+pub(super) async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state
+            .metrics
+            .render(&state.settings().signature_cache, &state.snapshot()),
+    )
+}
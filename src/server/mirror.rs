@@ -0,0 +1,47 @@
+//! Read-only mirrors, serving the status of a primary server they poll
+//!
+//! A mirror receives no heartbeats. It fetches the primary's client map,
+//! like cluster nodes exchange it, and computes the status from it with its
+//! own configuration, so it can run on a public host of its own.
+
+use tokio::time;
+
+use super::{AppState, ClientMap};
+use crate::{HEARTBEAT_INTERVAL, TIMEOUT};
+
+const DEFAULT_MIRROR_INTERVAL: u64 = 10;
+
+pub(super) async fn poll(state: AppState) {
+    loop {
+        let settings = state.settings();
+        let Some(mirror) = &settings.mirror else {
+            drop(settings);
+            time::sleep(time::Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+            continue;
+        };
+        let res = state
+            .http
+            .get(format!(
+                "{}/cluster/sync",
+                mirror.primary.trim_end_matches('/')
+            ))
+            .bearer_auth(&mirror.secret)
+            .timeout(time::Duration::from_secs(TIMEOUT))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match res {
+            Ok(res) => match res.json::<ClientMap>().await {
+                Ok(mut remote) => {
+                    remote.retain(|user, _| settings.users.contains_key(user));
+                    *state.lock_clients() = remote;
+                }
+                Err(e) => warn!("Invalid clients from primary {}: {}", mirror.primary, e),
+            },
+            Err(e) => warn!("Cannot poll primary {}: {}", mirror.primary, e),
+        }
+        let interval = mirror.interval.unwrap_or(DEFAULT_MIRROR_INTERVAL);
+        drop(settings);
+        time::sleep(time::Duration::from_secs(interval)).await;
+    }
+}
@@ -0,0 +1,107 @@
+//! Publishing every accepted heartbeat to a NATS subject, as JSON, for systems that build
+//! their own analytics on them
+//!
+//! Goes through async-nats, which also reconnects and keeps what is published meanwhile, up to
+//! a limit; heartbeats accepted before the first connection is up are not published.
+
+use async_nats::ConnectOptions;
+use serde::Serialize;
+use std::error::Error;
+use tokio::{sync::broadcast::error::RecvError, time};
+
+use super::AppState;
+use crate::{config::NatsConfig, DeviceState};
+
+/// Heartbeats the publisher may fall behind by before it drops some
+pub(super) const RECEIPT_BACKLOG: usize = 1024;
+const DEFAULT_PORT: u16 = 4222;
+const DEFAULT_SUBJECT: &str = "online_status.heartbeats";
+/// Seconds between two looks for a changed configuration
+const NATS_CHECK_INTERVAL: u64 = 60;
+/// Seconds to wait before connecting again after a lost connection
+const NATS_RETRY_INTERVAL: u64 = 30;
+
+type NatsError = Box<dyn Error + Send + Sync>;
+
+/// What is published of an accepted heartbeat
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Receipt {
+    pub(super) user: String,
+    pub(super) device: String,
+    /// When the server accepted it
+    pub(super) timestamp: u64,
+    /// When the client sent it
+    pub(super) client_timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) place: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) interval: Option<u64>,
+    pub(super) device_state: DeviceState,
+}
+
+pub(super) async fn run(state: AppState) {
+    loop {
+        let Some(nats) = state.settings().nats.clone() else {
+            time::sleep(time::Duration::from_secs(NATS_CHECK_INTERVAL)).await;
+            continue;
+        };
+        match session(&state, &nats).await {
+            // The configuration changed
+            Ok(()) => continue,
+            Err(e) => error!("NATS connection to {} failed: {}", nats.server, e),
+        }
+        time::sleep(time::Duration::from_secs(NATS_RETRY_INTERVAL)).await;
+    }
+}
+
+/// Publish until the connection is lost for good or the configuration changes
+async fn session(state: &AppState, nats: &NatsConfig) -> Result<(), NatsError> {
+    let mut options = ConnectOptions::new()
+        .name("online_status")
+        .require_tls(nats.tls);
+    if let Some(token) = &nats.token {
+        options = options.token(token.clone());
+    }
+    if let (Some(user), Some(password)) = (&nats.user, &nats.password) {
+        options = options.user_and_password(user.clone(), password.clone());
+    }
+    if let Some(path) = &nats.credentials {
+        options = options.credentials_file(path).await?;
+    }
+    let address = if nats.server.contains("://") {
+        nats.server.clone()
+    } else {
+        format!("{}:{}", nats.server, nats.port.unwrap_or(DEFAULT_PORT))
+    };
+    let client = options.connect(address).await?;
+    info!("Connected to NATS server {}", nats.server);
+    let subject = nats.subject.clone().unwrap_or(DEFAULT_SUBJECT.to_string());
+    let mut receipts = state.receipts.subscribe();
+    let mut check = time::interval(time::Duration::from_secs(NATS_CHECK_INTERVAL));
+    check.tick().await;
+
+    loop {
+        tokio::select! {
+            receipt = receipts.recv() => {
+                let receipt = match receipt {
+                    Ok(receipt) => receipt,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("NATS publisher missed {} heartbeats", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                let payload = serde_json::to_vec(&receipt)?;
+                client.publish(subject.clone(), payload.into()).await?;
+            }
+            _ = check.tick() => {
+                if state.settings().nats.as_ref() != Some(nats) {
+                    client.drain().await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
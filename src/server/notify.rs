@@ -0,0 +1,228 @@
+//! Sending events to the configured webhooks, and how that went lately
+
+use axum::{extract::State, http::HeaderMap, Json};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{authorize_admin, exec, sink::PresenceSink, ApiError, AppState, DEFAULT_USER};
+use crate::{
+    config::{GithubConfig, SlackConfig},
+    TIMEOUT,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Event {
+    /// What happened, e.g. `device_flaky`
+    pub(super) event: &'static str,
+    pub(super) user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) device: Option<String>,
+    /// Human-readable description
+    pub(super) message: String,
+    pub(super) timestamp: u64,
+}
+
+impl Event {
+    /// An event about `user` (and one of their devices), described by `what`
+    /// as in "laptop <what>"
+    pub(super) fn new(event: &'static str, user: &str, device: Option<&str>, what: &str) -> Self {
+        let subject = match (user, device) {
+            (DEFAULT_USER, Some(device)) => device.to_string(),
+            (DEFAULT_USER, None) => "Default user".to_string(),
+            (user, Some(device)) => format!("{}/{}", user, device),
+            (user, None) => user.to_string(),
+        };
+        Event {
+            event,
+            user: user.to_string(),
+            device: device.map(str::to_string),
+            message: format!("{} {}", subject, what),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+impl Event {
+    /// What `POST /notify/test` and `check --notify` send
+    pub(super) fn test() -> Self {
+        Event {
+            event: "test",
+            user: DEFAULT_USER.to_string(),
+            device: None,
+            message: "Test notification from online_status".to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+/// The outcome of the latest delivery to a sink
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Delivery {
+    /// Event sent, or `status` for the services whose status is set
+    event: &'static str,
+    timestamp: u64,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Remember how delivering `event` to `sink`, like `webhook <url>` or `slack`, went
+pub(super) fn delivered(
+    state: &AppState,
+    sink: String,
+    event: &'static str,
+    result: Result<(), String>,
+) {
+    let delivery = Delivery {
+        event,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        ok: result.is_ok(),
+        error: result.err(),
+    };
+    state.deliveries.lock().unwrap().insert(sink, delivery);
+}
+
+fn webhook(url: &str) -> String {
+    format!("webhook {}", url)
+}
+
+/// Send `event` to a webhook and remember how it went
+async fn post(state: &AppState, url: &str, event: &Event) -> Result<(), String> {
+    let result = state
+        .http
+        .post(url)
+        .json(event)
+        .timeout(time::Duration::from_secs(TIMEOUT))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    delivered(state, webhook(url), event.event, result.clone());
+    result
+}
+
+/// Events the bots announce unless configured otherwise
+const DEFAULT_ANNOUNCED: [&str; 2] = ["user_online", "user_offline"];
+
+/// Whether a bot subscribed to `events` announces `event`
+pub(super) fn announced(events: &[String], event: &Event) -> bool {
+    match events.is_empty() {
+        true => DEFAULT_ANNOUNCED.contains(&event.event),
+        false => events.iter().any(|e| e == event.event),
+    }
+}
+
+/// Send an event to every webhook subscribed to it, in the background
+pub(super) fn notify(state: &AppState, event: Event) {
+    info!("Event: {}", event.message);
+    let _ = state.events.send(event.clone());
+    let settings = state.settings();
+    #[cfg(feature = "plugins")]
+    if let Some(plugins) = &settings.plugins {
+        let plugins = plugins.clone();
+        let event = event.clone();
+        tokio::task::spawn_blocking(move || plugins.notify(&event));
+    }
+    for hook in &settings.notify {
+        if !hook.events.is_empty() && !hook.events.iter().any(|e| e == event.event) {
+            continue;
+        }
+        let (state, url, event) = (state.clone(), hook.url.clone(), event.clone());
+        tokio::spawn(async move {
+            if let Err(e) = post(&state, &url, &event).await {
+                error!("Notifying {} failed: {}", url, e);
+            }
+        });
+    }
+    for hook in &settings.exec {
+        if !hook.events.is_empty() && !hook.events.iter().any(|e| e == event.event) {
+            continue;
+        }
+        let (state, hook, event) = (state.clone(), hook.clone(), event.clone());
+        tokio::spawn(async move {
+            if let Err(e) = exec::run(&state, &hook, &event).await {
+                error!("Exec hook {} failed: {}", hook.command[0], e);
+            }
+        });
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct TestResult {
+    sink: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Send a `test` event to every webhook and exec hook, whatever events it is subscribed to
+pub(super) async fn test(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TestResult>>, ApiError> {
+    let settings = state.settings();
+    authorize_admin(&headers, &state)?;
+    let event = Event::test();
+    let mut results = Vec::new();
+    for hook in &settings.notify {
+        let result = post(&state, &hook.url, &event).await;
+        results.push(TestResult {
+            sink: webhook(&hook.url),
+            ok: result.is_ok(),
+            error: result.err(),
+        });
+    }
+    for hook in &settings.exec {
+        let result = exec::run(&state, hook, &event).await;
+        results.push(TestResult {
+            sink: exec::sink(hook),
+            ok: result.is_ok(),
+            error: result.err(),
+        });
+    }
+    Ok(Json(results))
+}
+
+/// The latest delivery to every configured sink, None for the ones that got none yet
+pub(super) fn sinks(state: &AppState) -> BTreeMap<String, Option<Delivery>> {
+    let settings = state.settings();
+    let mut sinks: Vec<String> = settings.notify.iter().map(|h| webhook(&h.url)).collect();
+    sinks.extend(settings.exec.iter().map(exec::sink));
+    if SlackConfig::configured(&settings).is_some() {
+        sinks.push(SlackConfig::NAME.to_string());
+    }
+    if GithubConfig::configured(&settings).is_some() {
+        sinks.push(GithubConfig::NAME.to_string());
+    }
+    let deliveries = state.deliveries.lock().unwrap();
+    sinks
+        .into_iter()
+        .map(|sink| {
+            let delivery = deliveries.get(&sink).cloned();
+            (sink, delivery)
+        })
+        .collect()
+}
+
+/// The latest delivery to every configured sink, null for the ones that got none yet
+pub(super) async fn status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<BTreeMap<String, Option<Delivery>>>, ApiError> {
+    authorize_admin(&headers, &state)?;
+    Ok(Json(sinks(&state)))
+}
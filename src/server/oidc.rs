@@ -0,0 +1,396 @@
+//! Logging in to the dashboard and admin API with an OpenID Connect provider, instead of the
+//! `admin_token`
+//!
+//! `/login` sends the browser to the provider, which sends it back to `/login/callback` with a
+//! code. The server trades that at the token endpoint for an ID token, and lets in the admins
+//! named in the config with a session cookie. As the ID token comes straight from the provider
+//! over TLS, its claims are checked but not its signature, as OpenID Connect allows for this
+//! flow. So the issuer and its endpoints have to be HTTPS URLs, unless on this machine.
+//! `/logout` ends the session.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{ApiError, AppState};
+use crate::{config::OidcConfig, TIMEOUT};
+
+const SESSION_COOKIE: &str = "online_status_session";
+/// Binds a login to the browser that started it
+const LOGIN_COOKIE: &str = "online_status_login";
+const DEFAULT_SESSION_HOURS: u64 = 12;
+/// Seconds a login may take at the provider
+const LOGIN_TIMEOUT: u64 = 10 * 60;
+
+pub(super) fn load(oidc: OidcConfig) -> Result<OidcConfig, Box<dyn Error>> {
+    if oidc.admins.is_empty() {
+        return Err("OIDC login needs at least one of admins".into());
+    }
+    if !oidc.redirect_url.ends_with("/login/callback") {
+        return Err("OIDC redirect_url must end with /login/callback".into());
+    }
+    secure(&oidc.issuer).map_err(|e| format!("OIDC issuer {}", e))?;
+    Ok(oidc)
+}
+
+/// Whether `url` is HTTPS, or HTTP to this machine, so that tokens are not sent in the clear
+fn secure(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+    let loopback = parsed.host_str().is_some_and(|host| {
+        host == "localhost"
+            || host
+                .trim_matches(['[', ']'])
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback())
+    });
+    match parsed.scheme() {
+        "https" => Ok(()),
+        "http" if loopback => Ok(()),
+        _ => Err(format!("{} is not an HTTPS URL", url)),
+    }
+}
+
+#[derive(Debug)]
+struct Login {
+    nonce: String,
+    started: u64,
+}
+
+#[derive(Debug)]
+struct Session {
+    /// Who logged in, by email or subject
+    admin: String,
+    expires: u64,
+}
+
+/// Logins in progress and sessions, by their random IDs
+#[derive(Debug, Default)]
+pub(super) struct Sessions {
+    logins: HashMap<String, Login>,
+    sessions: HashMap<String, Session>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn random_id() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+fn set_cookie(oidc: &OidcConfig, name: &str, value: &str, max_age: u64) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        name,
+        value,
+        max_age,
+        if oidc.redirect_url.starts_with("https:") {
+            "; Secure"
+        } else {
+            ""
+        }
+    )
+}
+
+/// The admin logged in with the request's session cookie, if any
+pub(super) fn admin(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    state.settings().oidc.as_ref()?;
+    let id = cookie(headers, SESSION_COOKIE)?;
+    let sessions = state.sessions.lock().unwrap();
+    sessions
+        .sessions
+        .get(id)
+        .filter(|s| s.expires > now())
+        .map(|s| s.admin.clone())
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    end_session_endpoint: Option<String>,
+}
+
+async fn discover(state: &AppState, oidc: &OidcConfig) -> Result<Discovery, String> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        oidc.issuer.trim_end_matches('/')
+    );
+    let discovery: Discovery = state
+        .http
+        .get(&url)
+        .timeout(time::Duration::from_secs(TIMEOUT))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("{}: {}", url, e))?
+        .json()
+        .await
+        .map_err(|e| format!("{}: {}", url, e))?;
+    [&discovery.authorization_endpoint, &discovery.token_endpoint]
+        .into_iter()
+        .chain(&discovery.end_session_endpoint)
+        .try_for_each(|endpoint| secure(endpoint))?;
+    Ok(discovery)
+}
+
+fn not_configured() -> ApiError {
+    StatusCode::NOT_FOUND.into()
+}
+
+fn provider_failed(e: String) -> ApiError {
+    error!("OIDC login failed: {}", e);
+    ApiError::new(
+        StatusCode::BAD_GATEWAY,
+        "oidc_failed",
+        "Cannot talk to the identity provider",
+    )
+    .detail(e)
+}
+
+pub(super) async fn login(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let settings = state.settings();
+    let oidc = settings.oidc.as_ref().ok_or_else(not_configured)?;
+    let discovery = discover(&state, oidc).await.map_err(provider_failed)?;
+    let (id, nonce) = (random_id(), random_id());
+    {
+        let mut sessions = state.sessions.lock().unwrap();
+        let now = now();
+        sessions
+            .logins
+            .retain(|_, l| now < l.started + LOGIN_TIMEOUT);
+        sessions.sessions.retain(|_, s| now < s.expires);
+        sessions.logins.insert(
+            id.clone(),
+            Login {
+                nonce: nonce.clone(),
+                started: now,
+            },
+        );
+    }
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &oidc.client_id),
+            ("redirect_uri", &oidc.redirect_url),
+            ("scope", "openid email"),
+            ("state", &id),
+            ("nonce", &nonce),
+        ],
+    )
+    .map_err(|e| provider_failed(e.to_string()))?;
+    Ok((
+        [(
+            header::SET_COOKIE,
+            set_cookie(oidc, LOGIN_COOKIE, &id, LOGIN_TIMEOUT),
+        )],
+        Redirect::to(url.as_str()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub(super) struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+/// The claims of an ID token, once they are found to be meant for this login
+fn claims(oidc: &OidcConfig, id_token: &str, nonce: &str) -> Result<Claims, String> {
+    let payload = id_token.split('.').nth(1).ok_or("ID token is no JWT")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|e| format!("ID token: {}", e))?;
+    let claims: Claims =
+        serde_json::from_slice(&payload).map_err(|e| format!("ID token: {}", e))?;
+    if claims.iss.trim_end_matches('/') != oidc.issuer.trim_end_matches('/') {
+        return Err(format!("ID token from another issuer, {}", claims.iss));
+    }
+    let meant = match &claims.aud {
+        Audience::One(aud) => *aud == oidc.client_id,
+        Audience::Many(auds) => auds.contains(&oidc.client_id),
+    };
+    if !meant {
+        return Err("ID token for another client".to_string());
+    }
+    if claims.exp <= now() {
+        return Err("ID token expired".to_string());
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err("ID token for another login".to_string());
+    }
+    Ok(claims)
+}
+
+pub(super) async fn callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, ApiError> {
+    let settings = state.settings();
+    let oidc = settings.oidc.as_ref().ok_or_else(not_configured)?;
+    if let Some(e) = query.error {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "login_failed", "Login failed").detail(e));
+    }
+    let (Some(code), Some(id)) = (query.code, query.state) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let login = match cookie(&headers, LOGIN_COOKIE) == Some(id.as_str()) {
+        true => state.sessions.lock().unwrap().logins.remove(&id),
+        false => None,
+    };
+    let Some(login) = login.filter(|l| now() < l.started + LOGIN_TIMEOUT) else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "login_unknown",
+            "Login expired or started elsewhere, try again",
+        ));
+    };
+
+    let discovery = discover(&state, oidc).await.map_err(provider_failed)?;
+    let token: TokenResponse = state
+        .http
+        .post(&discovery.token_endpoint)
+        .basic_auth(&oidc.client_id, Some(&oidc.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &oidc.redirect_url),
+        ])
+        .timeout(time::Duration::from_secs(TIMEOUT))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| provider_failed(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| provider_failed(e.to_string()))?;
+    let claims = claims(oidc, &token.id_token, &login.nonce).map_err(provider_failed)?;
+
+    // Anyone could claim an admin's address at some providers
+    let email = claims.email.filter(|_| claims.email_verified == Some(true));
+    let Some(admin) = [email, Some(claims.sub)]
+        .into_iter()
+        .flatten()
+        .find(|who| oidc.admins.contains(who))
+    else {
+        warn!("OIDC login of someone who is no admin");
+        return Err(StatusCode::FORBIDDEN.into());
+    };
+    info!("{} logged in", admin);
+    let hours = oidc.session_hours.unwrap_or(DEFAULT_SESSION_HOURS);
+    let id = random_id();
+    state.sessions.lock().unwrap().sessions.insert(
+        id.clone(),
+        Session {
+            admin,
+            expires: now() + hours * 60 * 60,
+        },
+    );
+    let dashboard = oidc
+        .redirect_url
+        .trim_end_matches("/login/callback")
+        .to_string()
+        + "/dashboard";
+    Ok((
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                set_cookie(oidc, SESSION_COOKIE, &id, hours * 60 * 60),
+            ),
+            (header::SET_COOKIE, set_cookie(oidc, LOGIN_COOKIE, "", 0)),
+        ]),
+        Redirect::to(&dashboard),
+    )
+        .into_response())
+}
+
+/// End the session, and the one at the provider if it supports that
+pub(super) async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let settings = state.settings();
+    let Some(oidc) = settings.oidc.as_ref() else {
+        return ApiError::from(StatusCode::NOT_FOUND).into_response();
+    };
+    if let Some(id) = cookie(&headers, SESSION_COOKIE) {
+        if let Some(session) = state.sessions.lock().unwrap().sessions.remove(id) {
+            info!("{} logged out", session.admin);
+        }
+    }
+    let clear = [(header::SET_COOKIE, set_cookie(oidc, SESSION_COOKIE, "", 0))];
+    let end_session = discover(&state, oidc)
+        .await
+        .ok()
+        .and_then(|d| d.end_session_endpoint)
+        .and_then(|url| {
+            reqwest::Url::parse_with_params(&url, [("client_id", &oidc.client_id)]).ok()
+        });
+    match end_session {
+        Some(url) => (clear, Redirect::to(url.as_str())).into_response(),
+        None => (clear, "Logged out").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secure_urls() {
+        assert!(secure("https://accounts.example.com").is_ok());
+        assert!(secure("http://localhost:8080/realms/main").is_ok());
+        assert!(secure("http://127.0.0.1:5556").is_ok());
+        assert!(secure("http://[::1]:5556").is_ok());
+        assert!(secure("http://accounts.example.com").is_err());
+        assert!(secure("http://localhost.example.com").is_err());
+        assert!(secure("ftp://localhost").is_err());
+        assert!(secure("accounts.example.com").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Paging through listings with `?limit=&offset=`, telling the number of all matching
+//! items in `X-Total-Count`
+
+use serde::{de, Deserialize, Deserializer};
+
+pub(super) const TOTAL_COUNT: &str = "x-total-count";
+
+#[derive(Deserialize)]
+pub(super) struct Page {
+    /// At most this many items, all if not given
+    #[serde(default, deserialize_with = "number")]
+    limit: Option<usize>,
+    /// Items to skip first
+    #[serde(default, deserialize_with = "number")]
+    offset: Option<usize>,
+}
+
+/// Query values are only known to be strings once a page is flattened into another query
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|n| n.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+impl Page {
+    /// The items on this page, and how many there are on all pages
+    pub(super) fn apply<T>(&self, items: impl Iterator<Item = T>) -> (Vec<T>, usize) {
+        let mut total = 0;
+        let mut page = Vec::new();
+        for item in items {
+            if total >= self.offset.unwrap_or(0)
+                && self.limit.is_none_or(|limit| page.len() < limit)
+            {
+                page.push(item);
+            }
+            total += 1;
+        }
+        (page, total)
+    }
+}
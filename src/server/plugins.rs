@@ -0,0 +1,164 @@
+//! WASI plugins loaded from a directory, for notification sinks and status post-processing
+//!
+//! Each plugin is a WASI command (`.wasm`) run once per call, with JSON on stdin:
+//! `{"hook": "event", "event": {...}}` for every event sent to webhooks, and
+//! `{"hook": "status", "user", "status", "devices": [...]}` whenever a status is
+//! computed. For the latter, whatever the plugin prints replaces the status,
+//! and nothing keeps it. Plugins ignore the hooks they do not handle.
+
+use serde::Serialize;
+use std::{collections::HashMap, error::Error, fmt, fs, path::Path, sync::Arc};
+use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store};
+use wasmtime_wasi::{
+    p1::{self, WasiP1Ctx},
+    p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
+    I32Exit, WasiCtxBuilder,
+};
+
+use super::{notify::Event, Device};
+
+/// Instructions a plugin may run per call
+const FUEL: u64 = 100_000_000;
+/// Bytes of output kept
+const MAX_OUTPUT: usize = 64 * 1024;
+
+pub(super) struct Plugins {
+    engine: Engine,
+    plugins: Vec<(String, InstancePre<WasiP1Ctx>)>,
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = self.plugins.iter().map(|(name, _)| name).collect();
+        f.debug_tuple("Plugins").field(&names).finish()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "hook", rename_all = "lowercase")]
+enum Input<'a> {
+    Event {
+        event: &'a Event,
+    },
+    Status {
+        user: &'a str,
+        status: &'a str,
+        devices: &'a [DeviceInput<'a>],
+    },
+}
+
+#[derive(Serialize)]
+struct DeviceInput<'a> {
+    name: &'a str,
+    online: bool,
+    last_seen: u64,
+    online_since: u64,
+    message: Option<&'a str>,
+    ignored: bool,
+}
+
+impl Plugins {
+    /// Compile every `.wasm` file in `dir`, in order of their names
+    pub(super) fn load(dir: &Path) -> Result<Arc<Self>, Box<dyn Error>> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(&engine);
+        p1::add_to_linker_sync(&mut linker, |ctx| ctx)?;
+
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == "wasm"))
+            .collect();
+        paths.sort();
+        let mut plugins = Vec::new();
+        for path in paths {
+            let module = Module::from_file(&engine, &path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let pre = linker
+                .instantiate_pre(&module)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            plugins.push((name, pre));
+        }
+        info!("Loaded {} plugin(s) from {}", plugins.len(), dir.display());
+        Ok(Arc::new(Plugins { engine, plugins }))
+    }
+
+    /// Run a plugin with `input` on stdin, returning its stdout
+    fn run(&self, pre: &InstancePre<WasiP1Ctx>, input: Vec<u8>) -> Result<String, Box<dyn Error>> {
+        // WASI blocks on the runtime, which a worker thread may only do like this
+        tokio::task::block_in_place(|| self.run_blocking(pre, input))
+    }
+
+    fn run_blocking(
+        &self,
+        pre: &InstancePre<WasiP1Ctx>,
+        input: Vec<u8>,
+    ) -> Result<String, Box<dyn Error>> {
+        let stdout = MemoryOutputPipe::new(MAX_OUTPUT);
+        let ctx = WasiCtxBuilder::new()
+            .stdin(MemoryInputPipe::new(input))
+            .stdout(stdout.clone())
+            .inherit_stderr()
+            .build_p1();
+        let mut store = Store::new(&self.engine, ctx);
+        store.set_fuel(FUEL)?;
+        let instance = pre.instantiate(&mut store)?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        match start.call(&mut store, ()) {
+            Ok(()) => {}
+            Err(e) if e.downcast_ref::<I32Exit>().is_some_and(|exit| exit.0 == 0) => {}
+            Err(e) => return Err(e.into()),
+        }
+        drop(store);
+        Ok(String::from_utf8_lossy(&stdout.contents()).into_owned())
+    }
+
+    /// Pass an event to every plugin
+    pub(super) fn notify(&self, event: &Event) {
+        let input = serde_json::to_vec(&Input::Event { event }).unwrap();
+        for (name, pre) in &self.plugins {
+            if let Err(e) = self.run(pre, input.clone()) {
+                error!("Plugin {} failed: {}", name, e);
+            }
+        }
+    }
+
+    /// Let every plugin in turn replace the status
+    pub(super) fn status(
+        &self,
+        user: &str,
+        mut status: String,
+        devices: &HashMap<String, Device>,
+        ignored: impl Fn(&str, &Device) -> bool,
+        now: u64,
+    ) -> String {
+        let devices: Vec<_> = devices
+            .iter()
+            .map(|(name, d)| DeviceInput {
+                name,
+                online: d.online(now),
+                last_seen: d.last_seen,
+                online_since: d.online_since,
+                message: d.message.as_deref(),
+                ignored: ignored(name, d),
+            })
+            .collect();
+        for (name, pre) in &self.plugins {
+            let input = serde_json::to_vec(&Input::Status {
+                user,
+                status: &status,
+                devices: &devices,
+            })
+            .unwrap();
+            match self.run(pre, input) {
+                Ok(output) if !output.trim().is_empty() => status = output.trim().to_string(),
+                Ok(_) => {}
+                Err(e) => warn!("Plugin {} failed: {}", name, e),
+            }
+        }
+        status
+    }
+}
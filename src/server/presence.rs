@@ -0,0 +1,279 @@
+//! Watching users go online and offline, and keeping a log of incidents
+//!
+//! An incident is a period where all devices of a user were offline, except
+//! when it started during the user's quiet hours.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{Local, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::Path as FsPath,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{
+    audit, authorize_admin, notify,
+    page::{Page, TOTAL_COUNT},
+    request_access, ApiError, AppState, ViewerQuery, DEFAULT_USER,
+};
+use crate::config::Privacy;
+
+pub(super) const WATCH_INTERVAL: u64 = 10;
+const MAX_INCIDENTS: usize = 1000;
+pub(super) const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Presence {
+    pub(super) online: bool,
+    /// When the user was first watched
+    pub(super) since: u64,
+    pub(super) incidents: Vec<Incident>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Incident {
+    pub(super) start: u64,
+    /// None while still offline
+    pub(super) end: Option<u64>,
+    duration_s: Option<u64>,
+}
+
+/// Read the presence of all users saved by an earlier run
+pub(super) fn load(path: &FsPath) -> Result<HashMap<String, Presence>, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(data) => {
+            serde_json::from_str(&data).map_err(|e| format!("{}: {}", path.display(), e).into())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e).into()),
+    }
+}
+
+fn save(path: &FsPath, presence: &HashMap<String, Presence>) {
+    let data = serde_json::to_string(presence).unwrap();
+    if let Err(e) = fs::write(path, data) {
+        warn!("Cannot save history to {}: {}", path.display(), e);
+    }
+}
+
+impl Presence {
+    /// Forget incidents that ended before `cutoff`, and everything else about
+    /// that time. Returns how many were removed.
+    fn prune(&mut self, cutoff: u64) -> usize {
+        let count = self.incidents.len();
+        self.incidents
+            .retain(|i| i.end.is_none_or(|end| end >= cutoff));
+        self.since = self.since.max(cutoff);
+        count - self.incidents.len()
+    }
+}
+
+pub(super) async fn watch(state: AppState) {
+    loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // Better no check than incidents made up of the devices from before it got wedged
+        if !state.watchdog.wedged() {
+            state.prune(now);
+            check(&state, now);
+        }
+        time::sleep(time::Duration::from_secs(WATCH_INTERVAL)).await;
+    }
+}
+
+fn check(state: &AppState, now: u64) {
+    let settings = state.settings();
+    let mut changed = false;
+    for (name, user) in &settings.users {
+        let report = state.report(name, now, Privacy::Devices);
+        let online = report.online;
+        if !online && state.offline_status(name, now) == "UNKNOWN" {
+            continue;
+        }
+        let mut presence = state.presence.lock().unwrap();
+        let Some(presence) = presence.get_mut(name) else {
+            presence.insert(
+                name.clone(),
+                Presence {
+                    online,
+                    since: now,
+                    incidents: Vec::new(),
+                },
+            );
+            changed = true;
+            continue;
+        };
+        if let Some(days) = settings.retention_days {
+            changed |= presence.prune(now.saturating_sub(days * DAY)) > 0;
+        }
+        if presence.online == online {
+            continue;
+        }
+        presence.online = online;
+        changed = true;
+        if online {
+            let end = report.online_since.unwrap_or(now);
+            if let Some(incident) = presence.incidents.last_mut().filter(|i| i.end.is_none()) {
+                incident.end = Some(end);
+                incident.duration_s = Some(end.saturating_sub(incident.start));
+            }
+        } else {
+            let start = report.offline_since.unwrap_or(now);
+            let quiet = user.quiet_hours.is_some_and(|q| {
+                let local = Local.timestamp_opt(start as i64, 0).unwrap();
+                q.contains(local.hour() * 60 + local.minute())
+            });
+            if !quiet {
+                if presence.incidents.len() == MAX_INCIDENTS {
+                    presence.incidents.remove(0);
+                }
+                presence.incidents.push(Incident {
+                    start,
+                    end: None,
+                    duration_s: None,
+                });
+            }
+        }
+        let (event, what) = if online {
+            ("user_online", "is online")
+        } else {
+            ("user_offline", "is offline")
+        };
+        notify::notify(state, notify::Event::new(event, name, None, what));
+    }
+    if let (true, Some(path)) = (changed, &settings.history) {
+        save(path, &state.presence.lock().unwrap());
+    }
+    state.watchdog.checked(now);
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum IncidentState {
+    Ongoing,
+    Ended,
+}
+
+#[derive(Deserialize)]
+pub(super) struct IncidentQuery {
+    state: Option<IncidentState>,
+    /// Only incidents lasting until this time or later
+    since: Option<u64>,
+    /// Only incidents that started before this time
+    until: Option<u64>,
+    #[serde(flatten)]
+    page: Page,
+    #[serde(flatten)]
+    viewer: ViewerQuery,
+}
+
+pub(super) async fn incidents(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<IncidentQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user_incidents(
+        State(state),
+        Path(DEFAULT_USER.to_string()),
+        headers,
+        Query(query),
+    )
+    .await
+}
+
+/// Incidents of a user, most recent first
+pub(super) async fn user_incidents(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<IncidentQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (privacy, _) = request_access(&state, &name, &headers, &query.viewer)?;
+    if privacy < Privacy::Devices {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let presence = state.presence.lock().unwrap();
+    let matching = presence
+        .get(&name)
+        .into_iter()
+        .flat_map(|p| p.incidents.iter().rev())
+        .filter(|i| match query.state {
+            Some(IncidentState::Ongoing) => i.end.is_none(),
+            Some(IncidentState::Ended) => i.end.is_some(),
+            None => true,
+        })
+        .filter(|i| {
+            query
+                .since
+                .is_none_or(|since| i.end.is_none_or(|end| end >= since))
+        })
+        .filter(|i| query.until.is_none_or(|until| i.start < until))
+        .cloned();
+    let (incidents, total) = query.page.apply(matching);
+    Ok(([(TOTAL_COUNT, total.to_string())], Json(incidents)))
+}
+
+#[derive(Deserialize)]
+pub(super) struct PurgeQuery {
+    /// Only this user's history, instead of everyone's
+    user: Option<String>,
+    /// Only incidents that ended before this time, instead of all
+    before: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(super) struct PurgeResult {
+    removed: usize,
+}
+
+/// Delete the incident log, or part of it
+pub(super) async fn purge(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<PurgeQuery>,
+) -> Result<Json<PurgeResult>, ApiError> {
+    let settings = state.settings();
+    authorize_admin(&headers, &state)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let cutoff = query.before.unwrap_or(now).min(now);
+    let mut presence = state.presence.lock().unwrap();
+    let removed = match &query.user {
+        Some(user) => presence
+            .get_mut(user)
+            .ok_or(StatusCode::NOT_FOUND)?
+            .prune(cutoff),
+        None => presence.values_mut().map(|p| p.prune(cutoff)).sum(),
+    };
+    info!(
+        "Purged {} incident(s) that ended before {}",
+        removed, cutoff
+    );
+    if let Some(path) = &settings.history {
+        save(path, &presence);
+    }
+    drop(presence);
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "purge",
+        query.user.as_deref(),
+        None,
+        None,
+        Some(serde_json::json!({ "before": cutoff, "removed": removed })),
+    );
+    Ok(Json(PurgeResult { removed }))
+}
@@ -0,0 +1,147 @@
+//! Pushing whether every user is online, and how many heartbeats arrived, to StatsD or
+//! InfluxDB, for monitoring that does not scrape `/metrics`
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, time};
+
+use super::{decided_report, AppState, DEFAULT_USER};
+use crate::{
+    config::{Privacy, PushConfig},
+    HEARTBEAT_INTERVAL, TIMEOUT,
+};
+
+const DEFAULT_PREFIX: &str = "online_status";
+
+type PushError = Box<dyn Error + Send + Sync>;
+
+/// A user's name in metric names and tags, where the default user is `default`
+fn metric_name(user: &str) -> String {
+    match user {
+        DEFAULT_USER => "default".to_string(),
+        user => user
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    }
+}
+
+/// A gauge for being online and a counter for the heartbeats since the last push, in one
+/// datagram per user to stay below common packet sizes
+fn statsd(
+    prefix: &str,
+    online: &BTreeMap<String, bool>,
+    new: &BTreeMap<String, u64>,
+) -> Vec<String> {
+    online
+        .iter()
+        .map(|(user, online)| {
+            let heartbeats = new.get(user).copied().unwrap_or(0);
+            let user = metric_name(user);
+            format!(
+                "{}.online.{}:{}|g\n{}.heartbeats.{}:{}|c",
+                prefix, user, *online as u8, prefix, user, heartbeats
+            )
+        })
+        .collect()
+}
+
+/// One line per user, with the heartbeats accepted since the server started
+fn influx(
+    prefix: &str,
+    online: &BTreeMap<String, bool>,
+    total: &BTreeMap<String, u64>,
+    now: u64,
+) -> String {
+    let mut out = String::new();
+    for (user, online) in online {
+        let _ = writeln!(
+            out,
+            "{},user={} online={}i,heartbeats={}i {}",
+            prefix,
+            metric_name(user),
+            *online as u8,
+            total.get(user).copied().unwrap_or(0),
+            now
+        );
+    }
+    out
+}
+
+async fn push(state: &AppState, push: &PushConfig, last: &mut BTreeMap<String, u64>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let settings = state.settings();
+    let online: BTreeMap<String, bool> = settings
+        .users
+        .keys()
+        .map(|user| {
+            let report = decided_report(state, user, now, Privacy::Devices);
+            (user.clone(), report.status == "ONLINE")
+        })
+        .collect();
+    let total = state.metrics.heartbeats();
+    let prefix = push.prefix.as_deref().unwrap_or(DEFAULT_PREFIX);
+
+    if let Some(address) = &push.statsd {
+        let new = total
+            .iter()
+            .map(|(user, count)| (user.clone(), count - last.get(user).copied().unwrap_or(0)))
+            .collect();
+        if let Err(e) = send_statsd(address, &statsd(prefix, &online, &new)).await {
+            error!("Pushing to StatsD at {} failed: {}", address, e);
+        }
+    }
+    if let Some(url) = &push.influx {
+        let mut req = state
+            .http
+            .post(url)
+            .query(&[("precision", "s")])
+            .body(influx(prefix, &online, &total, now))
+            .timeout(time::Duration::from_secs(TIMEOUT));
+        if let Some(token) = &push.influx_token {
+            req = req.header("Authorization", format!("Token {}", token));
+        }
+        if let Err(e) = req.send().await.and_then(|r| r.error_for_status()) {
+            error!("Pushing to InfluxDB at {} failed: {}", url, e);
+        }
+    }
+    *last = total;
+}
+
+async fn send_statsd(address: &str, datagrams: &[String]) -> Result<(), PushError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(address).await?;
+    for datagram in datagrams {
+        socket.send(datagram.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub(super) async fn run(state: AppState) {
+    // Heartbeats counted at the last push, for the StatsD counters
+    let mut last = state.metrics.heartbeats();
+    loop {
+        let config = state.settings().push.clone();
+        let interval = match &config {
+            Some(config) => {
+                push(&state, config, &mut last).await;
+                config.interval.unwrap_or(HEARTBEAT_INTERVAL)
+            }
+            None => HEARTBEAT_INTERVAL,
+        };
+        time::sleep(time::Duration::from_secs(interval.max(1))).await;
+    }
+}
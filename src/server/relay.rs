@@ -0,0 +1,70 @@
+//! Passing heartbeats on to an upstream server, for devices that cannot reach it directly
+
+use pgp::{crypto::hash::HashAlgorithm, SignedSecretKey};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+use super::AppState;
+use crate::{keys::sign_timestamp, HeartBeat, TIMEOUT};
+
+#[derive(Debug)]
+pub(super) struct Relay {
+    pub(super) url: String,
+    pub(super) privkey: Option<SignedSecretKey>,
+    pub(super) token: Option<String>,
+    pub(super) hash: HashAlgorithm,
+}
+
+impl Relay {
+    /// Whether the relay replaces the credentials of the heartbeats
+    fn resigns(&self) -> bool {
+        self.privkey.is_some() || self.token.is_some()
+    }
+}
+
+/// Send an accepted heartbeat upstream, either as received or with the
+/// relay's own credentials
+///
+/// The tokens heartbeats come with are never passed on, as they are this server's, not the
+/// upstream's, so heartbeats without a signature need the relay's own credentials.
+pub(super) async fn forward(state: AppState, mut info: HeartBeat) {
+    let settings = state.settings();
+    let Some(relay) = &settings.relay else {
+        return;
+    };
+    if !relay.resigns() && info.signature.is_none() && info.detached_signature.is_none() {
+        warn!("Not relaying a heartbeat without signature, as [relay] has no privkey or token");
+        return;
+    }
+    if relay.resigns() {
+        info.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        info.signature = match &relay.privkey {
+            Some(key) => match sign_timestamp(key, relay.hash, info.timestamp) {
+                Ok(signature) => Some(signature),
+                Err(e) => {
+                    error!("Cannot sign relayed heartbeat: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        info.hash = info.signature.as_ref().map(|_| relay.hash.to_string());
+        info.detached_signature = None;
+    }
+
+    let mut req = state
+        .http
+        .post(&relay.url)
+        .json(&info)
+        .timeout(time::Duration::from_secs(TIMEOUT));
+    if let Some(token) = &relay.token {
+        req = req.bearer_auth(token);
+    }
+    match req.send().await.and_then(|r| r.error_for_status()) {
+        Ok(_) => {}
+        Err(e) => error!("Relaying heartbeat failed: {}", e),
+    }
+}
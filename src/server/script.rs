@@ -0,0 +1,90 @@
+//! User-written rules computing the aggregate status, in Rhai
+//!
+//! The script sees `user`, `status` (as computed without the script), `now`,
+//! the local `hour`, `minute` and `weekday`, and `devices`, an array of maps
+//! with `name`, `online`, `last_seen`, `online_since`, `message` and `ignored`.
+//! It evaluates to the status string, e.g. `"AWAY"`.
+
+use chrono::{Local, TimeZone, Timelike};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::{collections::HashMap, error::Error, fmt, fs, path::Path};
+
+use super::Device;
+
+/// Stops runaway scripts
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub(super) struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Script")
+    }
+}
+
+impl Script {
+    pub(super) fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let ast = engine
+            .compile(source)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Script { engine, ast })
+    }
+
+    /// The status the script computes, or None if it fails
+    pub(super) fn status(
+        &self,
+        user: &str,
+        status: &str,
+        devices: &HashMap<String, Device>,
+        ignored: impl Fn(&str, &Device) -> bool,
+        now: u64,
+    ) -> Option<String> {
+        let devices: Array = devices
+            .iter()
+            .map(|(name, d)| {
+                let mut map = Map::new();
+                map.insert("name".into(), name.clone().into());
+                map.insert("online".into(), d.online(now).into());
+                map.insert("last_seen".into(), (d.last_seen as i64).into());
+                map.insert("online_since".into(), (d.online_since as i64).into());
+                map.insert(
+                    "message".into(),
+                    d.message.clone().map_or(Dynamic::UNIT, Dynamic::from),
+                );
+                map.insert("ignored".into(), ignored(name, d).into());
+                map.into()
+            })
+            .collect();
+        let local = Local.timestamp_opt(now as i64, 0).unwrap();
+        let mut scope = Scope::new();
+        scope.push_constant("user", user.to_string());
+        scope.push_constant("status", status.to_string());
+        scope.push_constant("now", now as i64);
+        scope.push_constant("hour", local.hour() as i64);
+        scope.push_constant("minute", local.minute() as i64);
+        scope.push_constant("weekday", local.format("%a").to_string());
+        scope.push_constant("devices", devices);
+        match self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+        {
+            Ok(result) => match result.into_string() {
+                Ok(status) => Some(status),
+                Err(kind) => {
+                    warn!("Status script returned {} instead of a string", kind);
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("Status script failed: {}", e);
+                None
+            }
+        }
+    }
+}
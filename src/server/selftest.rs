@@ -0,0 +1,161 @@
+//! `online_status selftest`: a signed heartbeat through an in-process server, to find
+//! mismatched keys, a wrong clock or a broken configuration before deploying
+
+use reqwest::header;
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{ip_filter::IpFilter, router, AppState, Settings, DEFAULT_USER};
+use crate::{
+    config::{Args, Privacy},
+    exit,
+    keys::{read_secret_key, sign_timestamp},
+    ErrorResponse, HeartBeat, TIMEOUT,
+};
+
+/// Device name of the test heartbeat, unless `--device` is given
+const SELFTEST_DEVICE: &str = "selftest";
+
+pub(crate) async fn selftest_main(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.privkey.is_none() && args.token.is_none() {
+        return Err("Give --privkey or --token to send the test heartbeat with".into());
+    }
+    let mut settings = Settings::load(&args).map_err(exit::config)?;
+    if settings.mirror.is_some() {
+        return Err("A mirror does not receive heartbeats".into());
+    }
+    info!("OK: Configuration and keys");
+    // Nothing of the test may leave this process
+    settings.relay = None;
+    settings.notify.clear();
+    settings.access_log = None;
+    // It comes from 127.0.0.1, wherever the real clients are
+    settings.heartbeat_filter = IpFilter::default();
+
+    if args.client.is_some() {
+        check_clock(&args).await?;
+    }
+    let privkey = match &args.privkey {
+        Some(path) => Some(
+            read_secret_key(path)
+                .map_err(|e| exit::config(format!("{}: {}", path.display(), e)))?,
+        ),
+        None => None,
+    };
+    let url = format!(
+        "{}{}",
+        args.base_path.as_deref().unwrap_or_default(),
+        settings.heartbeat_path
+    );
+    let state = AppState::new(args.clone(), settings)?;
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let url = format!("http://{}{}", listener.local_addr()?, url);
+    let app = router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let hash = args.hash.unwrap_or_default();
+    let signature = match &privkey {
+        Some(key) => Some(sign_timestamp(key, hash, now)?),
+        None => None,
+    };
+    let device = args
+        .device
+        .clone()
+        .unwrap_or_else(|| SELFTEST_DEVICE.to_string());
+    let heartbeat = HeartBeat {
+        timestamp: now,
+        hash: signature.as_ref().map(|_| hash.to_string()),
+        signature,
+        detached_signature: None,
+        device: Some(device.clone()),
+        message: None,
+        place: None,
+        latency_ms: None,
+        interval: None,
+        client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        uptime: None,
+        metrics: None,
+        extra: None,
+    };
+    let mut req = reqwest::Client::new().post(url);
+    if let Some(token) = &args.token {
+        req = req.bearer_auth(token);
+    }
+    let res = req
+        .header(header::ACCEPT, "application/json")
+        .json(&heartbeat)
+        .timeout(Duration::from_secs(TIMEOUT))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        let reason = match res.json::<ErrorResponse>().await {
+            Ok(e) => match e.detail {
+                Some(detail) => format!("{} ({}: {}, {})", status, e.code, e.message, detail),
+                None => format!("{} ({}: {})", status, e.code, e.message),
+            },
+            Err(_) => status.to_string(),
+        };
+        return Err(exit::status(
+            status,
+            format!("Heartbeat rejected: {}", reason),
+        ));
+    }
+    info!("OK: Heartbeat of device {} accepted", device);
+
+    let user = state
+        .snapshot()
+        .iter()
+        .find(|(_, devices)| devices.contains_key(&device))
+        .map(|(user, _)| user.clone())
+        .ok_or("The heartbeat was accepted, but belongs to no user")?;
+    let who = match user.as_str() {
+        DEFAULT_USER => "The default user".to_string(),
+        user => format!("User {}", user),
+    };
+    let report = state.report(&user, now, Privacy::Messages);
+    if report.status != "ONLINE" {
+        return Err(format!("{} is {} instead of ONLINE", who, report.status).into());
+    }
+    info!("OK: {} is ONLINE", who);
+    Ok(())
+}
+
+/// Compare the clock with that of the server given with -c, which rejects heartbeats
+/// more than `TIMEOUT` seconds off
+async fn check_clock(args: &Args) -> Result<(), Box<dyn Error>> {
+    let url = args.server_url("/capabilities");
+    let res = reqwest::Client::new()
+        .get(&url)
+        .timeout(Duration::from_secs(TIMEOUT))
+        .send()
+        .await
+        .map_err(|e| exit::network(format!("Cannot reach {}: {}", url, e)))?;
+    let date = res
+        .headers()
+        .get(header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+        .ok_or_else(|| format!("{} sent no Date header", url))?;
+    let offset = chrono::Utc::now().timestamp() - date.timestamp();
+    if offset.unsigned_abs() > TIMEOUT {
+        return Err(format!(
+            "The clock is {} seconds off from the server's, which rejects heartbeats \
+             more than {} seconds off",
+            offset, TIMEOUT
+        )
+        .into());
+    }
+    info!(
+        "OK: Clock within {} seconds of the server's",
+        offset.unsigned_abs()
+    );
+    Ok(())
+}
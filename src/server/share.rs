@@ -0,0 +1,224 @@
+//! Links that let whoever has them see more of a user's status for a while, like
+//! `/status?share=<token>`, without a viewer token of their own
+//!
+//! A token names the user, what it lets see and until when, signed with an HMAC over the
+//! `share_secret`, so the server keeps no list of them. Changing the secret revokes all.
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{audit, authorize_admin, ApiError, AppState, DEFAULT_USER};
+use crate::config::Privacy;
+
+const DEFAULT_HOURS: u64 = 24;
+/// A month, so that forgotten links do not work forever
+const MAX_HOURS: u64 = 31 * 24;
+const BLOCK: usize = 64;
+
+fn hmac(secret: &str, message: &str) -> String {
+    let mut key = [0; BLOCK];
+    if secret.len() > BLOCK {
+        key[..32].copy_from_slice(&Sha256::digest(secret.as_bytes()));
+    } else {
+        key[..secret.len()].copy_from_slice(secret.as_bytes());
+    }
+    let pad = |byte: u8| key.map(|k| k ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message.as_bytes())
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize();
+    hex::encode(outer)
+}
+
+fn privacy_name(privacy: Privacy) -> &'static str {
+    match privacy {
+        Privacy::Private => "private",
+        Privacy::Aggregate => "aggregate",
+        Privacy::Devices => "devices",
+        Privacy::Messages => "messages",
+    }
+}
+
+fn token(secret: &str, user: &str, privacy: Privacy, expires: u64) -> String {
+    let claim = format!(
+        "{}.{}.{}",
+        URL_SAFE_NO_PAD.encode(user),
+        privacy_name(privacy),
+        expires
+    );
+    let mac = hmac(secret, &claim);
+    format!("{}.{}", claim, mac)
+}
+
+/// Whether two secrets are the same, in a time that does not tell how much of them matches
+pub(super) fn constant_time_eq(a: &str, b: &str) -> bool {
+    let differs = a.bytes().zip(b.bytes()).fold(0, |d, (a, b)| d | (a ^ b));
+    a.len() == b.len() && differs == 0
+}
+
+/// What a share token lets see of `user`, if it is theirs, genuine and not expired
+pub(super) fn verify(secret: &str, token: &str, user: &str, now: u64) -> Option<Privacy> {
+    let (claim, mac) = token.rsplit_once('.')?;
+    // Compared as a whole, as the time a wrong MAC takes to be found out may tell its start
+    if !constant_time_eq(&hmac(secret, claim), mac) {
+        return None;
+    }
+    let mut parts = claim.split('.');
+    let owner = URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+    let name = parts.next()?;
+    let privacy = [Privacy::Aggregate, Privacy::Devices, Privacy::Messages]
+        .into_iter()
+        .find(|&p| privacy_name(p) == name)?;
+    let expires: u64 = parts.next()?.parse().ok()?;
+    (owner == user.as_bytes() && now < expires).then_some(privacy)
+}
+
+#[derive(Deserialize)]
+pub(super) struct ShareRequest {
+    /// The default user if unset
+    user: Option<String>,
+    /// Hours the link works for, 24 if unset
+    hours: Option<u64>,
+    /// What the link lets see, `messages` (everything) if unset
+    privacy: Option<Privacy>,
+}
+
+#[derive(Serialize)]
+pub(super) struct Share {
+    /// Path of the plain status with the token, to append to the server's address
+    path: String,
+    token: String,
+    expires: u64,
+}
+
+/// Make a share link
+pub(super) async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ShareRequest>,
+) -> Result<Json<Share>, ApiError> {
+    authorize_admin(&headers, &state)?;
+    let settings = state.settings();
+    let Some(secret) = &settings.share_secret else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "sharing_disabled",
+            "Sharing needs a share_secret",
+        ));
+    };
+    let user = request.user.unwrap_or_else(|| DEFAULT_USER.to_string());
+    if !settings.users.contains_key(&user) {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let hours = request.hours.unwrap_or(DEFAULT_HOURS).clamp(1, MAX_HOURS);
+    let expires = now + hours * 60 * 60;
+    // A private link would show nothing
+    let privacy = request
+        .privacy
+        .unwrap_or(Privacy::Messages)
+        .max(Privacy::Aggregate);
+    let token = token(secret, &user, privacy, expires);
+    let status = match user.as_str() {
+        DEFAULT_USER => settings.status_path.clone(),
+        user => format!("/u/{}/status", user),
+    };
+    info!(
+        "Shared the status of {} for {} hours",
+        if user.is_empty() {
+            "the default user"
+        } else {
+            &user
+        },
+        hours
+    );
+    audit::record(
+        &state,
+        audit::actor(&state, &headers),
+        "share",
+        Some(&user),
+        None,
+        None,
+        Some(serde_json::json!({ "privacy": privacy_name(privacy), "expires": expires })),
+    );
+    Ok(Json(Share {
+        path: format!("{}?share={}", status, token),
+        token,
+        expires,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    #[test]
+    fn genuine() {
+        let token = token(SECRET, "alice", Privacy::Devices, 2000);
+        assert_eq!(
+            verify(SECRET, &token, "alice", 1000),
+            Some(Privacy::Devices)
+        );
+        // The default user has an empty name
+        let token = super::token(SECRET, "", Privacy::Messages, 2000);
+        assert_eq!(verify(SECRET, &token, "", 1999), Some(Privacy::Messages));
+    }
+
+    #[test]
+    fn expired() {
+        let token = token(SECRET, "alice", Privacy::Devices, 2000);
+        assert_eq!(verify(SECRET, &token, "alice", 2000), None);
+        assert_eq!(verify(SECRET, &token, "alice", 3000), None);
+    }
+
+    #[test]
+    fn wrong_user() {
+        let token = token(SECRET, "alice", Privacy::Devices, 2000);
+        assert_eq!(verify(SECRET, &token, "bob", 1000), None);
+        assert_eq!(verify(SECRET, &token, "", 1000), None);
+    }
+
+    #[test]
+    fn tampered() {
+        let token = token(SECRET, "alice", Privacy::Aggregate, 2000);
+        // Asking for more, or for longer, with the MAC of the original claim
+        let more = token.replace(".aggregate.", ".messages.");
+        assert_eq!(verify(SECRET, &more, "alice", 1000), None);
+        let longer = token.replace(".2000.", ".9000.");
+        assert_eq!(verify(SECRET, &longer, "alice", 1000), None);
+        let (claim, mac) = token.rsplit_once('.').unwrap();
+        let flipped = if mac.starts_with('0') { "1" } else { "0" };
+        let forged = format!("{}.{}{}", claim, flipped, &mac[1..]);
+        assert_eq!(verify(SECRET, &forged, "alice", 1000), None);
+        assert_eq!(verify(SECRET, &format!("{}.", claim), "alice", 1000), None);
+        assert_eq!(verify(SECRET, claim, "alice", 1000), None);
+        assert_eq!(verify("another secret", &token, "alice", 1000), None);
+    }
+
+    #[test]
+    fn long_secret() {
+        let secret = "s".repeat(100);
+        let token = token(&secret, "alice", Privacy::Devices, 2000);
+        assert_eq!(
+            verify(&secret, &token, "alice", 1000),
+            Some(Privacy::Devices)
+        );
+        assert_eq!(verify(&"s".repeat(99), &token, "alice", 1000), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Remembering which signatures were already checked, so that retried or
+//! repeated heartbeats are not verified again
+//!
+//! Part of the settings, so it is emptied whenever the keys are reloaded.
+
+use pgp::crypto::hash::HashAlgorithm;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+const CAPACITY: usize = 1024;
+
+/// Hash of a timestamp and its signature, including the hash algorithm it claims
+pub(super) type Key = [u8; 32];
+
+/// Who made a signature: user name and key fingerprint, or none if it is invalid
+pub(super) type Signer = Option<(String, String)>;
+
+#[derive(Debug, Default)]
+pub(super) struct SignatureCache {
+    inner: Mutex<Inner>,
+    pub(super) hits: AtomicU64,
+    pub(super) misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<Key, (Signer, u64)>,
+    /// Incremented on every use, the entry used longest ago is evicted first
+    clock: u64,
+}
+
+pub(super) fn key(timestamp: u64, hash: HashAlgorithm, signature: &[String]) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(timestamp.to_be_bytes());
+    hasher.update([u8::from(hash)]);
+    for part in signature {
+        hasher.update(part.len().to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+impl SignatureCache {
+    pub(super) fn get(&self, key: &Key) -> Option<Signer> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        match inner.entries.get_mut(key) {
+            Some((signer, last_used)) => {
+                *last_used = clock;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(signer.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub(super) fn insert(&self, key: Key, signer: Signer) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.len() >= CAPACITY {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        inner.entries.insert(key, (signer, clock));
+    }
+}
@@ -0,0 +1,130 @@
+//! Services whose idea of a user's status is kept in line with ours, like Slack or GitHub
+//!
+//! A sink only tells what the service should show and how to set it. [`sync`] sets it
+//! whenever the hash of that changes, after it lasted the sink's debounce time, at most every
+//! `min_interval` seconds, and retries failures with increasing delays.
+
+use std::{
+    error::Error,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{decided_report, notify, AppState, Settings, StatusReport};
+use crate::{config::Privacy, HEARTBEAT_INTERVAL};
+
+/// Seconds to wait before the first retry, doubled with every further failure
+const RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 900;
+
+pub(super) type SinkError = Box<dyn Error + Send + Sync>;
+
+pub(super) trait PresenceSink: Sized + Send + Sync + 'static {
+    /// Name of the service, for the log
+    const NAME: &'static str;
+    type Status: Hash + Send + Sync;
+
+    /// The sink as configured now, if it is
+    fn configured(settings: &Settings) -> Option<Self>;
+    /// User whose status is synced
+    fn user(&self) -> &str;
+    /// Account the status is set for, so that a new one gets it even if it did not change
+    fn account(&self) -> &str;
+    /// What the service should show of `report`
+    fn status(&self, report: StatusReport) -> Self::Status;
+    fn set(
+        &self,
+        state: &AppState,
+        status: &Self::Status,
+    ) -> impl Future<Output = Result<(), SinkError>> + Send;
+
+    /// Seconds a status must last before it is set
+    fn debounce(&self) -> u64 {
+        0
+    }
+    /// Seconds at least between two updates
+    fn min_interval(&self) -> u64 {
+        0
+    }
+}
+
+/// What is known of a sink between two looks at the status
+#[derive(Default)]
+struct SyncState {
+    /// Hash of the account and status last set, and when
+    last: Option<(u64, u64)>,
+    /// Hash of a status not set yet, and since when it holds
+    pending: Option<(u64, u64)>,
+    failures: u32,
+    retry_at: u64,
+}
+
+/// Keep the service of `S` in line, looking every minute, after every event and when a
+/// waiting status is due
+pub(super) async fn sync<S: PresenceSink>(state: AppState) {
+    let mut events = state.events.subscribe();
+    let mut sync = SyncState::default();
+    loop {
+        let mut wait = HEARTBEAT_INTERVAL;
+        let sink = S::configured(&state.settings());
+        if let Some(sink) = sink {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let status = sink.status(decided_report(&state, sink.user(), now, Privacy::Messages));
+            let mut hasher = DefaultHasher::new();
+            (sink.account(), &status).hash(&mut hasher);
+            let hash = hasher.finish();
+
+            if sync.last.is_some_and(|(h, _)| h == hash) {
+                sync.pending = None;
+            } else {
+                if sync.pending.is_none_or(|(h, _)| h != hash) {
+                    sync.pending = Some((hash, now));
+                }
+                let since = sync.pending.map_or(now, |(_, since)| since);
+                let due = [
+                    since + sink.debounce(),
+                    sync.last.map_or(0, |(_, at)| at + sink.min_interval()),
+                    sync.retry_at,
+                ]
+                .into_iter()
+                .max()
+                .unwrap();
+                if now < due {
+                    wait = wait.min(due - now);
+                } else {
+                    let result = sink.set(&state, &status).await;
+                    let outcome = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+                    notify::delivered(&state, S::NAME.to_string(), "status", outcome);
+                    match result {
+                        Ok(()) => {
+                            sync.last = Some((hash, now));
+                            sync.pending = None;
+                            sync.failures = 0;
+                        }
+                        Err(e) => {
+                            let delay = (RETRY_DELAY << sync.failures.min(10)).min(MAX_RETRY_DELAY);
+                            error!(
+                                "Setting the {} status failed, retrying in {}s: {}",
+                                S::NAME,
+                                delay,
+                                e
+                            );
+                            sync.failures += 1;
+                            sync.retry_at = now + delay;
+                            wait = wait.min(delay);
+                        }
+                    }
+                }
+            }
+        }
+        tokio::select! {
+            _ = time::sleep(time::Duration::from_secs(wait.max(1))) => {}
+            _ = events.recv() => {}
+        }
+    }
+}
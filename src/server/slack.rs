@@ -0,0 +1,113 @@
+//! Keeping a Slack account's status and presence in line with a user's, so they never have
+//! to be set by hand
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time;
+
+use super::{
+    sink::{PresenceSink, SinkError},
+    AppState, Settings, StatusReport, DEFAULT_USER,
+};
+use crate::{config::SlackConfig, TIMEOUT};
+
+const SLACK_API: &str = "https://slack.com/api";
+/// Longest status text Slack takes
+const MAX_STATUS_TEXT_LEN: usize = 100;
+
+#[derive(Hash)]
+pub(super) struct SlackStatus {
+    /// `auto`, or `away` while offline
+    presence: &'static str,
+    text: String,
+    emoji: String,
+}
+
+#[derive(Deserialize)]
+struct SlackResponse {
+    ok: bool,
+    error: Option<String>,
+}
+
+impl PresenceSink for SlackConfig {
+    const NAME: &'static str = "Slack";
+    type Status = SlackStatus;
+
+    fn configured(settings: &Settings) -> Option<Self> {
+        settings.slack.clone()
+    }
+
+    fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(DEFAULT_USER)
+    }
+
+    fn account(&self) -> &str {
+        &self.token
+    }
+
+    fn status(&self, report: StatusReport) -> SlackStatus {
+        let (presence, text, emoji) = match &*report.status {
+            "OFFLINE" => ("away", String::new(), ""),
+            "ONLINE" => match report.message {
+                Some(message) => (
+                    "auto",
+                    message,
+                    self.message_emoji.as_deref().unwrap_or(":speech_balloon:"),
+                ),
+                None => ("auto", String::new(), ""),
+            },
+            status => (
+                "auto",
+                status.to_string(),
+                self.busy_emoji.as_deref().unwrap_or(":no_entry:"),
+            ),
+        };
+        SlackStatus {
+            presence,
+            text: text.chars().take(MAX_STATUS_TEXT_LEN).collect(),
+            emoji: emoji.to_string(),
+        }
+    }
+
+    async fn set(&self, state: &AppState, status: &SlackStatus) -> Result<(), SinkError> {
+        let profile = json!({
+            "profile": {
+                "status_text": status.text,
+                "status_emoji": status.emoji,
+                "status_expiration": 0,
+            }
+        });
+        call(state, self, "users.profile.set", profile).await?;
+        call(
+            state,
+            self,
+            "users.setPresence",
+            json!({"presence": status.presence}),
+        )
+        .await
+    }
+}
+
+/// A Web API method, which tells of failures in its body
+async fn call(
+    state: &AppState,
+    slack: &SlackConfig,
+    method: &str,
+    body: Value,
+) -> Result<(), SinkError> {
+    let res: SlackResponse = state
+        .http
+        .post(format!("{}/{}", SLACK_API, method))
+        .bearer_auth(&slack.token)
+        .json(&body)
+        .timeout(time::Duration::from_secs(TIMEOUT))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    match res.ok {
+        true => Ok(()),
+        false => Err(format!("{}: {}", method, res.error.unwrap_or_default()).into()),
+    }
+}
@@ -0,0 +1,66 @@
+//! How regularly a device sends its heartbeats
+
+use serde::Serialize;
+
+use super::Device;
+use crate::HEARTBEAT_INTERVAL;
+
+/// Number of recent intervals the score is computed from
+const INTERVAL_SAMPLES: usize = 30;
+/// Fewer intervals than this say nothing about stability yet
+const MIN_INTERVAL_SAMPLES: usize = 5;
+pub(super) const DEFAULT_FLAKY_THRESHOLD: f64 = 0.8;
+
+#[derive(Debug, Serialize)]
+pub(super) struct Stability {
+    /// Share of expected heartbeats that arrived, from 0 to 1
+    score: f64,
+    /// Heartbeats missed in the recent window
+    missed: u64,
+    /// Mean deviation of the heartbeat interval from the expected one
+    jitter_s: f64,
+}
+
+impl Device {
+    /// Remember the time since the previous heartbeat. Gaps long enough to
+    /// count as offline are periods of absence, not flakiness, and are skipped.
+    pub(super) fn record_interval(&mut self, now: u64) {
+        let gap = now.saturating_sub(self.last_seen);
+        if self.last_seen == 0 || gap > self.offline_timeout() {
+            return;
+        }
+        if self.intervals.len() == INTERVAL_SAMPLES {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(gap);
+    }
+
+    pub(super) fn stability(&self) -> Option<Stability> {
+        if self.intervals.len() < MIN_INTERVAL_SAMPLES {
+            return None;
+        }
+        let mut missed = 0;
+        let mut jitter = 0;
+        let expected = self.interval.unwrap_or(HEARTBEAT_INTERVAL);
+        for &gap in &self.intervals {
+            let beats = ((gap + expected / 2) / expected).max(1);
+            missed += beats - 1;
+            jitter += gap.abs_diff(beats * expected);
+        }
+        let received = self.intervals.len() as u64;
+        Some(Stability {
+            score: received as f64 / (received + missed) as f64,
+            missed,
+            jitter_s: jitter as f64 / received as f64,
+        })
+    }
+
+    /// Update whether the device is flaky, returning the new state if it changed
+    pub(super) fn update_flaky(&mut self, threshold: f64) -> Option<bool> {
+        let flaky = self.stability().is_some_and(|s| s.score < threshold);
+        (flaky != self.flaky).then(|| {
+            self.flaky = flaky;
+            flaky
+        })
+    }
+}
@@ -0,0 +1,177 @@
+//! Templates for the plain-text `/status`, e.g. `{{state}} — last seen {{relative_time}}`
+//!
+//! Values the viewer may not see render as empty strings.
+
+use super::StatusReport;
+
+const PLACEHOLDERS: [&str; 8] = [
+    "state",
+    "message",
+    "since",
+    "duration",
+    "last_seen",
+    "relative_time",
+    "devices",
+    "place",
+];
+
+/// Check that a template only uses known placeholders
+pub(super) fn validate(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            return Err("Unterminated placeholder in status_template".to_string());
+        };
+        let name = rest[start + 2..start + end].trim();
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(format!("Unknown placeholder in status_template: {}", name));
+        }
+        rest = &rest[start + end + 2..];
+    }
+    Ok(())
+}
+
+pub(super) fn render(template: &str, report: &StatusReport) -> String {
+    let now = report.timestamp;
+    let since = report.online_since.or(report.offline_since);
+    let last_seen = report
+        .devices
+        .as_ref()
+        .and_then(|devices| devices.iter().map(|d| d.last_seen).max());
+    let value = |name: &str| match name {
+        "state" => report.status.to_string(),
+        "message" => report.message.clone().unwrap_or_default(),
+        "since" => since.map(|t| t.to_string()).unwrap_or_default(),
+        "duration" => since
+            .map(|t| duration(now.saturating_sub(t)))
+            .unwrap_or_default(),
+        "last_seen" => last_seen.map(|t| t.to_string()).unwrap_or_default(),
+        "relative_time" => last_seen
+            .map(|t| relative_time(now.saturating_sub(t)))
+            .unwrap_or_default(),
+        "devices" => report
+            .devices
+            .as_ref()
+            .map(|devices| {
+                let online = devices.iter().filter(|d| d.status == "ONLINE").count();
+                format!("{}/{}", online, devices.len())
+            })
+            .unwrap_or_default(),
+        "place" => report
+            .devices
+            .iter()
+            .flatten()
+            .filter(|d| d.status == "ONLINE" && !d.ignored)
+            .max_by_key(|d| d.last_seen)
+            .and_then(|d| d.place.clone())
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").unwrap();
+        out.push_str(&rest[..start]);
+        out.push_str(&value(rest[start + 2..start + end].trim()));
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Compact length of a period, e.g. `3h 5m`
+fn duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// How long ago something was, e.g. `5 minutes ago`
+fn relative_time(secs: u64) -> String {
+    let (n, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(status: &'static str, message: Option<&str>, since: Option<u64>) -> StatusReport {
+        StatusReport {
+            status: status.into(),
+            online: status == "ONLINE",
+            timestamp: 100_000,
+            message: message.map(str::to_string),
+            online_since: since.filter(|_| status == "ONLINE"),
+            offline_since: since.filter(|_| status != "ONLINE"),
+            devices: None,
+            server_started: 0,
+            incomplete: false,
+        }
+    }
+
+    #[test]
+    fn validate_placeholders() {
+        assert!(validate("{{state}} — last seen {{relative_time}}").is_ok());
+        assert!(validate("{{ state }} {{message}}").is_ok());
+        assert!(validate("no placeholders").is_ok());
+        assert!(validate("{{state").unwrap_err().contains("Unterminated"));
+        assert!(validate("{{status}}").unwrap_err().contains("status"));
+        assert!(validate("{{state}} {{}}").is_err());
+    }
+
+    #[test]
+    fn render_values() {
+        let online = report(
+            "ONLINE",
+            Some("In a meeting"),
+            Some(100_000 - 3 * 3600 - 5 * 60),
+        );
+        assert_eq!(
+            render(
+                "{{state}}: {{ message }} for {{duration}} ({{since}})",
+                &online
+            ),
+            "ONLINE: In a meeting for 3h 5m (88900)",
+        );
+        let offline = report("OFFLINE", None, Some(100_000 - 30));
+        assert_eq!(
+            render("{{state}} for {{duration}}", &offline),
+            "OFFLINE for 30s"
+        );
+    }
+
+    #[test]
+    fn render_hidden() {
+        // What the viewer may not see is left out
+        let private = report("OFFLINE", None, None);
+        assert_eq!(
+            render(
+                "{{state}}{{message}}|{{since}}|{{devices}}|{{place}}|{{last_seen}}",
+                &private
+            ),
+            "OFFLINE||||",
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(59), "59s");
+        assert_eq!(duration(60), "1m");
+        assert_eq!(duration(3 * 3600 + 5 * 60 + 9), "3h 5m");
+        assert_eq!(duration(2 * 86400 + 4 * 3600), "2d 4h");
+        assert_eq!(relative_time(30), "just now");
+        assert_eq!(relative_time(60), "1 minute ago");
+        assert_eq!(relative_time(5 * 60), "5 minutes ago");
+        assert_eq!(relative_time(3600), "1 hour ago");
+        assert_eq!(relative_time(3 * 86400), "3 days ago");
+    }
+}
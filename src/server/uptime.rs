@@ -0,0 +1,117 @@
+//! SVG strip of daily availability bars for embedding, computed from the incident log
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::IntoResponse,
+};
+use chrono::DateTime;
+use serde::Deserialize;
+use std::fmt::Write;
+
+use super::{request_access, ApiError, AppState, ViewerQuery, DEFAULT_USER};
+
+const DEFAULT_DAYS: u64 = 90;
+const MAX_DAYS: u64 = 365;
+const DAY: u64 = 24 * 60 * 60;
+const BAR_WIDTH: u64 = 3;
+const BAR_GAP: u64 = 2;
+const BAR_HEIGHT: u64 = 34;
+
+#[derive(Deserialize)]
+pub(super) struct UptimeQuery {
+    days: Option<u64>,
+    #[serde(flatten)]
+    viewer: ViewerQuery,
+}
+
+pub(super) async fn uptime(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UptimeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user_uptime(
+        State(state),
+        Path(DEFAULT_USER.to_string()),
+        headers,
+        Query(query),
+    )
+    .await
+}
+
+pub(super) async fn user_uptime(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<UptimeQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (_, now) = request_access(&state, &name, &headers, &query.viewer)?;
+    let days = query.days.unwrap_or(DEFAULT_DAYS).clamp(1, MAX_DAYS);
+    let bars = {
+        let presence = state.presence.lock().unwrap();
+        daily_uptime(presence.get(&name), now, days)
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "image/svg+xml")],
+        render(&bars, now),
+    ))
+}
+
+/// Share of each UTC day, oldest first, that was not part of an incident.
+/// None for days before the user was watched.
+pub(super) fn daily_uptime(
+    presence: Option<&super::presence::Presence>,
+    now: u64,
+    days: u64,
+) -> Vec<Option<f64>> {
+    let today = now - now % DAY;
+    (0..days)
+        .rev()
+        .map(|ago| {
+            let presence = presence?;
+            let day_start = (today - ago * DAY).max(presence.since);
+            let day_end = (today - ago * DAY + DAY).min(now);
+            if day_start >= day_end {
+                return None;
+            }
+            let down: u64 = presence
+                .incidents
+                .iter()
+                .map(|i| {
+                    let end = i.end.unwrap_or(now).min(day_end);
+                    end.saturating_sub(i.start.max(day_start))
+                })
+                .sum();
+            Some(1.0 - down as f64 / (day_end - day_start) as f64)
+        })
+        .collect()
+}
+
+fn render(bars: &[Option<f64>], now: u64) -> String {
+    let width = bars.len() as u64 * (BAR_WIDTH + BAR_GAP) - BAR_GAP;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{BAR_HEIGHT}" viewBox="0 0 {width} {BAR_HEIGHT}">"#
+    );
+    let today = now - now % DAY;
+    for (i, uptime) in bars.iter().enumerate() {
+        let ago = (bars.len() - 1 - i) as u64;
+        let date = DateTime::from_timestamp((today - ago * DAY) as i64, 0)
+            .unwrap()
+            .format("%Y-%m-%d");
+        let (color, label) = match uptime {
+            None => ("#b3bac5", "No data".to_string()),
+            Some(u) if *u >= 0.999 => ("#3fb950", format!("{:.2}%", u * 100.0)),
+            Some(u) if *u >= 0.99 => ("#d4c41c", format!("{:.2}%", u * 100.0)),
+            Some(u) if *u >= 0.95 => ("#e3872d", format!("{:.2}%", u * 100.0)),
+            Some(u) => ("#e5534b", format!("{:.2}%", u * 100.0)),
+        };
+        write!(
+            svg,
+            r#"<rect x="{}" width="{BAR_WIDTH}" height="{BAR_HEIGHT}" rx="1" fill="{color}"><title>{date}: {label}</title></rect>"#,
+            i as u64 * (BAR_WIDTH + BAR_GAP),
+        )
+        .unwrap();
+    }
+    svg.push_str("</svg>");
+    svg
+}
@@ -0,0 +1,108 @@
+//! Noticing a wedged client map or stalled presence checks
+//!
+//! `/status` reads the last published snapshot of the client map meanwhile, so it answers with
+//! the last state known instead of hanging. A poisoned lock, left by a panicking request, is
+//! taken over rather than passed on as a panic to every later one.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard, TryLockError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+use super::{presence, AppState};
+
+/// Seconds between two looks
+const WATCHDOG_INTERVAL: u64 = 5;
+/// How long the client map may stay locked
+const LOCK_WAIT: Duration = Duration::from_secs(2);
+/// How often a waiting request tries to take a lock
+const LOCK_RETRY: Duration = Duration::from_millis(5);
+/// Missed presence checks after which they count as stalled, e.g. on a hanging disk
+const MISSED_CHECKS: u64 = 3;
+
+#[derive(Debug, Default)]
+pub(super) struct Watchdog {
+    /// Whether the client map could not be locked at the last look
+    wedged: AtomicBool,
+    /// When the last presence check, which saves the history, finished
+    checked: AtomicU64,
+}
+
+impl Watchdog {
+    pub(super) fn wedged(&self) -> bool {
+        self.wedged.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn checked(&self, now: u64) {
+        self.checked.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Lock a mutex, taking it over if poisoned
+pub(super) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| {
+        warn!("Recovering state left behind by a panic");
+        mutex.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// Lock a mutex, giving up after `wait`
+fn lock_within<T>(mutex: &Mutex<T>, wait: Duration) -> Option<MutexGuard<'_, T>> {
+    let start = Instant::now();
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(_)) => return Some(lock(mutex)),
+            Err(TryLockError::WouldBlock) if start.elapsed() >= wait => return None,
+            Err(TryLockError::WouldBlock) => std::thread::sleep(LOCK_RETRY),
+        }
+    }
+}
+
+pub(super) async fn watch(state: AppState) {
+    loop {
+        time::sleep(time::Duration::from_secs(WATCHDOG_INTERVAL)).await;
+        let probe = state.clone();
+        let locked =
+            tokio::task::spawn_blocking(move || lock_within(&probe.clients, LOCK_WAIT).is_some())
+                .await
+                .unwrap_or(false);
+        let was_wedged = state.watchdog.wedged.swap(!locked, Ordering::Relaxed);
+        match (was_wedged, locked) {
+            (false, false) => error!(
+                "The client map is locked for over {:?}, /status shows it as it was before",
+                LOCK_WAIT
+            ),
+            (true, true) => info!("The client map can be locked again"),
+            _ => {}
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let checked = state
+            .watchdog
+            .checked
+            .load(Ordering::Relaxed)
+            .max(state.started);
+        let stalled = now.saturating_sub(checked);
+        // No checks are made while the map is wedged
+        if locked && stalled > MISSED_CHECKS * presence::WATCH_INTERVAL {
+            let history = state.settings().history.clone();
+            error!(
+                "No presence check finished for {}s{}",
+                stalled,
+                match history {
+                    Some(path) => format!(", is saving the history to {} stuck?", path.display()),
+                    None => String::new(),
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// Live status chip, embedded with
+// <script src="https://<server>/widget.js" data-user="alice" data-label="Alice" async></script>
+(function () {
+  "use strict";
+  var script = document.currentScript;
+  if (!script) return;
+  var base = script.src.replace(/\/widget\.js(\?.*)?$/, "");
+  var data = script.dataset;
+  var url = base + (data.user ? "/u/" + encodeURIComponent(data.user) : "") + "/status.json";
+  var interval = Math.max(parseInt(data.interval, 10) || 60, 10) * 1000;
+
+  if (!document.querySelector('link[href="' + base + '/widget.css"]')) {
+    var css = document.createElement("link");
+    css.rel = "stylesheet";
+    css.href = base + "/widget.css";
+    document.head.appendChild(css);
+  }
+
+  var chip = document.createElement("span");
+  chip.className = "online-status-chip";
+  if (data.onlineColor) chip.style.setProperty("--online-status-online", data.onlineColor);
+  if (data.offlineColor) chip.style.setProperty("--online-status-offline", data.offlineColor);
+  var dot = document.createElement("span");
+  dot.className = "online-status-dot";
+  var text = document.createElement("span");
+  chip.appendChild(dot);
+  chip.appendChild(text);
+  script.parentNode.insertBefore(chip, script.nextSibling);
+
+  function show(status) {
+    chip.classList.toggle("online-status-online", status === "ONLINE");
+    text.textContent = (data.label ? data.label + ": " : "") + status;
+  }
+
+  function poll() {
+    fetch(url, { cache: "no-store" })
+      .then(function (res) {
+        if (!res.ok) throw new Error(res.status);
+        return res.json();
+      })
+      .then(function (report) {
+        show(report.status);
+      })
+      .catch(function () {
+        show("UNKNOWN");
+      })
+      .then(function () {
+        setTimeout(poll, interval);
+      });
+  }
+  poll();
+})();
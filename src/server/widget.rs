@@ -0,0 +1,31 @@
+//! `/widget.js` and `/widget.css`, a live status chip other websites embed with one
+//! script tag
+//!
+//! The script polls `/status.json` (or `/u/<user>/status.json`) of the server it was loaded
+//! from, which therefore allows any origin to read it.
+
+use axum::{http::header, response::IntoResponse};
+
+const SCRIPT: &str = include_str!("widget.js");
+const STYLE: &str = include_str!("widget.css");
+const CACHE_CONTROL: &str = "public, max-age=3600";
+
+pub(super) async fn script() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        SCRIPT,
+    )
+}
+
+pub(super) async fn style() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/css"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        STYLE,
+    )
+}
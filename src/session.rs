@@ -0,0 +1,137 @@
+//! Whether the Windows session is locked, from the WTS API
+//!
+//! Besides asking at every heartbeat, the client is notified of the session
+//! being locked or unlocked, so it reacts right away instead of at the next
+//! heartbeat a minute later.
+
+use std::{
+    mem, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Once,
+    },
+};
+use windows_sys::Win32::{
+    Foundation::{HWND, LPARAM, LRESULT, WPARAM},
+    System::{
+        LibraryLoader::GetModuleHandleW,
+        RemoteDesktop::{
+            WTSFreeMemory, WTSQuerySessionInformationW, WTSRegisterSessionNotification,
+            WTSSessionInfoEx, NOTIFY_FOR_THIS_SESSION, WTSINFOEXW, WTS_CURRENT_SERVER_HANDLE,
+            WTS_CURRENT_SESSION, WTS_SESSIONSTATE_LOCK,
+        },
+    },
+    UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+        HWND_MESSAGE, WM_WTSSESSION_CHANGE, WNDCLASSW, WTS_SESSION_LOCK, WTS_SESSION_UNLOCK,
+    },
+};
+
+use crate::ctl::Control;
+
+/// Kept up to date by the notifications once they are set up
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static NOTIFIED: AtomicBool = AtomicBool::new(false);
+/// Clients to wake up when the session is locked or unlocked, one per profile
+static CONTROLS: Mutex<Vec<Arc<Control>>> = Mutex::new(Vec::new());
+
+pub fn is_locked() -> bool {
+    if NOTIFIED.load(Ordering::Relaxed) {
+        LOCKED.load(Ordering::Relaxed)
+    } else {
+        query_locked()
+    }
+}
+
+fn query_locked() -> bool {
+    let mut buffer = ptr::null_mut();
+    let mut size = 0;
+    // SAFETY: on success, the buffer holds a WTSINFOEXW, freed with WTSFreeMemory
+    unsafe {
+        if WTSQuerySessionInformationW(
+            WTS_CURRENT_SERVER_HANDLE,
+            WTS_CURRENT_SESSION,
+            WTSSessionInfoEx,
+            &mut buffer,
+            &mut size,
+        ) == 0
+        {
+            return false;
+        }
+        let info = &*(buffer as *const WTSINFOEXW);
+        let locked = info.Level == 1
+            && info.Data.WTSInfoExLevel1.SessionFlags == WTS_SESSIONSTATE_LOCK as i32;
+        WTSFreeMemory(buffer.cast());
+        locked
+    }
+}
+
+/// Wake the client up whenever the session is locked or unlocked
+pub fn watch(control: Arc<Control>) {
+    CONTROLS.lock().unwrap().push(control);
+    static START: Once = Once::new();
+    START.call_once(|| {
+        std::thread::spawn(listen);
+    });
+}
+
+/// Receive session notifications in a message-only window
+fn listen() {
+    let class: Vec<u16> = "online_status_session\0".encode_utf16().collect();
+    // SAFETY: the class name outlives the window, which lives as long as the thread
+    unsafe {
+        let instance = GetModuleHandleW(ptr::null());
+        let mut window_class: WNDCLASSW = mem::zeroed();
+        window_class.lpfnWndProc = Some(window_proc);
+        window_class.hInstance = instance;
+        window_class.lpszClassName = class.as_ptr();
+        RegisterClassW(&window_class);
+        let window = CreateWindowExW(
+            0,
+            class.as_ptr(),
+            ptr::null(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            HWND_MESSAGE,
+            ptr::null_mut(),
+            instance,
+            ptr::null(),
+        );
+        if window.is_null() || WTSRegisterSessionNotification(window, NOTIFY_FOR_THIS_SESSION) == 0
+        {
+            warn!("Cannot be notified of the session being locked, checking at every heartbeat");
+            return;
+        }
+        LOCKED.store(query_locked(), Ordering::Relaxed);
+        NOTIFIED.store(true, Ordering::Relaxed);
+        let mut message = mem::zeroed();
+        while GetMessageW(&mut message, ptr::null_mut(), 0, 0) > 0 {
+            DispatchMessageW(&message);
+        }
+    }
+}
+
+unsafe extern "system" fn window_proc(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    if message != WM_WTSSESSION_CHANGE {
+        return DefWindowProcW(window, message, wparam, lparam);
+    }
+    let locked = match wparam as u32 {
+        WTS_SESSION_LOCK => true,
+        WTS_SESSION_UNLOCK => false,
+        _ => return 0,
+    };
+    LOCKED.store(locked, Ordering::Relaxed);
+    info!("Session {}", if locked { "locked" } else { "unlocked" });
+    for control in CONTROLS.lock().unwrap().iter() {
+        control.wake.notify_one();
+    }
+    0
+}
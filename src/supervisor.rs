@@ -0,0 +1,201 @@
+//! Limits the client keeps on its own memory and CPU use, for machines left running unattended
+//!
+//! Every half minute, the usage of the whole process is compared with `--max-memory` and
+//! `--max-cpu`. Once over a limit, the heartbeat loop is restarted, letting go of whatever it
+//! held on to. If that does not help, or with `--on-limit exit`, the client exits with
+//! [`exit::LIMITS`], for the service manager to start it afresh.
+
+use clap::ValueEnum;
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use tokio::{task::JoinHandle, time};
+
+use crate::{config::Args, exit};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Checks in a row over `--max-cpu` that count, as a busy moment is no leak
+const CPU_CHECKS: u32 = 2;
+/// Going over a limit again this soon after a restart means it did not help
+const RESTART_GRACE: Duration = Duration::from_secs(10 * 60);
+
+/// What to do once over a limit
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitAction {
+    /// Restart the heartbeat loop, and exit if that does not help
+    #[default]
+    Restart,
+    /// Exit for the service manager to restart the client
+    Exit,
+}
+
+pub struct Limits {
+    /// Bytes of resident memory
+    memory: Option<u64>,
+    /// Percent of one core, averaged over a check interval
+    cpu: Option<f64>,
+    action: LimitAction,
+}
+
+impl Limits {
+    pub fn new(args: &Args) -> Option<Self> {
+        (args.max_memory.is_some() || args.max_cpu.is_some()).then(|| Limits {
+            memory: args.max_memory.map(|mb| mb * 1024 * 1024),
+            cpu: args.max_cpu,
+            action: args.on_limit,
+        })
+    }
+}
+
+/// What the process used up to now
+struct Usage {
+    at: Instant,
+    cpu: Option<Duration>,
+    memory: Option<u64>,
+}
+
+impl Usage {
+    fn now() -> Self {
+        Usage {
+            at: Instant::now(),
+            cpu: cpu_time(),
+            memory: resident_memory(),
+        }
+    }
+}
+
+/// Run the task `start` spawns until it ends, restarting it whenever over a limit
+pub async fn supervise(
+    limits: Option<Limits>,
+    mut start: impl FnMut() -> JoinHandle<Result<(), String>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut task = start();
+    let Some(limits) = limits else {
+        return Ok(task.await??);
+    };
+    let mut checks = time::interval_at(time::Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+    let mut last = Usage::now();
+    let mut busy = 0;
+    let mut restarted: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            result = &mut task => return Ok(result??),
+            _ = checks.tick() => {}
+        }
+        let usage = Usage::now();
+        let mut exceeded = None;
+        if let (Some(limit), Some(memory)) = (limits.memory, usage.memory) {
+            if memory > limit {
+                exceeded = Some(format!(
+                    "Using {} MB of memory, over the limit of {} MB",
+                    memory / 1024 / 1024,
+                    limit / 1024 / 1024
+                ));
+            }
+        }
+        if let (Some(limit), Some(cpu), Some(last_cpu)) = (limits.cpu, usage.cpu, last.cpu) {
+            let elapsed = usage.at.duration_since(last.at).as_secs_f64();
+            let percent = cpu.saturating_sub(last_cpu).as_secs_f64() / elapsed * 100.0;
+            busy = if percent > limit { busy + 1 } else { 0 };
+            if busy >= CPU_CHECKS && exceeded.is_none() {
+                exceeded = Some(format!(
+                    "Using {:.0}% CPU for {} seconds, over the limit of {}%",
+                    percent,
+                    busy as u64 * CHECK_INTERVAL.as_secs(),
+                    limit
+                ));
+            }
+        }
+        last = usage;
+        let Some(exceeded) = exceeded else {
+            continue;
+        };
+        let again = restarted.is_some_and(|at| at.elapsed() < RESTART_GRACE);
+        if limits.action == LimitAction::Exit || again {
+            error!("{}, exiting", exceeded);
+            std::process::exit(exit::LIMITS);
+        }
+        warn!("{}, restarting the heartbeats", exceeded);
+        task.abort();
+        let _ = (&mut task).await;
+        task = start();
+        restarted = Some(Instant::now());
+        busy = 0;
+    }
+}
+
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    // SAFETY: getrusage only writes the struct it is given
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        usage
+    };
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn resident_memory() -> Option<u64> {
+    let output = std::process::Command::new("ps")
+        .args(["-o", "rss=", "-p", &std::process::id().to_string()])
+        .output()
+        .ok()?;
+    let kilobytes: u64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(target_os = "windows")]
+fn cpu_time() -> Option<Duration> {
+    use windows_sys::Win32::{
+        Foundation::FILETIME,
+        System::Threading::{GetCurrentProcess, GetProcessTimes},
+    };
+
+    let mut times = [FILETIME {
+        dwLowDateTime: 0,
+        dwHighDateTime: 0,
+    }; 4];
+    let [creation, exit, kernel, user] = &mut times;
+    // SAFETY: the pseudo handle of the current process needs no closing
+    let ok = unsafe { GetProcessTimes(GetCurrentProcess(), creation, exit, kernel, user) } != 0;
+    // In units of 100 nanoseconds
+    let ticks = |t: &FILETIME| (t.dwHighDateTime as u64) << 32 | t.dwLowDateTime as u64;
+    ok.then(|| Duration::from_nanos((ticks(kernel) + ticks(user)) * 100))
+}
+
+#[cfg(target_os = "windows")]
+fn resident_memory() -> Option<u64> {
+    use windows_sys::Win32::System::{
+        ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        Threading::GetCurrentProcess,
+    };
+
+    // SAFETY: the counters are as large as told
+    unsafe {
+        let mut counters = std::mem::zeroed::<PROCESS_MEMORY_COUNTERS>();
+        let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+        if K32GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) == 0 {
+            return None;
+        }
+        Some(counters.WorkingSetSize as u64)
+    }
+}
@@ -0,0 +1,93 @@
+//! Coarse metrics of the device for `--system-metrics`: load, memory and disk use
+//!
+//! Values are rounded, as the server only needs to see that a machine is in trouble.
+
+use crate::SystemMetrics;
+
+pub fn metrics() -> SystemMetrics {
+    SystemMetrics {
+        load: load().map(|load| (load * 100.0).round() / 100.0),
+        memory_percent: memory_percent().map(f64::round),
+        disk_percent: disk_percent().map(f64::round),
+    }
+}
+
+/// Average number of runnable processes over the last minute
+#[cfg(unix)]
+fn load() -> Option<f64> {
+    let mut loads = [0.0; 3];
+    (unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) } == 1).then_some(loads[0])
+}
+
+/// Windows has no load average
+#[cfg(not(unix))]
+fn load() -> Option<f64> {
+    None
+}
+
+/// Memory in use, without what the kernel could free, like caches
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn memory_percent() -> Option<f64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    };
+    let total = field("MemTotal").filter(|&total| total > 0.0)?;
+    Some((total - field("MemAvailable")?) / total * 100.0)
+}
+
+#[cfg(windows)]
+fn memory_percent() -> Option<f64> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    (unsafe { GlobalMemoryStatusEx(&mut status) } != 0).then_some(status.dwMemoryLoad as f64)
+}
+
+/// Not known on these systems, like macOS, which keeps most memory in use
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn memory_percent() -> Option<f64> {
+    None
+}
+
+/// Space in use on the root file system, out of what users may fill
+#[cfg(unix)]
+fn disk_percent() -> Option<f64> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c"/".as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let used = stat.f_blocks.saturating_sub(stat.f_bfree) as f64;
+    let usable = used + stat.f_bavail as f64;
+    (usable > 0.0).then(|| used / usable * 100.0)
+}
+
+/// Space in use on the system drive
+#[cfg(windows)]
+fn disk_percent() -> Option<f64> {
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let drive: Vec<u16> = std::env::var("SystemDrive")
+        .unwrap_or_else(|_| "C:".to_string())
+        .encode_utf16()
+        .chain("\\".encode_utf16())
+        .chain([0])
+        .collect();
+    let (mut available, mut total, mut free) = (0, 0, 0);
+    if unsafe { GetDiskFreeSpaceExW(drive.as_ptr(), &mut available, &mut total, &mut free) } == 0 {
+        return None;
+    }
+    (total > 0).then(|| (total - free) as f64 / total as f64 * 100.0)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn disk_percent() -> Option<f64> {
+    None
+}
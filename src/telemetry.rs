@@ -0,0 +1,214 @@
+//! Minimal OpenTelemetry tracing, exported as OTLP/HTTP JSON
+//!
+//! Spans are recorded when dropped and sent in batches to
+//! `<endpoint>/v1/traces`. Without an endpoint, spans are discarded.
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use serde_json::{json, Value};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::TIMEOUT;
+
+const EXPORT_INTERVAL: u64 = 5;
+const MAX_QUEUED_SPANS: usize = 2048;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl SpanContext {
+    /// The W3C `traceparent` header value
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-01",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id)
+        )
+    }
+
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.split('-');
+        let (_version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+        Some(SpanContext {
+            trace_id: hex::decode(trace_id).ok()?.try_into().ok()?,
+            span_id: hex::decode(span_id).ok()?.try_into().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A unit of work, recorded when dropped
+pub struct Span {
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn new(name: &'static str, kind: SpanKind, parent: Option<SpanContext>) -> Self {
+        Span {
+            context: SpanContext {
+                trace_id: parent.map_or_else(rand::random, |p| p.trace_id),
+                span_id: rand::random(),
+            },
+            parent: parent.map(|p| p.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        Span::new(name, SpanKind::Internal, Some(self.context))
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn fail(&mut self, message: impl ToString) {
+        self.error = Some(message.to_string());
+    }
+
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_nanos().to_string();
+        let attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": otlp_value(value) }))
+            .collect();
+        let status = match &self.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 0 }),
+        };
+        let mut span = json!({
+            "traceId": hex::encode(self.context.trace_id),
+            "spanId": hex::encode(self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(end),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = hex::encode(parent).into();
+        }
+        span
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(exporter) = EXPORTER.get() {
+            let mut queue = exporter.queue.lock().unwrap();
+            if queue.len() < MAX_QUEUED_SPANS {
+                queue.push(self.to_otlp(SystemTime::now()));
+            }
+        }
+    }
+}
+
+fn otlp_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+struct Exporter {
+    queue: Mutex<Vec<Value>>,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Start exporting spans to an OTLP/HTTP collector, e.g. `http://localhost:4318`
+pub fn init(endpoint: &str, service: &str) {
+    if EXPORTER
+        .set(Exporter {
+            queue: Mutex::new(Vec::new()),
+        })
+        .is_err()
+    {
+        return;
+    }
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let resource = json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": service } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+        ]
+    });
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            tokio::time::sleep(Duration::from_secs(EXPORT_INTERVAL)).await;
+            let spans = std::mem::take(&mut *EXPORTER.get().unwrap().queue.lock().unwrap());
+            if spans.is_empty() {
+                continue;
+            }
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": resource,
+                    "scopeSpans": [{
+                        "scope": { "name": env!("CARGO_PKG_NAME") },
+                        "spans": spans,
+                    }],
+                }],
+            });
+            let res = client
+                .post(&url)
+                .json(&body)
+                .timeout(Duration::from_secs(TIMEOUT))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = res {
+                warn!("Cannot export traces: {}", e);
+            }
+        }
+    });
+}
+
+/// Wrap every request in a server span, continuing the caller's trace if any
+pub async fn trace_request(mut req: Request, next: Next) -> Response {
+    let parent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let mut span = Span::new("request", SpanKind::Server, parent);
+    span.set("http.request.method", req.method().as_str());
+    span.set("url.path", req.uri().path());
+    req.extensions_mut().insert(span.context());
+    let res = next.run(req).await;
+    span.set("http.response.status_code", res.status().as_u16());
+    if res.status().is_server_error() {
+        span.fail(res.status());
+    }
+    res
+}
@@ -0,0 +1,182 @@
+//! System tray icon of the client, showing whether heartbeats are sent and
+//! offering the commands of the control socket
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tao::{
+    event::{Event, StartCause},
+    event_loop::{ControlFlow, EventLoopBuilder},
+};
+use tray_icon::{
+    menu::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem, Submenu},
+    Icon, TrayIcon, TrayIconBuilder,
+};
+
+use crate::ctl::{Control, CtlCommand, DND_MESSAGE};
+
+const DEFAULT_MESSAGES: [&str; 3] = ["Lunch", "In a meeting", "Away"];
+const ICON_SIZE: u32 = 32;
+
+struct Tray {
+    icon: TrayIcon,
+    state: MenuItem,
+    pause: MenuItem,
+    dnd: CheckMenuItem,
+    send_now: MenuItem,
+    quit: MenuItem,
+    /// Message items and what they set the message to
+    messages: HashMap<MenuId, Option<String>>,
+    /// Message before DND was turned on
+    saved_message: Option<String>,
+    /// What is currently shown, to only update on changes
+    shown: Option<(String, [u8; 3])>,
+}
+
+/// A filled circle in the given colour
+fn icon(rgb: [u8; 3]) -> Icon {
+    let center = ICON_SIZE as f32 / 2.0;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = (x as f32 + 0.5 - center).hypot(y as f32 + 0.5 - center);
+            let alpha = if distance <= center - 2.0 { 255 } else { 0 };
+            rgba.extend_from_slice(&[rgb[0], rgb[1], rgb[2], alpha]);
+        }
+    }
+    Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE).unwrap()
+}
+
+impl Tray {
+    fn build(messages: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = MenuItem::new("", false, None);
+        let pause = MenuItem::new("Pause", true, None);
+        let dnd = CheckMenuItem::new("Do not disturb", true, false, None);
+        let send_now = MenuItem::new("Send heartbeat now", true, None);
+        let quit = MenuItem::new("Quit", true, None);
+        let submenu = Submenu::new("Message", true);
+        let mut items = HashMap::new();
+        let presets: Vec<&str> = if messages.is_empty() {
+            DEFAULT_MESSAGES.to_vec()
+        } else {
+            messages.iter().map(String::as_str).collect()
+        };
+        for text in presets {
+            let item = MenuItem::new(text, true, None);
+            submenu.append(&item)?;
+            items.insert(item.id().clone(), Some(text.to_string()));
+        }
+        let clear = MenuItem::new("Clear", true, None);
+        submenu.append_items(&[&PredefinedMenuItem::separator(), &clear])?;
+        items.insert(clear.id().clone(), None);
+
+        let menu = Menu::new();
+        menu.append_items(&[
+            &state,
+            &PredefinedMenuItem::separator(),
+            &pause,
+            &dnd,
+            &submenu,
+            &send_now,
+            &PredefinedMenuItem::separator(),
+            &quit,
+        ])?;
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("online_status")
+            .build()?;
+        Ok(Tray {
+            icon,
+            state,
+            pause,
+            dnd,
+            send_now,
+            quit,
+            messages: items,
+            saved_message: None,
+            shown: None,
+        })
+    }
+
+    fn handle(&mut self, control: &Control, id: &MenuId) {
+        if id == self.pause.id() {
+            control.apply(if control.paused() {
+                CtlCommand::Resume
+            } else {
+                CtlCommand::Pause
+            });
+        } else if id == self.dnd.id() {
+            let message = if self.dnd.is_checked() {
+                self.saved_message = control.message();
+                Some(DND_MESSAGE.to_string())
+            } else {
+                self.saved_message.take()
+            };
+            control.apply(CtlCommand::SetMessage { message });
+        } else if id == self.send_now.id() {
+            control.apply(CtlCommand::SendNow);
+        } else if id == self.quit.id() {
+            std::process::exit(0);
+        } else if let Some(message) = self.messages.get(id) {
+            self.dnd.set_checked(false);
+            control.apply(CtlCommand::SetMessage {
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// Show the current state of the client
+    fn update(&mut self, control: &Control) {
+        let paused = control.paused();
+        let text = match (paused, control.message()) {
+            (true, _) => "Paused".to_string(),
+            (false, Some(message)) => format!("Online: {}", message),
+            (false, None) => "Online".to_string(),
+        };
+        let color = match (paused, self.dnd.is_checked()) {
+            (true, _) => [0x8b, 0x94, 0x9e],
+            (false, true) => [0xe5, 0x53, 0x4b],
+            (false, false) => [0x3f, 0xb9, 0x50],
+        };
+        let shown = Some((text, color));
+        if self.shown == shown {
+            return;
+        }
+        let (text, color) = shown.as_ref().unwrap();
+        self.state.set_text(text);
+        self.pause.set_text(if paused { "Resume" } else { "Pause" });
+        let _ = self.icon.set_icon(Some(icon(*color)));
+        let _ = self
+            .icon
+            .set_tooltip(Some(format!("online_status: {}", text)));
+        self.shown = shown;
+    }
+}
+
+/// Run the tray on this thread until the user quits. Must be the main thread.
+pub fn run(control: Arc<Control>, messages: Vec<String>) -> ! {
+    let event_loop = EventLoopBuilder::new().build();
+    let mut tray = None;
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_secs(1));
+        // The icon can only be created once the event loop runs on macOS
+        if let Event::NewEvents(StartCause::Init) = event {
+            match Tray::build(&messages) {
+                Ok(built) => tray = Some(built),
+                Err(e) => {
+                    error!("Cannot create tray icon: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        let Some(tray) = tray.as_mut() else {
+            return;
+        };
+        while let Ok(event) = MenuEvent::receiver().try_recv() {
+            tray.handle(&control, &event.id);
+        }
+        tray.update(&control);
+    })
+}
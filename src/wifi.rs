@@ -0,0 +1,60 @@
+//! The Wi-Fi network the client is on, so heartbeats can say "at work" without
+//! telling the server the network's name
+
+use tokio::process::Command;
+
+/// Parse `--place work=CorpWiFi`
+pub fn parse_place(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((label, ssid)) if !label.is_empty() && !ssid.is_empty() => {
+            Ok((label.to_string(), ssid.to_string()))
+        }
+        _ => Err("expected <label>=<ssid>".to_string()),
+    }
+}
+
+/// The label of the current network, `other` for unknown ones, none without Wi-Fi
+pub async fn place(places: &[(String, String)]) -> Option<String> {
+    let current = ssid().await?;
+    let label = places
+        .iter()
+        .find(|(_, ssid)| *ssid == current)
+        .map_or("other", |(label, _)| label);
+    Some(label.to_string())
+}
+
+async fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().await.ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "windows")]
+async fn ssid() -> Option<String> {
+    let interfaces = output("netsh", &["wlan", "show", "interfaces"]).await?;
+    interfaces.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "SSID").then(|| value.trim().to_string())
+    })
+}
+
+#[cfg(target_os = "macos")]
+async fn ssid() -> Option<String> {
+    let network = output("networksetup", &["-getairportnetwork", "en0"]).await?;
+    let ssid = network.trim().strip_prefix("Current Wi-Fi Network: ")?;
+    Some(ssid.to_string())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+async fn ssid() -> Option<String> {
+    if let Some(networks) = output("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"]).await {
+        return networks
+            .lines()
+            .find_map(|line| line.strip_prefix("yes:"))
+            .map(str::to_string);
+    }
+    let ssid = output("iwgetid", &["-r"]).await?;
+    Some(ssid.trim().to_string()).filter(|s| !s.is_empty())
+}